@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::dsp::hermite;
+
+/// WAV/FLAC を読んでチャンネルごとに分ける (sample_rate, channels)
+pub fn read(path: &str) -> Result<(f64, Vec<Vec<f32>>)> {
+    let extension = Path::new(path)
+        .extension()
+        .map(|x| x.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("flac") => read_flac(path),
+        _ => read_wav(path),
+    }
+}
+
+/// sample_rate に変換して読む
+pub fn read_resampled(path: &str, sample_rate: f64) -> Result<Vec<Vec<f32>>> {
+    let (file_sample_rate, channels) = read(path)?;
+    if file_sample_rate == sample_rate {
+        Ok(channels)
+    } else {
+        Ok(channels
+            .iter()
+            .map(|x| resample(x, file_sample_rate, sample_rate))
+            .collect())
+    }
+}
+
+fn read_wav(path: &str) -> Result<(f64, Vec<Vec<f32>>)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|x| x.map(|x| x as f32 * scale))
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    Ok((
+        spec.sample_rate as f64,
+        deinterleave(&samples, spec.channels as usize),
+    ))
+}
+
+fn read_flac(path: &str) -> Result<(f64, Vec<Vec<f32>>)> {
+    let mut reader = claxon::FlacReader::open(path)?;
+    let info = reader.streaminfo();
+    let scale = 1.0 / (1i64 << (info.bits_per_sample - 1)) as f32;
+    let samples = reader
+        .samples()
+        .map(|x| x.map(|x| x as f32 * scale))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((
+        info.sample_rate as f64,
+        deinterleave(&samples, info.channels as usize),
+    ))
+}
+
+fn deinterleave(samples: &[f32], nchannels: usize) -> Vec<Vec<f32>> {
+    let nchannels = nchannels.max(1);
+    (0..nchannels)
+        .map(|channel| {
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(nchannels)
+                .cloned()
+                .collect()
+        })
+        .collect()
+}
+
+/// 4 点エルミート補間でリサンプル
+pub fn resample(src: &[f32], src_rate: f64, dst_rate: f64) -> Vec<f32> {
+    let ratio = src_rate / dst_rate;
+    let len = (src.len() as f64 / ratio).floor() as usize;
+    let at = |i: isize| src.get(i.max(0) as usize).cloned().unwrap_or(0.0);
+    (0..len)
+        .map(|i| {
+            let position = i as f64 * ratio;
+            let index = position.floor() as isize;
+            let t = (position - index as f64) as f32;
+            hermite([at(index - 1), at(index), at(index + 1), at(index + 2)], t)
+        })
+        .collect()
+}
@@ -0,0 +1,197 @@
+use std::ffi::CStr;
+
+use bincode::{Decode, Encode};
+use clap_sys::ext::{
+    audio_ports::{CLAP_PORT_MONO, CLAP_PORT_STEREO},
+    surround::CLAP_PORT_SURROUND,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::process_data::MAX_CHANNELS;
+
+// 足りないスピーカーに振り分けるときのゲイン -3dB
+const DOWNMIX_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// 各チャンネルのスピーカーの役割
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+}
+
+impl Speaker {
+    /// バスにないときに代わりに送るスピーカー LFE はどこにも送らない
+    fn downmix_targets(&self) -> &'static [Speaker] {
+        match self {
+            Speaker::FrontLeft | Speaker::FrontRight => &[Speaker::FrontCenter],
+            Speaker::FrontCenter => &[Speaker::FrontLeft, Speaker::FrontRight],
+            Speaker::LowFrequency => &[],
+            Speaker::BackLeft => &[Speaker::FrontLeft],
+            Speaker::BackRight => &[Speaker::FrontRight],
+        }
+    }
+}
+
+/// トラックのチャンネル構成
+/// チャンネルの並びは CLAP の surround 拡張の順 (FL FR FC LFE BL BR)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub enum ChannelLayout {
+    Mono,
+    #[default]
+    Stereo,
+    Quad,
+    Surround51,
+}
+
+pub const CHANNEL_LAYOUTS: [ChannelLayout; 4] = [
+    ChannelLayout::Mono,
+    ChannelLayout::Stereo,
+    ChannelLayout::Quad,
+    ChannelLayout::Surround51,
+];
+
+impl ChannelLayout {
+    pub fn nchannels(&self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Quad => 4,
+            ChannelLayout::Surround51 => 6,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChannelLayout::Mono => "Mono",
+            ChannelLayout::Stereo => "Stereo",
+            ChannelLayout::Quad => "Quad",
+            ChannelLayout::Surround51 => "5.1",
+        }
+    }
+
+    pub fn port_type(&self) -> &'static CStr {
+        match self {
+            ChannelLayout::Mono => CLAP_PORT_MONO,
+            ChannelLayout::Stereo => CLAP_PORT_STEREO,
+            ChannelLayout::Quad | ChannelLayout::Surround51 => CLAP_PORT_SURROUND,
+        }
+    }
+
+    /// clap_audio_port_info の port_type と channel_count から
+    pub fn from_port_type(port_type: Option<&CStr>, nchannels: usize) -> Option<Self> {
+        match (port_type, nchannels) {
+            (Some(x), 1) if x == CLAP_PORT_MONO => Some(ChannelLayout::Mono),
+            (Some(x), 2) if x == CLAP_PORT_STEREO => Some(ChannelLayout::Stereo),
+            (Some(x), 4) if x == CLAP_PORT_SURROUND => Some(ChannelLayout::Quad),
+            (Some(x), 6) if x == CLAP_PORT_SURROUND => Some(ChannelLayout::Surround51),
+            (None, 1) => Some(ChannelLayout::Mono),
+            (None, 2) => Some(ChannelLayout::Stereo),
+            _ => None,
+        }
+    }
+
+    /// 1 チャンネルだけならセンター
+    pub fn speakers(&self) -> &'static [Speaker] {
+        match self {
+            ChannelLayout::Mono => &[Speaker::FrontCenter],
+            ChannelLayout::Stereo => &[Speaker::FrontLeft, Speaker::FrontRight],
+            ChannelLayout::Quad => &[
+                Speaker::FrontLeft,
+                Speaker::FrontRight,
+                Speaker::BackLeft,
+                Speaker::BackRight,
+            ],
+            ChannelLayout::Surround51 => &[
+                Speaker::FrontLeft,
+                Speaker::FrontRight,
+                Speaker::FrontCenter,
+                Speaker::LowFrequency,
+                Speaker::BackLeft,
+                Speaker::BackRight,
+            ],
+        }
+    }
+
+    /// チャンネル数が合うもののうち一番大きいもの 余ったチャンネルは使わない
+    pub fn from_nchannels(nchannels: usize) -> Self {
+        CHANNEL_LAYOUTS
+            .into_iter()
+            .rev()
+            .find(|x| x.nchannels() <= nchannels)
+            .unwrap_or(ChannelLayout::Mono)
+    }
+
+    /// self の各チャンネルを bus の同じスピーカーに送るゲイン [src][dst]
+    /// bus にないスピーカーは -3dB で近いスピーカーに振り分ける
+    pub fn downmix_gains(&self, bus: ChannelLayout) -> [[f32; MAX_CHANNELS]; MAX_CHANNELS] {
+        let mut gains = [[0.0; MAX_CHANNELS]; MAX_CHANNELS];
+        for (src, speaker) in self.speakers().iter().enumerate() {
+            bus.downmix_add(*speaker, 1.0, &mut gains[src]);
+        }
+        gains
+    }
+
+    fn downmix_add(&self, speaker: Speaker, gain: f32, gains: &mut [f32; MAX_CHANNELS]) {
+        if let Some(dst) = self.speakers().iter().position(|x| *x == speaker) {
+            gains[dst] += gain;
+            return;
+        }
+        // どのバスにも FrontLeft か FrontCenter はあるので行ったり来たりはしない
+        for target in speaker.downmix_targets() {
+            self.downmix_add(*target, gain * DOWNMIX_GAIN, gains);
+        }
+    }
+
+    /// 正面を 0 度、右回りを正とした各チャンネルのスピーカーの角度
+    /// LFE は None でパンの対象にしない
+    pub fn speaker_angles(&self) -> &'static [Option<f32>] {
+        match self {
+            ChannelLayout::Mono => &[Some(0.0)],
+            ChannelLayout::Stereo => &[Some(-30.0), Some(30.0)],
+            ChannelLayout::Quad => &[Some(-45.0), Some(45.0), Some(-135.0), Some(135.0)],
+            ChannelLayout::Surround51 => &[
+                Some(-30.0),
+                Some(30.0),
+                Some(0.0),
+                None,
+                Some(-110.0),
+                Some(110.0),
+            ],
+        }
+    }
+
+    /// angle の方向の音を隣り合う 2 つのスピーカーにコンスタントパワーで振り分ける
+    pub fn pan_gains(&self, angle: f32) -> [f32; MAX_CHANNELS] {
+        let angles = self.speaker_angles();
+        let mut gains = [0.0; MAX_CHANNELS];
+        let mut speakers = angles
+            .iter()
+            .enumerate()
+            .filter_map(|(channel, x)| x.map(|x| (channel, x)))
+            .collect::<Vec<_>>();
+        if speakers.len() == 1 {
+            gains[speakers[0].0] = 1.0;
+            return gains;
+        }
+        speakers.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let angle = (angle + 180.0).rem_euclid(360.0) - 180.0;
+        for i in 0..speakers.len() {
+            let (a, a_angle) = speakers[i];
+            let (b, b_angle) = speakers[(i + 1) % speakers.len()];
+            // 最後と最初の間は後ろを回る
+            let span = (b_angle - a_angle).rem_euclid(360.0);
+            let offset = (angle - a_angle).rem_euclid(360.0);
+            if offset <= span {
+                let t = offset / span * std::f32::consts::FRAC_PI_2;
+                gains[a] = t.cos();
+                gains[b] = t.sin();
+                return gains;
+            }
+        }
+        gains
+    }
+}
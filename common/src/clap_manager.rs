@@ -1,33 +1,81 @@
 use std::{
+    env::{self, split_paths},
     ffi::{c_char, CStr, CString, OsStr},
     fs::{self, create_dir_all, metadata, File},
-    io::{BufReader, Write},
+    io::{BufReader, Read, Write},
     path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bincode::{Decode, Encode};
 use clap_sys::{entry::clap_plugin_entry, factory::plugin_factory::clap_plugin_factory};
 use libloading::{Library, Symbol};
+use serde::{Deserialize, Serialize};
 
 use crate::{plugin::description::Description, util::dir_user_setting};
 
+/// スキャン用の子プロセスの引数
+pub const SCAN_ARG: &str = "--scan";
+/// 子プロセスの標準出力のこの行に結果を書く プラグインが何か出力しても区別できるように
+const SCAN_RESULT_PREFIX: &str = "SCAN_RESULT ";
+// これより長くかかるプラグインはハングしたとみなす
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// スキャンで落ちたりハングしたりしたプラグイン ファイルが更新されるまでスキャンしない
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockedPlugin {
+    pub path: String,
+    pub modified: u64,
+    #[serde(default)]
+    pub size: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum ScanStatus {
+    /// スキャンして見つかったプラグインの名前
+    Found(Vec<String>),
+    /// 変わっていないので前のスキャンの結果を使った
+    Unchanged(Vec<String>),
+    /// 前のスキャンのあとで消えた
+    Removed,
+    /// ブロックリストにあったのでスキャンしなかった
+    Blocked(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct ScanReport {
+    pub path: String,
+    pub status: ScanStatus,
+}
+
 pub struct ClapManager {
     pub setting_path: PathBuf,
+    pub blocklist_path: PathBuf,
     pub descriptions: Vec<Description>,
+    pub blocklist: Vec<BlockedPlugin>,
 }
 
 impl ClapManager {
     pub fn new() -> Self {
         let setting_path = dir_user_setting().join("claps.json");
+        let blocklist_path = dir_user_setting().join("clap_blocklist.json");
         let mut this = Self {
             setting_path,
+            blocklist_path,
             descriptions: vec![],
+            blocklist: vec![],
         };
         let _ = this.load();
+        let _ = this.blocklist_load();
         this
     }
 
-    pub fn description(&self, id: &String) -> Option<&Description> {
+    pub fn description(&self, id: &str) -> Option<&Description> {
         self.descriptions.iter().find(|x| x.id == *id)
     }
 
@@ -38,38 +86,119 @@ impl ClapManager {
         Ok(())
     }
 
-    pub fn scan(&mut self) {
-        self.descriptions.clear();
-        for path in self.find_clap_files(&Path::new("C:\\Program Files\\Common Files\\CLAP")) {
-            log::debug!("path {path:?}");
-            log::debug!("extension {:?}", path.extension());
-            if path.extension() == Some(OsStr::new("clap")) || path.is_dir() {
-                match self.scan_plugin_file(&path) {
-                    Ok(_) => (),
-                    Err(error) => log::error!("scan clap file is failed! {:?} {:?}", path, error),
+    fn blocklist_load(&mut self) -> Result<()> {
+        let file = File::open(&self.blocklist_path)?;
+        let reader = BufReader::new(file);
+        self.blocklist = serde_json::from_reader(reader)?;
+        Ok(())
+    }
+
+    /// scanner はスキャン用の子プロセスとして起動する実行ファイル
+    /// extra_dirs は設定で足したディレクトリ
+    /// 変更時刻とサイズが変わっていないファイルはスキャンしない claps.json が変わったら true
+    pub fn scan(
+        &mut self,
+        scanner: &Path,
+        extra_dirs: &[String],
+    ) -> Result<(Vec<ScanReport>, bool)> {
+        let mut reports = vec![];
+        let mut descriptions = vec![];
+        let mut changed_p = false;
+        let mut paths = vec![];
+        for dir in clap_search_dirs(extra_dirs) {
+            log::debug!("search {dir:?}");
+            for path in self.find_clap_files(&dir) {
+                if !paths.contains(&path) {
+                    paths.push(path);
                 }
             }
         }
-        self.descriptions.sort_by_key(|x| x.name.clone());
-        self.save();
-    }
-
-    fn features_to_vec(&self, features: *const *const c_char) -> Vec<String> {
-        let mut result = Vec::new();
-        if features.is_null() {
-            return result;
+        for path in paths.iter() {
+            log::debug!("path {path:?}");
+            let path_string = path.to_string_lossy().to_string();
+            let (modified, size) = file_stamp(&path);
+            let cached = self
+                .descriptions
+                .iter()
+                .filter(|x| x.path == path_string)
+                .cloned()
+                .collect::<Vec<_>>();
+            let blocked = self
+                .blocklist
+                .iter()
+                .find(|x| x.path == path_string && x.modified == modified && x.size == size);
+            let status = if let Some(blocked) = blocked {
+                changed_p |= !cached.is_empty();
+                ScanStatus::Blocked(blocked.error.clone())
+            } else if !cached.is_empty()
+                && cached
+                    .iter()
+                    .all(|x| x.modified == modified && x.size == size)
+            {
+                let names = cached.iter().map(|x| x.name.clone()).collect();
+                descriptions.extend(cached);
+                ScanStatus::Unchanged(names)
+            } else {
+                match scan_in_subprocess(scanner, &path) {
+                    Ok(found) => {
+                        changed_p |= !cached.is_empty() || !found.is_empty();
+                        let names = found.iter().map(|x| x.name.clone()).collect();
+                        descriptions.extend(found);
+                        ScanStatus::Found(names)
+                    }
+                    Err(error) => {
+                        log::error!("scan clap file is failed! {:?} {:?}", path, error);
+                        changed_p |= !cached.is_empty();
+                        let error = error.to_string();
+                        self.blocklist.retain(|x| x.path != path_string);
+                        self.blocklist.push(BlockedPlugin {
+                            path: path_string.clone(),
+                            modified,
+                            size,
+                            error: error.clone(),
+                        });
+                        ScanStatus::Failed(error)
+                    }
+                }
+            };
+            reports.push(ScanReport {
+                path: path_string,
+                status,
+            });
         }
 
-        unsafe {
-            let mut ptr = features;
-            while !(*ptr).is_null() {
-                let c_str = CStr::from_ptr(*ptr);
-                result.push(c_str.to_string_lossy().to_string());
-                ptr = ptr.add(1);
+        // なくなったファイル
+        let mut removeds: Vec<String> = vec![];
+        for description in self.descriptions.iter() {
+            if !paths
+                .iter()
+                .any(|x| x.to_string_lossy() == description.path)
+                && !removeds.contains(&description.path)
+            {
+                removeds.push(description.path.clone());
             }
         }
+        for path in removeds {
+            changed_p = true;
+            reports.push(ScanReport {
+                path,
+                status: ScanStatus::Removed,
+            });
+        }
 
-        result
+        descriptions.sort_by_key(|x| x.name.clone());
+        self.descriptions = descriptions;
+        if changed_p {
+            self.save()?;
+        }
+        self.blocklist_save()?;
+        Ok((reports, changed_p))
+    }
+
+    /// 次のスキャンでもう一度試す
+    pub fn unblock(&mut self, path: &str) -> Result<()> {
+        self.blocklist.retain(|x| x.path != path);
+        self.blocklist_save()
     }
 
     fn find_clap_files(&self, dir: &Path) -> Vec<PathBuf> {
@@ -77,76 +206,239 @@ impl ClapManager {
         if let Ok(entries) = fs::read_dir(dir) {
             for entry in entries.flatten() {
                 let path = entry.path();
-                if path.is_dir() {
+                if path.extension().and_then(OsStr::to_str) == Some("clap") {
+                    // *.clap ファイルと macOS のバンドルのディレクトリ
+                    result.push(path);
+                } else if path.is_dir() {
                     // サブディレクトリを再帰的に探索
                     result.extend(self.find_clap_files(&path));
-                } else if path.extension().and_then(OsStr::to_str) == Some("clap") {
-                    // *.clap ファイルだけ追加
-                    result.push(path);
                 }
             }
         }
         result
     }
 
-    fn save(&mut self) {
-        if let Some(parent) = self.setting_path.parent() {
-            create_dir_all(parent).unwrap();
+    fn save(&mut self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.descriptions)?;
+        write_replace(&self.setting_path, &json)
+    }
+
+    fn blocklist_save(&mut self) -> Result<()> {
+        let json = serde_json::to_string_pretty(&self.blocklist)?;
+        write_replace(&self.blocklist_path, &json)
+    }
+}
+
+/// プラグインのホストが読んでいる途中でも壊れたものが見えないように、別のファイルに書いてから置き換える
+fn write_replace(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// CLAP の仕様 (entry.h) の順で CLAP_PATH、設定、ユーザー、システムのディレクトリ
+pub fn clap_search_dirs(extra_dirs: &[String]) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    if let Some(clap_path) = env::var_os("CLAP_PATH") {
+        dirs.extend(split_paths(&clap_path));
+    }
+    dirs.extend(extra_dirs.iter().map(PathBuf::from));
+
+    let env_dir = |name: &str| env::var_os(name).map(PathBuf::from);
+    if cfg!(target_os = "windows") {
+        if let Some(dir) = env_dir("LOCALAPPDATA") {
+            dirs.push(dir.join("Programs\\Common\\CLAP"));
         }
-        let mut file = File::create(&self.setting_path).unwrap();
-        let json = serde_json::to_string_pretty(&self.descriptions).unwrap();
-        file.write_all(json.as_bytes()).unwrap();
+        let common = env_dir("COMMONPROGRAMFILES")
+            .unwrap_or(PathBuf::from("C:\\Program Files\\Common Files"));
+        dirs.push(common.join("CLAP"));
+    } else if cfg!(target_os = "macos") {
+        if let Some(dir) = env_dir("HOME") {
+            dirs.push(dir.join("Library/Audio/Plug-Ins/CLAP"));
+        }
+        dirs.push(PathBuf::from("/Library/Audio/Plug-Ins/CLAP"));
+    } else {
+        if let Some(dir) = env_dir("HOME") {
+            dirs.push(dir.join(".clap"));
+        }
+        dirs.push(PathBuf::from("/usr/lib/clap"));
     }
 
-    fn scan_plugin_file(&mut self, path: &Path) -> Result<()> {
-        unsafe {
-            if let Ok(lib) = Library::new(path) {
-                let entry: Symbol<*const clap_plugin_entry> = lib.get(b"clap_entry\0")?;
-                let entry = &**entry;
-                let c_path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
-                entry.init.unwrap()(c_path.as_ptr());
-                let factory =
-                    (entry.get_factory.unwrap())(b"clap.plugin-factory\0".as_ptr() as *const _)
-                        as *const clap_plugin_factory;
+    let mut result: Vec<PathBuf> = vec![];
+    for dir in dirs {
+        if !dir.as_os_str().is_empty() && !result.contains(&dir) {
+            result.push(dir);
+        }
+    }
+    result
+}
 
-                if factory.is_null() {
-                    return Ok(());
-                }
-                let factory = &*factory;
-                let count = (factory.get_plugin_count.unwrap())(factory);
-                for index in 0..count {
-                    let descriptor = (factory.get_plugin_descriptor.unwrap())(factory, index);
-                    let descriptor = &*descriptor;
-                    self.descriptions.push(Description {
-                        id: CStr::from_ptr(descriptor.id).to_string_lossy().to_string(),
-                        path: path.to_str().unwrap().to_string(),
-                        modified: metadata(path)
-                            .unwrap()
-                            .modified()
-                            .unwrap()
-                            .elapsed()
-                            .unwrap()
-                            .as_secs(),
-                        index,
-                        name: CStr::from_ptr(descriptor.name)
-                            .to_string_lossy()
-                            .to_string(),
-                        vender: CStr::from_ptr(descriptor.vendor)
-                            .to_string_lossy()
-                            .to_string(),
-                        version: CStr::from_ptr(descriptor.version)
-                            .to_string_lossy()
-                            .to_string(),
-                        description: CStr::from_ptr(descriptor.description)
-                            .to_string_lossy()
-                            .to_string(),
-                        features: self.features_to_vec(descriptor.features),
-                    });
-                }
+/// macOS のバンドル (Foo.clap/Contents/MacOS/Foo) なら中の実行ファイル、それ以外はそのまま
+/// clap_entry.init にはバンドルのパスを渡す
+pub fn clap_binary_path(path: &Path) -> PathBuf {
+    if !path.is_dir() {
+        return path.to_path_buf();
+    }
+    let dir = path.join("Contents").join("MacOS");
+    if let Some(stem) = path.file_stem() {
+        let binary = dir.join(stem);
+        if binary.is_file() {
+            return binary;
+        }
+    }
+    fs::read_dir(&dir)
+        .ok()
+        .and_then(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.path())
+                .find(|path| path.is_file())
+        })
+        .unwrap_or(path.to_path_buf())
+}
+
+/// (変更時刻の UNIX 秒, サイズ) バンドルは中の実行ファイルで 取れなければ 0
+pub fn file_stamp(path: &Path) -> (u64, u64) {
+    let Ok(metadata) = metadata(clap_binary_path(path)) else {
+        return (0, 0);
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    (modified, metadata.len())
+}
+
+/// 落ちてもハングしても巻き込まれないように子プロセスでスキャンする
+fn scan_in_subprocess(scanner: &Path, path: &Path) -> Result<Vec<Description>> {
+    let mut child = Command::new(scanner)
+        .arg(SCAN_ARG)
+        .arg(path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // 出力が多いとパイプが詰まって終わらないので別スレッドで読む
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > SCAN_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("timed out after {}s", SCAN_TIMEOUT.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    let output = reader.join().unwrap_or_default();
 
-                (entry.deinit.unwrap())();
+    let result = output
+        .lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(SCAN_RESULT_PREFIX));
+    let Some(result) = result else {
+        return Err(anyhow!("scanner exited without result ({})", status));
+    };
+    let result: std::result::Result<Vec<Description>, String> = serde_json::from_str(result)?;
+    result.map_err(|e| anyhow!(e))
+}
+
+/// スキャン用の子プロセスで呼んで結果を標準出力に書く
+pub fn scan_and_print(path: &Path) {
+    let result = scan_plugin_file(path).map_err(|e| e.to_string());
+    let json = serde_json::to_string(&result).unwrap();
+    println!("{}{}", SCAN_RESULT_PREFIX, json);
+}
+
+fn scan_plugin_file(path: &Path) -> Result<Vec<Description>> {
+    let mut descriptions = vec![];
+    let (modified, size) = file_stamp(path);
+    unsafe {
+        let lib = Library::new(clap_binary_path(path))?;
+        let entry: Symbol<*const clap_plugin_entry> = lib.get(b"clap_entry\0")?;
+        let entry = &**entry;
+        let c_path = CString::new(path.to_string_lossy().as_bytes())?;
+        let init = entry.init.ok_or(anyhow!("clap_entry.init is null"))?;
+        if !init(c_path.as_ptr()) {
+            return Err(anyhow!("clap_entry.init failed"));
+        }
+        let get_factory = entry
+            .get_factory
+            .ok_or(anyhow!("clap_entry.get_factory is null"))?;
+        let factory = get_factory(b"clap.plugin-factory\0".as_ptr() as *const _)
+            as *const clap_plugin_factory;
+
+        if !factory.is_null() {
+            let factory = &*factory;
+            let count = (factory.get_plugin_count.unwrap())(factory);
+            for index in 0..count {
+                let descriptor = (factory.get_plugin_descriptor.unwrap())(factory, index);
+                if descriptor.is_null() {
+                    continue;
+                }
+                let descriptor = &*descriptor;
+                descriptions.push(Description {
+                    id: CStr::from_ptr(descriptor.id).to_string_lossy().to_string(),
+                    path: path.to_string_lossy().to_string(),
+                    modified,
+                    size,
+                    index,
+                    name: CStr::from_ptr(descriptor.name)
+                        .to_string_lossy()
+                        .to_string(),
+                    vender: CStr::from_ptr(descriptor.vendor)
+                        .to_string_lossy()
+                        .to_string(),
+                    version: CStr::from_ptr(descriptor.version)
+                        .to_string_lossy()
+                        .to_string(),
+                    description: CStr::from_ptr(descriptor.description)
+                        .to_string_lossy()
+                        .to_string(),
+                    features: features_to_vec(descriptor.features),
+                });
             }
         }
-        Ok(())
+
+        if let Some(deinit) = entry.deinit {
+            deinit();
+        }
+    }
+    Ok(descriptions)
+}
+
+fn features_to_vec(features: *const *const c_char) -> Vec<String> {
+    let mut result = Vec::new();
+    if features.is_null() {
+        return result;
     }
+
+    unsafe {
+        let mut ptr = features;
+        while !(*ptr).is_null() {
+            let c_str = CStr::from_ptr(*ptr);
+            result.push(c_str.to_string_lossy().to_string());
+            ptr = ptr.add(1);
+        }
+    }
+
+    result
 }
@@ -15,3 +15,59 @@ pub fn db_from_norm(norm: f32, min_db: f32, max_db: f32) -> f32 {
 pub fn linear_to_db(val: f32) -> f32 {
     20.0 * val.max(1e-20).log10()
 }
+
+/// y[1] と y[2] の間の t (0.0 - 1.0) の位置を 4 点エルミート補間
+pub fn hermite(y: [f32; 4], t: f32) -> f32 {
+    let c1 = 0.5 * (y[2] - y[0]);
+    let c2 = y[0] - 2.5 * y[1] + 2.0 * y[2] - 0.5 * y[3];
+    let c3 = 0.5 * (y[3] - y[0]) + 1.5 * (y[1] - y[2]);
+    ((c3 * t + c2) * t + c1) * t + y[1]
+}
+
+/// 直近 4 サンプルから 4 倍オーバーサンプリングしたサンプル間のピーク
+pub fn true_peak(history: [f32; 4]) -> f32 {
+    [0.25, 0.5, 0.75]
+        .into_iter()
+        .fold(history[2].abs(), |acc, t| {
+            acc.max(hermite(history, t).abs())
+        })
+}
+
+/// 基数 2 の FFT re.len() は 2 のべき乗
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f32::consts::PI / len as f32;
+        let (w_im, w_re) = angle.sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut u_re, mut u_im) = (1.0f32, 0.0f32);
+            for k in 0..len / 2 {
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * u_re - im[b] * u_im;
+                let t_im = re[b] * u_im + im[b] * u_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+                (u_re, u_im) = (u_re * w_re - u_im * w_im, u_re * w_im + u_im * w_re);
+            }
+        }
+        len <<= 1;
+    }
+}
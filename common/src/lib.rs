@@ -1,4 +1,6 @@
 pub mod audio_buffer;
+pub mod audio_file;
+pub mod channel_layout;
 pub mod clap_manager;
 pub mod dsp;
 pub mod event;
@@ -8,9 +10,17 @@ pub mod plugin_ref;
 pub mod process_data;
 pub mod process_track_context;
 pub mod protocol;
+pub mod sampler;
 pub mod shmem;
 pub mod str;
 pub mod util;
 
 pub const PIPE_CTRL_NAME: &'static str = r"\\.\pipe\sing_like_coding\ctrl";
+
+/// 専用のプロセスで動かすモジュールのパイプ
+pub fn pipe_ctrl_name(module_id: usize) -> String {
+    format!("{}.{}", PIPE_CTRL_NAME, module_id)
+}
+/// プラグインのホストとスキャンの子プロセスの実行ファイル
+pub const PLUGIN_HOST_EXE: &str = "sing_like_coding_plugin.exe";
 pub const PIPE_BUFFER_SIZE: u32 = 8092;
@@ -10,6 +10,9 @@ pub struct Module {
     pub name: String,
     pub audio_inputs: Vec<AudioInput>,
     pub state: Option<Vec<u8>>,
+    /// 専用のプラグインホストのプロセスで動かす
+    #[serde(default)]
+    pub isolated_p: bool,
 }
 
 impl Module {
@@ -25,6 +28,7 @@ impl Module {
             name,
             audio_inputs,
             state: None,
+            isolated_p: false,
         }
     }
 }
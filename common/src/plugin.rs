@@ -1,2 +1,4 @@
 pub mod description;
+pub mod note_name;
 pub mod param;
+pub mod preset;
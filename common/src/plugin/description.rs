@@ -4,7 +4,10 @@ use serde::{Deserialize, Serialize};
 pub struct Description {
     pub id: String,
     pub path: String,
+    /// 変更時刻の UNIX 秒 size と合わせてスキャンし直すかどうか決める
     pub modified: u64,
+    #[serde(default)]
+    pub size: u64,
     pub index: u32,
     pub name: String,
     pub vender: String,
@@ -0,0 +1,11 @@
+use bincode::{Decode, Encode};
+
+/// note-name でプラグインが付けたキーの名前 ドラムの Kick など
+/// port と channel は -1 なら全部
+#[derive(Clone, Debug, Encode, Decode, PartialEq)]
+pub struct NoteName {
+    pub name: String,
+    pub port: i16,
+    pub key: i16,
+    pub channel: i16,
+}
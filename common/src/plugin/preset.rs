@@ -0,0 +1,12 @@
+use bincode::{Decode, Encode};
+
+/// preset-discovery で見つけたプラグイン付属のプリセット
+/// preset-load の from_location にそのまま渡す
+#[derive(Clone, Debug, Encode, Decode, PartialEq)]
+pub struct FactoryPreset {
+    pub name: String,
+    pub location_kind: u32,
+    /// location_kind が CLAP_PRESET_DISCOVERY_LOCATION_PLUGIN のときは None
+    pub location: Option<String>,
+    pub load_key: Option<String>,
+}
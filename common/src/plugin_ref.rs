@@ -1,6 +1,8 @@
+use std::time::Instant;
+
 use windows::Win32::{
-    Foundation::HANDLE,
-    System::Threading::{CreateEventA, SetEvent, WaitForSingleObject, INFINITE},
+    Foundation::{HANDLE, WAIT_OBJECT_0},
+    System::Threading::{CreateEventA, ResetEvent, SetEvent, WaitForSingleObject},
 };
 
 use crate::{
@@ -8,6 +10,13 @@ use crate::{
     shmem::{event_request_name, event_response_name},
 };
 
+// ブロックの長さのこの倍より長く応答がなければそのブロックは無音にして先に進む
+// オーディオデバイスのコールバックを止めてアンダーランさせないように短く
+const PROCESS_TIMEOUT_BLOCKS: f64 = 4.0;
+const PROCESS_TIMEOUT_MIN_MS: u32 = 10;
+// 遅れたままこれだけ応答がなければハングしたとみなす
+const PROCESS_HANG_MS: u128 = 1000;
+
 #[derive(Clone)]
 pub struct PluginRef {
     pub id: usize,
@@ -15,6 +24,16 @@ pub struct PluginRef {
     pub event_request: HANDLE,
     pub event_response: HANDLE,
     pub latency: u32,
+    /// process の往復にかかった秒数 集計したら 0 に戻す
+    pub elapsed_sum: f64,
+    pub elapsed_max: f64,
+    /// ホストで読み込みが終わるまでと、応答がなかったあとは process しないで無音を出す
+    pub loaded_p: bool,
+    pub dead_p: bool,
+    /// 応答が間に合わなかったブロックの開始時刻 前の応答が来るまで次を頼まない
+    pub late_since: Option<Instant>,
+    /// 専用のプロセスで動いているので落ちてもほかは巻き込まない
+    pub isolated_p: bool,
 }
 
 impl PluginRef {
@@ -45,12 +64,61 @@ impl PluginRef {
             event_request,
             event_response,
             latency: 0,
+            elapsed_sum: 0.0,
+            elapsed_max: 0.0,
+            loaded_p: false,
+            dead_p: false,
+            late_since: None,
+            isolated_p: false,
         })
     }
 
     pub fn process(&mut self) -> anyhow::Result<()> {
+        if !self.loaded_p || self.dead_p {
+            self.process_data_mut().silence_out();
+            return Ok(());
+        }
+        if let Some(late_since) = self.late_since {
+            // 前のブロックをまだ処理している 応答が来ていなければ今回も無音
+            let event = unsafe { WaitForSingleObject(self.event_response, 0) };
+            if event != WAIT_OBJECT_0 {
+                if late_since.elapsed().as_millis() >= PROCESS_HANG_MS {
+                    log::error!("plugin {} hung", self.id);
+                    self.dead_p = true;
+                }
+                self.process_data_mut().silence_out();
+                return Ok(());
+            }
+            self.late_since = None;
+        }
+        let start = Instant::now();
         unsafe { SetEvent(self.event_request) }?;
-        unsafe { WaitForSingleObject(self.event_response, INFINITE) };
+        let event = unsafe { WaitForSingleObject(self.event_response, self.process_timeout_ms()) };
+        if event != WAIT_OBJECT_0 {
+            log::warn!("plugin {} did not respond in time {:?}", self.id, event);
+            self.late_since = Some(start);
+            self.process_data_mut().silence_out();
+            return Ok(());
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        self.elapsed_sum += elapsed;
+        self.elapsed_max = self.elapsed_max.max(elapsed);
+        Ok(())
+    }
+
+    fn process_timeout_ms(&self) -> u32 {
+        let process_data = self.process_data();
+        let block_ms = process_data.nframes as f64 / process_data.sample_rate * 1000.0;
+        ((block_ms * PROCESS_TIMEOUT_BLOCKS).ceil() as u32).max(PROCESS_TIMEOUT_MIN_MS)
+    }
+
+    /// ホストを立ち上げ直したら読み込み直すまで待つ
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        unsafe { ResetEvent(self.event_request) }?;
+        unsafe { ResetEvent(self.event_response) }?;
+        self.loaded_p = false;
+        self.dead_p = false;
+        self.late_since = None;
         Ok(())
     }
 
@@ -1,3 +1,5 @@
+use std::ptr::addr_of_mut;
+
 use clap_sys::{
     fixedpoint::{clap_beattime, clap_sectime},
     id::clap_id,
@@ -5,7 +7,7 @@ use clap_sys::{
 
 use crate::dsp::linear_to_db;
 
-pub const MAX_CHANNELS: usize = 2;
+pub const MAX_CHANNELS: usize = 8;
 pub const MAX_FRAMES: usize = 2048;
 pub const MAX_EVENTS: usize = 128;
 pub const MAX_PORTS: usize = 8;
@@ -34,6 +36,16 @@ pub struct ProcessData {
     pub events_input: [Event; MAX_EVENTS],
     pub nevents_output: usize,
     pub events_output: [Event; MAX_EVENTS],
+    /// MAX_EVENTS に入りきらず次のブロックの頭に回すイベント
+    pub nevents_input_spill: usize,
+    pub events_input_spill: [Event; MAX_EVENTS],
+    pub nevents_output_spill: usize,
+    pub events_output_spill: [Event; MAX_EVENTS],
+    /// 次のブロックに回しても入りきらずに捨てたイベントの数 読んだ側が 0 に戻す
+    pub nevents_input_dropped: usize,
+    pub nevents_output_dropped: usize,
+    /// プラグインの note-name が変わった 読んだ側が 0 に戻す
+    pub note_names_changed_p: u8,
     pub nports_in: usize,
     pub buffer_in: [[[f32; MAX_FRAMES]; MAX_CHANNELS]; MAX_PORTS],
     pub nchannels_in: [usize; MAX_PORTS],
@@ -56,6 +68,32 @@ pub struct Event {
     pub delay: usize,
 }
 
+impl Event {
+    fn note(kind: EventKind, key: i16, velocity: f64, channel: i16, delay: usize) -> Self {
+        Self {
+            kind,
+            key,
+            velocity,
+            channel,
+            param_id: 0,
+            value: 0.0,
+            delay,
+        }
+    }
+
+    fn param_value(param_id: clap_id, value: f64, delay: usize) -> Self {
+        Self {
+            kind: EventKind::ParamValue,
+            key: 0,
+            velocity: 0.0,
+            channel: 0,
+            param_id,
+            value,
+            delay,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug)]
 pub enum EventKind {
@@ -102,6 +140,29 @@ impl ProcessData {
                 value: 0.0,
                 delay: 0,
             }; MAX_EVENTS],
+            nevents_input_spill: 0,
+            events_input_spill: [Event {
+                kind: EventKind::NoteOn,
+                key: 0,
+                velocity: 0.0,
+                channel: 0,
+                param_id: 0,
+                value: 0.0,
+                delay: 0,
+            }; MAX_EVENTS],
+            nevents_output_spill: 0,
+            events_output_spill: [Event {
+                kind: EventKind::NoteOn,
+                key: 0,
+                velocity: 0.0,
+                channel: 0,
+                param_id: 0,
+                value: 0.0,
+                delay: 0,
+            }; MAX_EVENTS],
+            nevents_input_dropped: 0,
+            nevents_output_dropped: 0,
+            note_names_changed_p: 0,
             nports_in: 1,
             buffer_in: [[[0.0; MAX_FRAMES]; MAX_CHANNELS]; MAX_PORTS],
             nchannels_in: [2; MAX_PORTS],
@@ -113,6 +174,22 @@ impl ProcessData {
         }
     }
 
+    /// 1 MiB を超えるのでスタックを通さずにヒープに作る
+    pub fn new_boxed() -> Box<Self> {
+        let mut this = Box::<Self>::new_zeroed();
+        let ptr = this.as_mut_ptr();
+        let event = Event::note(EventKind::NoteOn, 0, 0.0, 0, 0);
+        unsafe {
+            // EventKind は 0 が有効な値ではないので events だけは書いておく ほかは 0 でよい
+            addr_of_mut!((*ptr).events_input).write([event; MAX_EVENTS]);
+            addr_of_mut!((*ptr).events_output).write([event; MAX_EVENTS]);
+            addr_of_mut!((*ptr).events_input_spill).write([event; MAX_EVENTS]);
+            addr_of_mut!((*ptr).events_output_spill).write([event; MAX_EVENTS]);
+            addr_of_mut!((*ptr).nframes).write(MAX_FRAMES);
+            this.assume_init()
+        }
+    }
+
     pub fn peak(&self, port: usize, channel: usize) -> f32 {
         let value = if self.constant_mask_out[port] & (1 << channel) == 0 {
             self.buffer_out[port][channel][..self.nframes]
@@ -124,9 +201,43 @@ impl ProcessData {
         linear_to_db(value)
     }
 
-    pub fn prepare(&mut self) {
-        self.nevents_input = 0;
+    /// 出力に NaN や Inf がなければ true
+    pub fn finite_p(&self) -> bool {
+        (0..self.nports_out.min(MAX_PORTS)).all(|port| {
+            (0..self.nchannels_out[port].min(MAX_CHANNELS)).all(|channel| {
+                let buffer = &self.buffer_out[port][channel];
+                if self.constant_mask_out[port] & (1 << channel) == 0 {
+                    buffer[..self.nframes].iter().all(|x| x.is_finite())
+                } else {
+                    buffer[0].is_finite()
+                }
+            })
+        })
+    }
+
+    /// 応答のないプラグインの出力を無音にする
+    pub fn silence_out(&mut self) {
         self.nevents_output = 0;
+        for port in 0..MAX_PORTS {
+            for channel in 0..MAX_CHANNELS {
+                self.buffer_out[port][channel][0] = 0.0;
+                self.constant_mask_out[port] |= 1 << channel;
+            }
+        }
+    }
+
+    pub fn prepare(&mut self) {
+        // 前のブロックで入りきらなかったものを頭に入れる
+        self.nevents_input = events_spill_take(
+            &mut self.events_input,
+            &self.events_input_spill,
+            &mut self.nevents_input_spill,
+        );
+        self.nevents_output = events_spill_take(
+            &mut self.events_output,
+            &self.events_output_spill,
+            &mut self.nevents_output_spill,
+        );
         for port in 0..MAX_PORTS {
             for channel in 0..MAX_CHANNELS {
                 self.buffer_in[port][channel][0] = 0.0;
@@ -139,72 +250,122 @@ impl ProcessData {
     }
 
     pub fn input_note_on(&mut self, key: i16, velocity: f64, channel: i16, delay: usize) {
-        if self.nevents_input == MAX_EVENTS {
-            panic!();
-        }
-        self.events_input[self.nevents_input].kind = EventKind::NoteOn;
-        self.events_input[self.nevents_input].key = key;
-        self.events_input[self.nevents_input].velocity = velocity;
-        self.events_input[self.nevents_input].channel = channel;
-        self.events_input[self.nevents_input].delay = delay;
-        self.nevents_input += 1;
+        self.input_push(Event::note(
+            EventKind::NoteOn,
+            key,
+            velocity,
+            channel,
+            delay,
+        ));
     }
 
-    pub fn input_note_off(&mut self, key: i16, channel: i16, delay: usize) {
-        if self.nevents_input == MAX_EVENTS {
-            panic!();
-        }
-        self.events_input[self.nevents_input].kind = EventKind::NoteOff;
-        self.events_input[self.nevents_input].key = key;
-        self.events_input[self.nevents_input].velocity = 0.0;
-        self.events_input[self.nevents_input].channel = channel;
-        self.events_input[self.nevents_input].delay = delay;
-        self.nevents_input += 1;
+    pub fn input_note_off(&mut self, key: i16, velocity: f64, channel: i16, delay: usize) {
+        self.input_push(Event::note(
+            EventKind::NoteOff,
+            key,
+            velocity,
+            channel,
+            delay,
+        ));
     }
 
     pub fn input_param_value(&mut self, param_id: clap_id, value: f64, delay: usize) {
-        if self.nevents_input == MAX_EVENTS {
-            panic!();
-        }
-        self.events_input[self.nevents_input].kind = EventKind::ParamValue;
-        self.events_input[self.nevents_input].param_id = param_id;
-        self.events_input[self.nevents_input].value = value;
-        self.events_input[self.nevents_input].delay = delay;
-        self.nevents_input += 1;
+        self.input_push(Event::param_value(param_id, value, delay));
     }
 
     pub fn output_note_on(&mut self, key: i16, velocity: f64, channel: i16, delay: usize) {
-        if self.nevents_output == MAX_EVENTS {
-            panic!();
-        }
-        self.events_output[self.nevents_output].kind = EventKind::NoteOn;
-        self.events_output[self.nevents_output].key = key;
-        self.events_output[self.nevents_output].velocity = velocity;
-        self.events_output[self.nevents_output].channel = channel;
-        self.events_output[self.nevents_output].delay = delay;
-        self.nevents_output += 1;
+        self.output_push(Event::note(
+            EventKind::NoteOn,
+            key,
+            velocity,
+            channel,
+            delay,
+        ));
     }
 
-    pub fn output_note_off(&mut self, key: i16, channel: i16, delay: usize) {
-        if self.nevents_output == MAX_EVENTS {
-            panic!();
-        }
-        self.events_output[self.nevents_output].kind = EventKind::NoteOff;
-        self.events_output[self.nevents_output].key = key;
-        self.events_output[self.nevents_output].velocity = 0.0;
-        self.events_output[self.nevents_output].channel = channel;
-        self.events_output[self.nevents_output].delay = delay;
-        self.nevents_output += 1;
+    pub fn output_note_off(&mut self, key: i16, velocity: f64, channel: i16, delay: usize) {
+        self.output_push(Event::note(
+            EventKind::NoteOff,
+            key,
+            velocity,
+            channel,
+            delay,
+        ));
     }
 
     pub fn output_param_value(&mut self, param_id: clap_id, value: f64, delay: usize) {
-        if self.nevents_output == MAX_EVENTS {
-            panic!();
+        self.output_push(Event::param_value(param_id, value, delay));
+    }
+
+    fn input_push(&mut self, event: Event) {
+        if self.nevents_input < MAX_EVENTS {
+            self.events_input[self.nevents_input] = event;
+            self.nevents_input += 1;
+        } else if events_push(
+            &mut self.events_input_spill,
+            &mut self.nevents_input_spill,
+            event,
+        ) {
+            self.nevents_input_dropped += 1;
         }
-        self.events_output[self.nevents_output].kind = EventKind::ParamValue;
-        self.events_output[self.nevents_output].param_id = param_id;
-        self.events_output[self.nevents_output].value = value;
-        self.events_output[self.nevents_output].delay = delay;
-        self.nevents_output += 1;
     }
+
+    fn output_push(&mut self, event: Event) {
+        if self.nevents_output < MAX_EVENTS {
+            self.events_output[self.nevents_output] = event;
+            self.nevents_output += 1;
+        } else if events_push(
+            &mut self.events_output_spill,
+            &mut self.nevents_output_spill,
+            event,
+        ) {
+            self.nevents_output_dropped += 1;
+        }
+    }
+}
+
+/// 次のブロックの頭で delay 0 にして渡す 返すのは入れた数
+fn events_spill_take(
+    events: &mut [Event; MAX_EVENTS],
+    spill: &[Event; MAX_EVENTS],
+    nspill: &mut usize,
+) -> usize {
+    for (event, spilled) in events.iter_mut().zip(spill[..*nspill].iter()) {
+        *event = Event {
+            delay: 0,
+            ..*spilled
+        };
+    }
+    std::mem::take(nspill)
+}
+
+/// 次のブロックに回す分も MAX_EVENTS を超えたら
+/// 1. 一番古いパラメーターを捨てて追加する
+/// 2. パラメーターがなくて追加するのがノートオフなら一番古いノートオンを捨てて追加する
+/// 3. それ以外は追加しない
+/// ノートオフは捨てないので音が鳴りっぱなしにならない
+/// 何か捨てたら true
+fn events_push(events: &mut [Event; MAX_EVENTS], nevents: &mut usize, event: Event) -> bool {
+    if *nevents < MAX_EVENTS {
+        events[*nevents] = event;
+        *nevents += 1;
+        return false;
+    }
+    let index = events
+        .iter()
+        .position(|x| matches!(x.kind, EventKind::ParamValue))
+        .or_else(|| {
+            matches!(event.kind, EventKind::NoteOff)
+                .then(|| {
+                    events
+                        .iter()
+                        .position(|x| matches!(x.kind, EventKind::NoteOn))
+                })
+                .flatten()
+        });
+    if let Some(index) = index {
+        events.copy_within(index + 1.., index);
+        events[MAX_EVENTS - 1] = event;
+    }
+    true
 }
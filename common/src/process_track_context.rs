@@ -13,9 +13,13 @@ pub struct ProcessTrackContext {
     pub play_position: Range<usize>,
     pub loop_range: Range<usize>,
     pub on_keys: Vec<Option<i16>>,
+    /// 次のブロックの頭で止めるノート
+    pub keys_release: Vec<i16>,
     pub event_list_input: Vec<Event>,
     pub line_offset: isize,
     pub line_offset_stack: Vec<isize>,
+    /// (lane_index, line, time) 鳴らし始める audio clip
+    pub audio_clip_starts: Vec<(usize, usize, usize)>,
     pub plugins: Vec<PluginRef>,
 }
 
@@ -25,6 +29,20 @@ unsafe impl Sync for ProcessTrackContext {}
 impl ProcessTrackContext {
     pub fn prepare(&mut self) {
         self.event_list_input.clear();
+        for key in self.keys_release.drain(..) {
+            self.event_list_input.push(Event::NoteOff(key, 0.0, 0));
+        }
+        self.audio_clip_starts.clear();
         self.buffer.ensure_buffer(self.nchannels, self.nframes);
     }
+
+    /// take を消したり置き換えたりすると on_keys のレーンの番号がずれるので
+    /// lane_start から後ろのレーンで鳴っているノートは止める
+    pub fn notes_release_from(&mut self, lane_start: usize) {
+        for key in self.on_keys.iter_mut().skip(lane_start) {
+            if let Some(key) = key.take() {
+                self.keys_release.push(key);
+            }
+        }
+    }
 }
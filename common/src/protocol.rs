@@ -1,31 +1,74 @@
 use bincode::{config, Decode, Encode};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::{audio_buffer::AudioBuffer, module::ModuleId, plugin::param::Param};
+use crate::{
+    audio_buffer::AudioBuffer,
+    channel_layout::ChannelLayout,
+    module::ModuleId,
+    plugin::{note_name::NoteName, param::Param, preset::FactoryPreset},
+};
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
 pub enum MainToPlugin {
     Hwnd(isize),
+    AudioConfig(f64, u32, u32), // sample_rate, min_frames_count, max_frames_count
     Load(ModuleId, String, bool, Option<Vec<u8>>),
     Unload(usize),
+    ChannelLayout(ModuleId, ChannelLayout),
     GuiOpen(ModuleId),
     Params(ModuleId),
+    NoteNames(ModuleId),
     StateLoad(ModuleId, Vec<u8>),
     StateSave(ModuleId),
-    Scan,
+    Presets(ModuleId),
+    PresetLoad(ModuleId, FactoryPreset),
+    /// プラグインのプロセスを立ち上げ直す メイン側の Communicator が処理する
+    Restart,
+    /// モジュール専用のプロセスを立ち上げる メイン側の Communicator が処理する
+    Isolate(ModuleId),
     Quit,
 }
 
+impl MainToPlugin {
+    pub fn module_id(&self) -> Option<ModuleId> {
+        match self {
+            MainToPlugin::Load(id, ..)
+            | MainToPlugin::Unload(id)
+            | MainToPlugin::ChannelLayout(id, _)
+            | MainToPlugin::GuiOpen(id)
+            | MainToPlugin::Params(id)
+            | MainToPlugin::NoteNames(id)
+            | MainToPlugin::StateLoad(id, _)
+            | MainToPlugin::StateSave(id)
+            | MainToPlugin::Presets(id)
+            | MainToPlugin::PresetLoad(id, _)
+            | MainToPlugin::Isolate(id) => Some(*id),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub enum PluginToMain {
     DidHwnd,
+    DidAudioConfig,
     DidLoad(usize, u32), // id, latency
+    /// claps.json にないなどで読み込めなかった モジュールは無音のまま
+    LoadFailed(ModuleId, String),
     DidUnload(ModuleId),
+    DidChannelLayout,
     DidGuiOpen,
     DidParams(Vec<Param>),
+    DidNoteNames(ModuleId, Vec<NoteName>),
     DidStateLoad,
     DidStateSave(ModuleId, Vec<u8>),
-    DidScan,
+    /// None はまだ集めている途中
+    DidPresets(Option<Vec<FactoryPreset>>),
+    DidPresetLoad(bool),
+    DidRestart,
+    DidIsolate,
+    /// 返事の前にプロセスが落ちた (そのとき送っていたモジュール)
+    HostDied(Option<ModuleId>),
     Quit,
 }
 
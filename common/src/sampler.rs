@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+/// CLAP プラグインの代わりにプラグインホストの中で動く組み込みサンプラー
+pub const SAMPLER_PLUGIN_ID: &str = "sing_like_coding.sampler";
+pub const SAMPLER_NAME: &str = "Sampler";
+
+/// Module::state に入れる
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SamplerState {
+    pub zones: Vec<Zone>,
+}
+
+impl SamplerState {
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// 1 ファイルなら全鍵盤、複数ならドラムのように 1 キーずつ並べる
+    pub fn from_paths(paths: &[String]) -> Self {
+        let zones = if paths.len() == 1 {
+            vec![Zone::new(paths[0].clone())]
+        } else {
+            paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| {
+                    let key = (36 + i as i16).min(127);
+                    Zone {
+                        key_min: key,
+                        key_max: key,
+                        root_key: key,
+                        mode: PlayMode::OneShot,
+                        ..Zone::new(path.clone())
+                    }
+                })
+                .collect()
+        };
+        Self { zones }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum PlayMode {
+    /// ノートオフでリリースに入る
+    #[default]
+    Gate,
+    /// ノートオフを無視して最後まで鳴らす
+    OneShot,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Zone {
+    pub path: String,
+    pub key_min: i16,
+    pub key_max: i16,
+    /// このキーで元の音程
+    pub root_key: i16,
+    /// 0 - 127
+    pub velocity_min: f64,
+    pub velocity_max: f64,
+    /// dB
+    pub gain: f32,
+    /// 秒
+    pub attack: f32,
+    pub decay: f32,
+    /// 0.0 - 1.0
+    pub sustain: f32,
+    pub release: f32,
+    /// ファイルのフレーム位置 (start, end)
+    pub loop_range: Option<(usize, usize)>,
+    pub mode: PlayMode,
+    /// 同じグループの他の音を止める
+    pub choke_group: Option<u8>,
+}
+
+impl Zone {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            key_min: 0,
+            key_max: 127,
+            root_key: 60,
+            velocity_min: 0.0,
+            velocity_max: 127.0,
+            gain: 0.0,
+            attack: 0.001,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.05,
+            loop_range: None,
+            mode: PlayMode::Gate,
+            choke_group: None,
+        }
+    }
+
+    pub fn contains(&self, key: i16, velocity: f64) -> bool {
+        self.key_min <= key
+            && key <= self.key_max
+            && self.velocity_min <= velocity
+            && velocity <= self.velocity_max
+    }
+}
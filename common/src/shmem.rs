@@ -6,6 +6,7 @@ use windows::core::PCSTR;
 use crate::str::to_pcstr;
 
 pub const SONG_STATE_NAME: &str = "SingLikeCoding.Song.State";
+pub const TAP_STATE_NAME: &str = "SingLikeCoding.Tap.State";
 
 pub fn process_data_name(id: usize) -> String {
     format!("SingLikeCoding.Process.Data.{}", id)
@@ -56,11 +56,7 @@ impl<'a> Default for AppMain<'a> {
         Singer::start_listener(singer.clone(), recevier_from_ui);
         Singer::start_listener_midi(singer.clone(), receiver_midi);
 
-        let mut device = Device::open_default(singer.clone()).unwrap();
-        device.start().unwrap();
-        let device = Some(device);
-
-        let app_state = AppState::new(
+        let mut app_state = AppState::new(
             singer.lock().unwrap().song.clone(),
             sender_to_singer,
             receiver_from_audio,
@@ -68,6 +64,14 @@ impl<'a> Default for AppMain<'a> {
             receiver_communicator_to_main_thread,
             sender_midi,
         );
+
+        let mut device = Device::open(singer.clone(), &app_state.config).unwrap();
+        device.start().unwrap();
+        app_state
+            .audio_config_send(device.sample_rate(), device.max_frames_count())
+            .unwrap();
+        let device = Some(device);
+
         let view = RootView::new();
 
         Self {
@@ -111,6 +115,9 @@ impl<'a> eframe::App for AppMain<'a> {
             self.singer.lock().unwrap().gui_context = Some(ctx.clone());
 
             self.state.gui_context = Some(ctx.clone());
+
+            // 新しいものと変わったものだけスキャンする
+            self.state.plugin_scan_background();
         }
         let _ = self.view.view(ctx, &mut self.device, &mut self.state);
 
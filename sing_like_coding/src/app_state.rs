@@ -5,19 +5,23 @@ use std::{
     io::Write,
     path::PathBuf,
     sync::mpsc::{Receiver, Sender},
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Result;
 use arboard::Clipboard;
 use clap_sys::id::clap_id;
 use common::{
+    channel_layout::ChannelLayout,
+    clap_manager::{ClapManager, ScanReport},
     dsp::{db_from_norm, db_to_norm},
     event::Event,
-    module::{AudioInput, Module, ModuleIndex},
-    plugin::{description::Description, param::Param},
+    module::{AudioInput, Module, ModuleId, ModuleIndex},
+    plugin::{description::Description, note_name::NoteName, param::Param, preset::FactoryPreset},
+    process_data::MAX_FRAMES,
     protocol::{MainToPlugin, PluginToMain},
-    shmem::{open_shared_memory, SONG_STATE_NAME},
+    sampler::{SamplerState, SAMPLER_NAME, SAMPLER_PLUGIN_ID},
+    shmem::{open_shared_memory, SONG_STATE_NAME, TAP_STATE_NAME},
 };
 use eframe::egui::Color32;
 use midly::{MidiMessage, Smf};
@@ -29,9 +33,15 @@ use crate::{
     config::Config,
     eval::Eval,
     midi_device::MidiDevice,
-    model::{lane::Lane, lane_item::LaneItem, note::Note, song::Song, track::Track},
+    model::{
+        audio_clip::AudioClip, lane::Lane, lane_item::LaneItem, note::Note, pan_law::PanLaw,
+        song::Song, track::Track,
+    },
+    plugin_preset,
+    plugin_scanner::PluginScanner,
     singer::{AudioToMain, MainToAudio},
     song_state::SongState,
+    tap::{TapSource, TapState},
     util::midi_tick_to_line_delay,
     view::{
         root_view::Route,
@@ -39,6 +49,9 @@ use crate::{
     },
 };
 
+// 立ち上げ直してもすぐ落ちるならそれ以上は自動で立ち上げない
+const PLUGIN_HOST_RESTART_INTERVAL: Duration = Duration::from_secs(3);
+
 #[derive(Clone)]
 pub enum UiCommand {
     Command,
@@ -52,6 +65,11 @@ pub enum UiCommand {
     Loop,
     LoopRange,
     Mixer(MixerCommand),
+    MetronomeToggle,
+    MetronomeRecOnlyToggle,
+    LimiterToggle,
+    LoudnessReset,
+    TapSource(Option<TapSource>),
     Module(ModuleCommand),
     PatternToggle,
     PatternCursor(isize, isize),
@@ -61,18 +79,29 @@ pub enum UiCommand {
     PatternPaste,
     PlayCursor,
     PlayToggle,
+    Punch,
+    PunchRange,
     RecToggle,
+    RecReplaceToggle,
     Redo,
     Repeat,
     SongSave,
     Track(TrackCommand),
     TrackAdd,
+    TrackDualPan(usize, bool),
+    TrackInputChannels(usize, Vec<usize>),
+    TrackLayout(usize, ChannelLayout),
+    TrackMonitor(usize, bool),
     TrackMute(Option<usize>, Option<bool>),
     TrackPan(usize, f32),
+    TrackPanLaw(usize, PanLaw),
+    TrackPanRear(usize, f32),
+    TrackPanRight(usize, f32),
     TrackRecOn(usize),
     TrackRecOff(usize),
     TrackSolo(Option<usize>, Option<bool>),
     TrackVolume(usize, f32),
+    TrackWidth(usize, f32),
     Undo,
     Nop,
 }
@@ -269,9 +298,12 @@ pub enum FocusedPart {
 }
 
 pub struct AppState<'a> {
+    // (sample_rate, max_frames_count) プラグインのホストを立ち上げ直すときに送り直す
+    audio_config: (f64, u32),
     pub config: Config,
     pub confirm_exit_popup_p: bool,
     pub confirm_exit_popup_focus_request_p: bool,
+    pub device_start_request_p: bool,
     pub now: Instant,
     pub elapsed: f32,
     digit: Option<i64>,
@@ -288,6 +320,7 @@ pub struct AppState<'a> {
     pub rename_request_focus_p: bool,
     pub rename_track_index: Option<usize>,
     pub route: Route,
+    pub sampler_edit: Option<(ModuleIndex, SamplerState)>,
     pub select_p: bool,
     pub selection_track_min: Option<CursorTrack>,
     pub selection_track_max: Option<CursorTrack>,
@@ -299,15 +332,52 @@ pub struct AppState<'a> {
     sender_to_singer: Sender<MainToAudio>,
     receiver_from_audio: Receiver<AudioToMain>,
     sender_to_loop: Sender<MainToPlugin>,
-    sender_midi: Sender<Event>,
+    sender_midi: Sender<(Instant, Event)>,
     receiver_communicator_to_main_thread: Receiver<PluginToMain>,
     _song_state_shmem: Shmem,
     pub song_state: &'a SongState,
+    _tap_state_shmem: Shmem,
+    pub tap_state: &'a TapState,
+    /// アナライザーを開いているとき
+    pub tap_source: Option<TapSource>,
     ui_command_last: UiCommand,
     callbacks_plugin_to_main: VecDeque<Box<dyn Fn(&mut AppState, PluginToMain) -> Result<()>>>,
     pub gui_context: Option<eframe::egui::Context>,
 
     pub param_select_view_params: Vec<Param>,
+    /// 落ちたプラグインの名前 閉じるまで表示する
+    pub plugin_failed: Option<String>,
+    /// 読み込めなかったプラグインの名前と理由 閉じるまで表示する
+    pub plugin_load_failed: Option<(String, String)>,
+    /// 最後のスキャンの結果 失敗したものとブロックリストでとばしたものも
+    pub plugin_scan_reports: Vec<ScanReport>,
+    pub plugin_scanner: PluginScanner,
+    // スキャンが終わったら結果の画面を出す
+    plugin_scan_show_p: bool,
+    /// claps.json が変わるたびに増える プラグインの一覧を読み直す
+    pub claps_version: usize,
+    plugin_host_restart_p: bool,
+    plugin_host_restart_last: Option<Instant>,
+    // 立ち上げ直してすぐ落ちたので画面の Restart まで自動では立ち上げない
+    plugin_host_restart_stop_p: bool,
+    // 専用のプロセスのモジュールを最後に立ち上げ直した時刻
+    module_restart_lasts: HashMap<ModuleId, Instant>,
+    // 同じく自動では立ち上げない専用のプロセスのモジュール
+    module_restart_stops: HashSet<ModuleId>,
+    // state を取り終えて立ち上げ直すのを待っている専用のプロセスのモジュール
+    module_restarting_ids: HashSet<ModuleId>,
+    /// note-name でプラグインが付けたキーの名前
+    pub note_names: HashMap<ModuleId, Vec<NoteName>>,
+    // SongState::note_names_version が変わったら聞き直す
+    note_names_version: usize,
+    /// プリセットの画面で開いているモジュール
+    pub preset_module_id: Option<ModuleId>,
+    /// preset_module_id のプラグイン付属のプリセット
+    pub factory_presets: Vec<FactoryPreset>,
+    /// プラグインのプロセスがまだ集めている途中なら聞き直す時刻
+    pub factory_presets_retry_at: Option<Instant>,
+    /// ユーザーのプリセットを保存、削除するたびに増える
+    pub presets_version: usize,
 
     // for MainView layout.
     pub offset_tracks: Vec<f32>,
@@ -326,15 +396,19 @@ impl<'a> AppState<'a> {
         receiver_from_audio: Receiver<AudioToMain>,
         sender_to_loop: Sender<MainToPlugin>,
         receiver_communicator_to_main_thread: Receiver<PluginToMain>,
-        sender_midi: Sender<Event>,
+        sender_midi: Sender<(Instant, Event)>,
     ) -> Self {
         let song_state_shmem = open_shared_memory::<SongState>(SONG_STATE_NAME).unwrap();
         let song_state = unsafe { &*(song_state_shmem.as_ptr() as *const SongState) };
+        let tap_state_shmem = open_shared_memory::<TapState>(TAP_STATE_NAME).unwrap();
+        let tap_state = unsafe { &*(tap_state_shmem.as_ptr() as *const TapState) };
 
         let mut this = Self {
+            audio_config: (48000.0, MAX_FRAMES as u32),
             config: Config::load().unwrap_or_default(),
             confirm_exit_popup_p: false,
             confirm_exit_popup_focus_request_p: true,
+            device_start_request_p: false,
             now: Instant::now(),
             elapsed: 0.0,
             digit: None,
@@ -355,6 +429,7 @@ impl<'a> AppState<'a> {
             rename_track_index: None,
             rename_request_focus_p: false,
             route: Route::Track,
+            sampler_edit: None,
             select_p: false,
             selection_track_min: Default::default(),
             selection_track_max: Default::default(),
@@ -370,11 +445,32 @@ impl<'a> AppState<'a> {
             receiver_communicator_to_main_thread,
             _song_state_shmem: song_state_shmem,
             song_state,
+            _tap_state_shmem: tap_state_shmem,
+            tap_state,
+            tap_source: None,
             ui_command_last: UiCommand::Nop,
             callbacks_plugin_to_main: Default::default(),
             gui_context: None,
 
             param_select_view_params: vec![],
+            plugin_failed: None,
+            plugin_load_failed: None,
+            plugin_scan_reports: vec![],
+            plugin_scanner: PluginScanner::new(),
+            plugin_scan_show_p: false,
+            claps_version: 0,
+            plugin_host_restart_p: false,
+            plugin_host_restart_last: None,
+            plugin_host_restart_stop_p: false,
+            module_restart_lasts: Default::default(),
+            module_restart_stops: Default::default(),
+            module_restarting_ids: Default::default(),
+            note_names: Default::default(),
+            note_names_version: 0,
+            preset_module_id: None,
+            factory_presets: vec![],
+            factory_presets_retry_at: None,
+            presets_version: 0,
 
             offset_tracks: vec![],
             offset_flatten_lanes: vec![],
@@ -392,6 +488,18 @@ impl<'a> AppState<'a> {
         this
     }
 
+    /// プラグインを実際のサンプルレートとブロックサイズで activate しなおす
+    pub fn audio_config_send(&mut self, sample_rate: f64, max_frames_count: u32) -> Result<()> {
+        self.audio_config = (sample_rate, max_frames_count);
+        self.send_to_plugin(
+            MainToPlugin::AudioConfig(sample_rate, 1, max_frames_count),
+            Box::new(|state, _| {
+                state.device_start_request_p = true;
+                Ok(())
+            }),
+        )
+    }
+
     pub fn bpm_set(&mut self, bpm: f64) -> Result<()> {
         self.send_to_audio(MainToAudio::Bpm(bpm))?;
         Ok(())
@@ -412,6 +520,11 @@ impl<'a> AppState<'a> {
         }
     }
 
+    pub fn count_in_bars_set(&mut self, bars: usize) -> Result<()> {
+        self.send_to_audio(MainToAudio::CountInBars(bars))?;
+        Ok(())
+    }
+
     pub fn cursor_up(&mut self) {
         for _ in 0..self.digit.unwrap_or(1) {
             self.cursor_track = self.cursor_track.up(&self.song);
@@ -555,6 +668,22 @@ impl<'a> AppState<'a> {
         }
     }
 
+    pub fn audio_clip_insert(&mut self) -> Result<()> {
+        if let Some(path) = FileDialog::new()
+            .add_filter("Audio", &["wav", "flac"])
+            .set_directory(song_directory())
+            .pick_file()
+        {
+            self.send_to_audio(MainToAudio::LaneItem(vec![(
+                self.cursor_track.clone(),
+                Some(LaneItem::AudioClip(AudioClip::new(
+                    path.to_string_lossy().to_string(),
+                ))),
+            )]))?;
+        }
+        Ok(())
+    }
+
     pub fn eval(&mut self, buffer: &str) -> Result<()> {
         Eval::eval(buffer, self)?;
         Ok(())
@@ -568,6 +697,20 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    /// カーソル位置の AudioClip を変更する
+    pub fn eval_audio_clip(&mut self, f: impl FnOnce(&mut AudioClip)) -> Result<()> {
+        if let Some(LaneItem::AudioClip(mut clip)) =
+            self.song.lane_item(&self.cursor_track).cloned()
+        {
+            f(&mut clip);
+            self.send_to_audio(MainToAudio::LaneItem(vec![(
+                self.cursor_track.clone(),
+                Some(LaneItem::AudioClip(clip)),
+            )]))?;
+        }
+        Ok(())
+    }
+
     pub fn eval_label(&mut self, label: String) -> Result<()> {
         self.send_to_audio(MainToAudio::LaneItem(vec![(
             self.cursor_track.clone(),
@@ -628,6 +771,27 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    pub fn metronome_volume_set(&mut self, volume: f32) -> Result<()> {
+        self.send_to_audio(MainToAudio::MetronomeVolume(volume))?;
+        Ok(())
+    }
+
+    pub fn limiter_ceiling_set(&mut self, ceiling: f32) -> Result<()> {
+        self.send_to_audio(MainToAudio::LimiterCeiling(ceiling))?;
+        Ok(())
+    }
+
+    pub fn tap_source_set(&mut self, tap_source: Option<TapSource>) -> Result<()> {
+        self.tap_source = tap_source;
+        self.send_to_audio(MainToAudio::TapSource(tap_source))?;
+        Ok(())
+    }
+
+    pub fn bad_samples_clear(&mut self) -> Result<()> {
+        self.send_to_audio(MainToAudio::BadSamplesClear)?;
+        Ok(())
+    }
+
     pub fn midi_file_read(
         &mut self,
         track_index: usize,
@@ -748,15 +912,113 @@ impl<'a> AppState<'a> {
         let module = module.unwrap();
         let module_id = module.id;
         let plugin_id = module.plugin_id.clone();
-        let state = module.state.take();
+        // ホストが落ちたときに読み込み直すので残しておく
+        let state = module.state.clone();
+        let isolated_p = module.isolated_p;
+        self.send_to_audio(MainToAudio::PluginIsolated(module_id, isolated_p))?;
+        if isolated_p {
+            self.send_to_plugin(MainToPlugin::Isolate(module_id), Box::new(|_, _| Ok(())))?;
+        }
         self.send_to_plugin(
             MainToPlugin::Load(module_id, plugin_id, gui_open_p, state),
             // TODO singer にプラグインがアクティブになったことを通知？
             Box::new(|_, _| Ok(())),
         )?;
+        let layout = self.song.tracks[module_index.0].layout;
+        if layout != ChannelLayout::Stereo {
+            self.send_to_plugin(
+                MainToPlugin::ChannelLayout(module_id, layout),
+                Box::new(|_, _| Ok(())),
+            )?;
+        }
         Ok(())
     }
 
+    /// 今の state のまま、専用のプロセスか共有のプロセスに読み込み直す
+    pub fn module_isolated_set(
+        &mut self,
+        module_index: ModuleIndex,
+        isolated_p: bool,
+    ) -> Result<()> {
+        let Some(module) = self.module_at_mut(module_index) else {
+            return Ok(());
+        };
+        if module.isolated_p == isolated_p {
+            return Ok(());
+        }
+        module.isolated_p = isolated_p;
+        let module_id = module.id;
+        self.send_to_audio(MainToAudio::ModuleIsolated(module_index, isolated_p))?;
+        self.send_to_plugin(
+            MainToPlugin::StateSave(module_id),
+            Box::new(move |state, _| {
+                state.send_to_audio(MainToAudio::PluginReset(module_id))?;
+                state.send_to_plugin(MainToPlugin::Unload(module_id), Box::new(|_, _| Ok(())))?;
+                if let Some(module_index) = state.song.module_index_by_id(module_id) {
+                    state.module_load(module_index, false)?;
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    pub fn module_isolated_all_set(&mut self, isolated_p: bool) -> Result<()> {
+        for track_index in 0..self.song.tracks.len() {
+            for module_index in 0..self.song.tracks[track_index].modules.len() {
+                self.module_isolated_set((track_index, module_index), isolated_p)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// サンプラーはメイン側の画面で編集する
+    pub fn module_open(&mut self, module_index: ModuleIndex) -> Result<()> {
+        let Some(module) = self.module_at(module_index) else {
+            return Ok(());
+        };
+        let module_id = module.id;
+        if module.plugin_id == SAMPLER_PLUGIN_ID {
+            self.send_to_plugin(
+                MainToPlugin::StateSave(module_id),
+                Box::new(move |state, _| {
+                    let sampler_state = state
+                        .module_at(module_index)
+                        .and_then(|x| x.state.as_ref())
+                        .and_then(|x| SamplerState::from_bytes(x).ok())
+                        .unwrap_or_default();
+                    state.sampler_edit = Some((module_index, sampler_state));
+                    state.route = Route::Sampler;
+                    Ok(())
+                }),
+            )?;
+        } else {
+            self.send_to_plugin(MainToPlugin::GuiOpen(module_id), Box::new(|_, _| Ok(())))?;
+            self.note_names_request(module_id)?;
+        }
+        Ok(())
+    }
+
+    /// note-name の changed はメイン側に届けられないので、
+    /// 読み込み、GUI、プリセットで変わりそうなときに聞き直す
+    fn note_names_request(&mut self, module_id: ModuleId) -> Result<()> {
+        self.send_to_plugin(MainToPlugin::NoteNames(module_id), Box::new(|_, _| Ok(())))
+    }
+
+    /// トラックの中で最初に名前を付けているモジュールのもの (ドラムのトラック)
+    pub fn note_name(&self, track_index: usize, key: i16, channel: i16) -> Option<&str> {
+        self.song.tracks[track_index]
+            .modules
+            .iter()
+            .find_map(|module| self.note_names.get(&module.id))?
+            .iter()
+            .find(|x| {
+                x.key == key
+                    && (x.channel == -1 || x.channel == channel)
+                    && (x.port == -1 || x.port == 0)
+            })
+            .map(|x| x.name.as_str())
+    }
+
     pub fn now_update(&mut self) {
         self.elapsed = self.now.elapsed().as_secs_f32();
         self.now = Instant::now();
@@ -930,10 +1192,52 @@ impl<'a> AppState<'a> {
         if let Some(module_id) = self.module_at(module_index).map(|x| x.id) {
             self.send_to_audio(MainToAudio::PluginDelete(module_index))?;
             self.send_to_plugin(MainToPlugin::Unload(module_id), Box::new(|_, _| Ok(())))?;
+            self.note_names.remove(&module_id);
         }
         Ok(())
     }
 
+    /// 終わったら結果の画面を出す
+    pub fn plugin_scan(&mut self) -> Result<()> {
+        self.plugin_scan_show_p = true;
+        self.plugin_scan_background();
+        Ok(())
+    }
+
+    /// 起動時に裏でスキャンして、変わったものだけ claps.json に反映する
+    pub fn plugin_scan_background(&mut self) {
+        self.plugin_scanner
+            .start(self.config.clap_paths.clone(), self.gui_context.clone());
+    }
+
+    pub fn plugin_scan_receive(&mut self) {
+        let Some((reports, changed_p)) = self.plugin_scanner.receive() else {
+            return;
+        };
+        self.plugin_scan_reports = reports;
+        if changed_p {
+            self.claps_version += 1;
+        }
+        if std::mem::take(&mut self.plugin_scan_show_p) {
+            self.route = Route::PluginScanResult;
+        }
+    }
+
+    pub fn clap_paths_set(&mut self, clap_paths: Vec<String>) -> Result<()> {
+        self.config.clap_paths = clap_paths;
+        self.config.save()
+    }
+
+    /// ブロックリストから外してスキャンし直す
+    pub fn plugin_unblock(&mut self, path: String) -> Result<()> {
+        // スキャン中のスレッドがブロックリストを書き戻すので終わってから
+        if self.plugin_scanner.scanning_p() {
+            return Ok(());
+        }
+        ClapManager::new().unblock(&path)?;
+        self.plugin_scan()
+    }
+
     pub fn plugin_load(&mut self, description: &Description, gui_open_p: bool) -> Result<()> {
         match self.send_to_audio(MainToAudio::PluginLoad(
             self.cursor_track.track,
@@ -952,6 +1256,167 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    pub fn sampler_load(&mut self, track_index: usize, sampler_state: &SamplerState) -> Result<()> {
+        match self.send_to_audio(MainToAudio::PluginLoad(
+            track_index,
+            SAMPLER_PLUGIN_ID.to_string(),
+            SAMPLER_NAME.to_string(),
+        ))? {
+            AudioToMain::PluginLoad(_id, song) => {
+                self.song = song;
+                let module_index = self.song.tracks[track_index].modules.len() - 1;
+                if let Some(module) = self.module_at_mut((track_index, module_index)) {
+                    module.state = Some(sampler_state.to_bytes()?);
+                }
+                self.module_load((track_index, module_index), false)?;
+            }
+            x => unreachable!("{:?}", x),
+        }
+        Ok(())
+    }
+
+    pub fn sampler_state_set(
+        &mut self,
+        module_index: ModuleIndex,
+        sampler_state: &SamplerState,
+    ) -> Result<()> {
+        if let Some(module) = self.module_at_mut(module_index) {
+            let module_id = module.id;
+            let state = sampler_state.to_bytes()?;
+            module.state = Some(state.clone());
+            self.send_to_plugin(
+                MainToPlugin::StateLoad(module_id, state),
+                Box::new(|_, _| Ok(())),
+            )?;
+            self.song_dirty_p = true;
+        }
+        Ok(())
+    }
+
+    /// プリセットの画面を開く 付属のプリセットは届いたら出す
+    pub fn presets_open(&mut self, module_index: ModuleIndex) -> Result<()> {
+        let Some(module_id) = self.module_at(module_index).map(|x| x.id) else {
+            return Ok(());
+        };
+        self.preset_module_id = Some(module_id);
+        self.factory_presets.clear();
+        self.factory_presets_request(module_id)?;
+        self.route = Route::PresetSelect;
+        Ok(())
+    }
+
+    fn factory_presets_request(&mut self, module_id: ModuleId) -> Result<()> {
+        self.factory_presets_retry_at = None;
+        self.send_to_plugin(
+            MainToPlugin::Presets(module_id),
+            Box::new(move |state, message| {
+                if state.preset_module_id != Some(module_id) {
+                    return Ok(());
+                }
+                match message {
+                    PluginToMain::DidPresets(Some(presets)) => state.factory_presets = presets,
+                    PluginToMain::DidPresets(None) => {
+                        state.factory_presets_retry_at =
+                            Some(Instant::now() + Duration::from_millis(500));
+                    }
+                    _ => {}
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    /// 付属のプリセットを集め終わるまで聞き直す
+    pub fn factory_presets_poll(&mut self) -> Result<()> {
+        if let (Some(module_id), Some(retry_at)) =
+            (self.preset_module_id, self.factory_presets_retry_at)
+        {
+            if Instant::now() >= retry_at {
+                self.factory_presets_request(module_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn presets_close(&mut self) {
+        self.preset_module_id = None;
+        self.factory_presets.clear();
+        self.factory_presets_retry_at = None;
+        self.route = Route::Track;
+    }
+
+    /// 今の state をユーザーのプリセットとして保存する
+    pub fn preset_save(&mut self, name: String) -> Result<()> {
+        let Some(module_id) = self.preset_module_id else {
+            return Ok(());
+        };
+        self.send_to_plugin(
+            MainToPlugin::StateSave(module_id),
+            Box::new(move |state, _| {
+                // DidStateSave の中身は receive_from_communicator で module.state に移してある
+                if let Some(module) = state.song.module_by_id(module_id) {
+                    if let Some(module_state) = &module.state {
+                        plugin_preset::save(&module.plugin_id, &name, module_state)?;
+                        state.presets_version += 1;
+                    }
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    pub fn preset_user_load(&mut self, name: &str) -> Result<()> {
+        let Some(module) = self
+            .preset_module_id
+            .and_then(|x| self.song.module_by_id_mut(x))
+        else {
+            return Ok(());
+        };
+        let module_id = module.id;
+        let state = plugin_preset::load(&module.plugin_id, name)?;
+        module.state = Some(state.clone());
+        self.send_to_plugin(
+            MainToPlugin::StateLoad(module_id, state),
+            Box::new(|_, _| Ok(())),
+        )?;
+        self.note_names_request(module_id)?;
+        self.song_dirty_p = true;
+        Ok(())
+    }
+
+    pub fn preset_user_delete(&mut self, name: &str) -> Result<()> {
+        let Some(module) = self
+            .preset_module_id
+            .and_then(|x| self.song.module_by_id(x))
+        else {
+            return Ok(());
+        };
+        plugin_preset::delete(&module.plugin_id, name)?;
+        self.presets_version += 1;
+        Ok(())
+    }
+
+    /// 読み込めたら state を取り直して曲に残す
+    pub fn preset_factory_load(&mut self, preset: FactoryPreset) -> Result<()> {
+        let Some(module_id) = self.preset_module_id else {
+            return Ok(());
+        };
+        self.send_to_plugin(
+            MainToPlugin::PresetLoad(module_id, preset),
+            Box::new(move |state, message| {
+                if message == PluginToMain::DidPresetLoad(true) {
+                    state.send_to_plugin(
+                        MainToPlugin::StateSave(module_id),
+                        Box::new(|_, _| Ok(())),
+                    )?;
+                    state.note_names_request(module_id)?;
+                    state.song_dirty_p = true;
+                }
+                Ok(())
+            }),
+        )
+    }
+
     pub fn plugin_sidechain(
         &mut self,
         module_index: ModuleIndex,
@@ -961,6 +1426,17 @@ impl<'a> AppState<'a> {
         Ok(())
     }
 
+    fn punch_range(&mut self) -> Result<()> {
+        if self.select_p {
+            self.run_ui_command(&UiCommand::Lane(LaneCommand::SelectMode))?;
+        }
+        if let (Some(min), Some(max)) = (&self.selection_track_min, &self.selection_track_max) {
+            let range = (min.line * 0x100)..((max.line + 1) * 0x100);
+            self.send_to_audio(MainToAudio::PunchRange(range))?;
+        }
+        Ok(())
+    }
+
     pub fn quit(&mut self) {
         let _ = self.send_to_audio(MainToAudio::Quit);
     }
@@ -978,25 +1454,199 @@ impl<'a> AppState<'a> {
         while let Ok(mut message) = self.receiver_communicator_to_main_thread.try_recv() {
             match &mut message {
                 PluginToMain::DidHwnd => {}
+                PluginToMain::DidAudioConfig => {}
                 PluginToMain::DidLoad(id, latency) => {
                     self.send_to_audio(MainToAudio::PluginLatency(*id, *latency))?;
+                    self.note_names_request(*id)?;
+                }
+                PluginToMain::LoadFailed(id, error) => {
+                    let name = self
+                        .song
+                        .module_by_id(*id)
+                        .map(|x| x.name.clone())
+                        .unwrap_or_default();
+                    log::error!("{} failed to load {}", name, error);
+                    self.plugin_load_failed = Some((name, std::mem::take(error)));
                 }
                 PluginToMain::DidUnload(_) => {}
+                PluginToMain::DidChannelLayout => {}
                 PluginToMain::DidGuiOpen => {}
                 PluginToMain::DidParams(_params) => {}
+                PluginToMain::DidNoteNames(id, note_names) => {
+                    if note_names.is_empty() {
+                        self.note_names.remove(id);
+                    } else {
+                        self.note_names.insert(*id, std::mem::take(note_names));
+                    }
+                }
                 PluginToMain::DidStateLoad => {}
                 PluginToMain::DidStateSave(id, state) => {
-                    if let Some(module) = self.song.module_by_id_mut(*id) {
-                        module.state = Some(std::mem::take(state));
+                    // ホストにないモジュールは空で返ってくるので前の state を残す
+                    if !state.is_empty() {
+                        if let Some(module) = self.song.module_by_id_mut(*id) {
+                            module.state = Some(std::mem::take(state));
+                        }
                     }
                 }
-                PluginToMain::DidScan => {}
+                PluginToMain::DidPresets(_) => {}
+                PluginToMain::DidPresetLoad(_) => {}
+                PluginToMain::DidRestart => {}
+                PluginToMain::DidIsolate => {}
+                PluginToMain::HostDied(module_id) => {
+                    self.plugin_host_failed(*module_id)?;
+                }
                 PluginToMain::Quit => {}
             }
             if let Some(callback) = self.callbacks_plugin_to_main.pop_front() {
                 callback(self, message)?;
             }
         }
+        if self.note_names_version != self.song_state.note_names_version {
+            self.note_names_version = self.song_state.note_names_version;
+            // どのモジュールかはわからないので全部
+            let module_ids = self
+                .song
+                .tracks
+                .iter()
+                .flat_map(|track| track.modules.iter().map(|module| module.id))
+                .collect::<Vec<_>>();
+            for module_id in module_ids {
+                self.note_names_request(module_id)?;
+            }
+        }
+        if self.song_state.plugin_host_dead_p {
+            self.plugin_host_failed(Some(self.song_state.dead_module_id))?;
+        } else if self.song_state.isolated_dead_module_id != usize::MAX {
+            self.plugin_host_failed(Some(self.song_state.isolated_dead_module_id))?;
+        }
+        Ok(())
+    }
+
+    /// プラグインのプロセスが落ちたかハングした
+    fn plugin_host_failed(&mut self, module_id: Option<ModuleId>) -> Result<()> {
+        if self.plugin_host_restart_p {
+            return Ok(());
+        }
+        if module_id.is_some_and(|id| self.module_restarting_ids.contains(&id)) {
+            return Ok(());
+        }
+        let module = module_id.and_then(|id| self.song.module_by_id(id));
+        if let Some(module) = module.filter(|x| x.isolated_p) {
+            let module_id = module.id;
+            let name = module.name.clone();
+            return self.module_restart(module_id, name);
+        }
+        let name = module
+            .map(|x| x.name.clone())
+            .unwrap_or("Plugin host".to_string());
+        if self.plugin_failed.is_none() {
+            log::error!("{} failed", name);
+        }
+        self.plugin_failed = Some(name);
+        if self.plugin_host_restart_stop_p {
+            return Ok(());
+        }
+        // 立ち上げ直してすぐまた落ちるなら繰り返さない
+        if self
+            .plugin_host_restart_last
+            .is_some_and(|x| x.elapsed() < PLUGIN_HOST_RESTART_INTERVAL)
+        {
+            log::error!("plugin host crashed again soon after restart, auto restart stopped");
+            self.plugin_host_restart_stop_p = true;
+            return Ok(());
+        }
+        self.plugin_host_restart()
+    }
+
+    /// 専用のプロセスだけ立ち上げ直す
+    fn module_restart(&mut self, module_id: ModuleId, name: String) -> Result<()> {
+        if self.plugin_failed.is_none() {
+            log::error!("{} failed", name);
+        }
+        self.plugin_failed = Some(name);
+        if self.module_restart_stops.contains(&module_id) {
+            return Ok(());
+        }
+        if self
+            .module_restart_lasts
+            .get(&module_id)
+            .is_some_and(|x| x.elapsed() < PLUGIN_HOST_RESTART_INTERVAL)
+        {
+            log::error!(
+                "module {module_id} crashed again soon after restart, auto restart stopped"
+            );
+            self.module_restart_stops.insert(module_id);
+            return Ok(());
+        }
+        self.module_reload(module_id)
+    }
+
+    /// 応答するなら state を取ってから立ち上げ直す
+    fn module_reload(&mut self, module_id: ModuleId) -> Result<()> {
+        self.module_restarting_ids.insert(module_id);
+        self.send_to_plugin(
+            MainToPlugin::StateSave(module_id),
+            Box::new(move |state, _| {
+                state.module_restarting_ids.remove(&module_id);
+                state.module_restart_lasts.insert(module_id, Instant::now());
+                state.send_to_audio(MainToAudio::PluginReset(module_id))?;
+                if let Some(module_index) = state.song.module_index_by_id(module_id) {
+                    // Isolate が前のプロセスを殺してから立ち上げる
+                    state.module_load(module_index, false)?;
+                }
+                Ok(())
+            }),
+        )
+    }
+
+    /// 画面の Restart 自動で立ち上げるのをやめたものもまた立ち上げる
+    pub fn plugin_failed_restart(&mut self) -> Result<()> {
+        self.plugin_failed = None;
+        self.plugin_host_restart_stop_p = false;
+        for module_id in std::mem::take(&mut self.module_restart_stops) {
+            self.module_reload(module_id)?;
+        }
+        self.plugin_host_restart()
+    }
+
+    /// 応答するなら共有のプロセスのモジュールの state を取ってから立ち上げ直す
+    fn plugin_host_restart(&mut self) -> Result<()> {
+        self.plugin_host_restart_p = true;
+        let module_ids = self
+            .song
+            .tracks
+            .iter()
+            .flat_map(|track| track.modules.iter())
+            .filter(|module| !module.isolated_p)
+            .map(|module| module.id)
+            .collect::<Vec<_>>();
+        for module_id in module_ids {
+            self.send_to_plugin(MainToPlugin::StateSave(module_id), Box::new(|_, _| Ok(())))?;
+        }
+        self.send_to_plugin(
+            MainToPlugin::Restart,
+            Box::new(|state, _| state.plugin_host_reload()),
+        )
+    }
+
+    /// 立ち上げ直したホストに共有のプロセスのモジュールを最後の state で読み込み直す
+    fn plugin_host_reload(&mut self) -> Result<()> {
+        self.send_to_audio(MainToAudio::PluginHostRestart)?;
+        self.plugin_host_restart_p = false;
+        self.plugin_host_restart_last = Some(Instant::now());
+
+        let (sample_rate, max_frames_count) = self.audio_config;
+        self.send_to_plugin(
+            MainToPlugin::AudioConfig(sample_rate, 1, max_frames_count),
+            Box::new(|_, _| Ok(())),
+        )?;
+        for track_index in 0..self.song.tracks.len() {
+            for module_index in 0..self.song.tracks[track_index].modules.len() {
+                if !self.song.tracks[track_index].modules[module_index].isolated_p {
+                    self.module_load((track_index, module_index), false)?;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -1032,6 +1682,12 @@ impl<'a> AppState<'a> {
             UiCommand::LoopRange => {
                 self.loop_range()?;
             }
+            UiCommand::Punch => {
+                self.send_to_audio(MainToAudio::Punch)?;
+            }
+            UiCommand::PunchRange => {
+                self.punch_range()?;
+            }
             UiCommand::FocusedPartNext => {
                 self.focused_part = match self.focused_part {
                     FocusedPart::Track => FocusedPart::Lane,
@@ -1052,26 +1708,79 @@ impl<'a> AppState<'a> {
                 self.send_to_audio(MainToAudio::PlayLine(self.cursor_track.line))?;
             }
             UiCommand::PlayToggle => {
-                if self.song_state.play_p {
+                if self.song_state.play_p || self.song_state.count_in_p {
                     self.send_to_audio(MainToAudio::Stop)?;
                 } else {
                     self.send_to_audio(MainToAudio::Play)?;
                 }
             }
+            UiCommand::MetronomeToggle => {
+                self.send_to_audio(MainToAudio::MetronomeToggle)?;
+            }
+            UiCommand::MetronomeRecOnlyToggle => {
+                self.send_to_audio(MainToAudio::MetronomeRecOnlyToggle)?;
+            }
+            UiCommand::LimiterToggle => {
+                self.send_to_audio(MainToAudio::LimiterToggle)?;
+            }
+            UiCommand::LoudnessReset => {
+                self.send_to_audio(MainToAudio::LoudnessReset)?;
+            }
+            UiCommand::TapSource(tap_source) => {
+                self.tap_source_set(*tap_source)?;
+            }
             UiCommand::RecToggle => {
                 self.send_to_audio(MainToAudio::RecToggle)?;
             }
+            UiCommand::RecReplaceToggle => {
+                self.send_to_audio(MainToAudio::RecReplaceToggle)?;
+            }
             UiCommand::Redo => self.redo()?,
             UiCommand::SongSave => self.song_save()?,
             UiCommand::Track(command) => self.run_track_command(&command)?,
             UiCommand::TrackAdd => {
                 TrackAdd {}.call(self)?;
             }
+            UiCommand::TrackInputChannels(track_index, input_channels) => {
+                self.send_to_audio(MainToAudio::TrackInputChannels(
+                    *track_index,
+                    input_channels.clone(),
+                ))?;
+            }
+            UiCommand::TrackDualPan(track_index, dual_pan_p) => {
+                self.send_to_audio(MainToAudio::TrackDualPan(*track_index, *dual_pan_p))?;
+            }
+            UiCommand::TrackLayout(track_index, layout) => {
+                self.send_to_audio(MainToAudio::TrackLayout(*track_index, *layout))?;
+                let module_ids = self.song.tracks[*track_index]
+                    .modules
+                    .iter()
+                    .map(|x| x.id)
+                    .collect::<Vec<_>>();
+                for module_id in module_ids {
+                    self.send_to_plugin(
+                        MainToPlugin::ChannelLayout(module_id, *layout),
+                        Box::new(|_, _| Ok(())),
+                    )?;
+                }
+            }
+            UiCommand::TrackMonitor(track_index, monitor_p) => {
+                self.send_to_audio(MainToAudio::TrackMonitor(*track_index, *monitor_p))?;
+            }
             UiCommand::TrackMute(track_index, mute) => {
                 let track_index = track_index.unwrap_or(self.cursor_track.track);
                 let mute = mute.unwrap_or(!self.song.tracks[track_index].mute);
                 self.send_to_audio(MainToAudio::TrackMute(track_index, mute))?;
             }
+            UiCommand::TrackPanLaw(track_index, pan_law) => {
+                self.send_to_audio(MainToAudio::TrackPanLaw(*track_index, *pan_law))?;
+            }
+            UiCommand::TrackPanRear(track_index, pan_rear) => {
+                self.send_to_audio(MainToAudio::TrackPanRear(*track_index, *pan_rear))?;
+            }
+            UiCommand::TrackPanRight(track_index, pan_right) => {
+                self.send_to_audio(MainToAudio::TrackPanRight(*track_index, *pan_right))?;
+            }
             UiCommand::TrackRecOn(track_index) => {
                 self.rec_set(*track_index, true)?;
             }
@@ -1089,6 +1798,9 @@ impl<'a> AppState<'a> {
             UiCommand::TrackVolume(track_index, volume) => {
                 self.send_to_audio(MainToAudio::TrackVolume(*track_index, *volume))?;
             }
+            UiCommand::TrackWidth(track_index, width) => {
+                self.send_to_audio(MainToAudio::TrackWidth(*track_index, *width))?;
+            }
             UiCommand::Undo => self.undo()?,
             UiCommand::LaneAdd => {
                 self.send_to_audio(MainToAudio::LaneAdd(self.cursor_track.track))?;
@@ -1219,8 +1931,8 @@ impl<'a> AppState<'a> {
                 }
             }
             UiCommand::Module(ModuleCommand::Open) => {
-                if let Some(module) = self.module_at_cursort() {
-                    self.send_to_plugin(MainToPlugin::GuiOpen(module.id), Box::new(|_, _| Ok(())))?;
+                if self.module_at_cursort().is_some() {
+                    self.module_open((self.cursor_track.track, self.cursor_module.index))?;
                 } else {
                     self.route = Route::PluginSelect;
                 }
@@ -1747,6 +2459,7 @@ impl<'a> AppState<'a> {
                     LaneItem::Label(_) => {}
                     LaneItem::Call(_) => {}
                     LaneItem::Ret => {}
+                    LaneItem::AudioClip(_) => {}
                 }
                 commands.push((cursor, Some(lane_item)));
             }
@@ -1767,6 +2480,7 @@ impl<'a> AppState<'a> {
                     LaneItem::Label(_) => {}
                     LaneItem::Call(_) => {}
                     LaneItem::Ret => {}
+                    LaneItem::AudioClip(_) => {}
                 }
                 lane_item
             } else if off {
@@ -2024,6 +2738,28 @@ impl<'a> AppState<'a> {
         self.cursor_track.lane = 0;
     }
 
+    /// 選択範囲がこのトラックにあればその line だけ、なければ全体の take を切り替える
+    pub fn take_activate(&mut self, track_index: usize, take_index: Option<usize>) -> Result<()> {
+        let lines = match self.lane_items_selection_range() {
+            Some((min, max)) if (min.track..=max.track).contains(&track_index) => {
+                min.line..(max.line + 1)
+            }
+            _ => 0..usize::MAX,
+        };
+        self.send_to_audio(MainToAudio::TakeActivate(track_index, take_index, lines))?;
+        Ok(())
+    }
+
+    pub fn take_delete(&mut self, track_index: usize, take_index: usize) -> Result<()> {
+        self.send_to_audio(MainToAudio::TakeDelete(track_index, take_index))?;
+        Ok(())
+    }
+
+    pub fn time_signature_set(&mut self, numerator: u16, denominator: u16) -> Result<()> {
+        self.send_to_audio(MainToAudio::TimeSignature(numerator, denominator))?;
+        Ok(())
+    }
+
     pub fn track_rename(&mut self) -> Result<()> {
         if let Some(track_index) = self.rename_track_index.take() {
             self.send_to_audio(MainToAudio::TrackRename(
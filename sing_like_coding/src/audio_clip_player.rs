@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use anyhow::Result;
+use common::audio_file;
+
+use crate::model::audio_clip::AudioClip;
+
+/// 曲のサンプルレートに変換済みのオーディオデータ
+pub struct AudioClipData {
+    pub sample_rate: f64,
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl AudioClipData {
+    pub fn load(path: &str, sample_rate: f64) -> Result<Self> {
+        Ok(Self {
+            sample_rate,
+            channels: audio_file::read_resampled(path, sample_rate)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.first().map(|x| x.len()).unwrap_or(0)
+    }
+}
+
+struct Voice {
+    data: Arc<AudioClipData>,
+    // data のフレーム位置 ブロックの途中から鳴らすときは負になる
+    position: isize,
+    gain: f32,
+    // このブロックのこのフレームで止める
+    stop_frame: Option<usize>,
+}
+
+/// (path, sample_rate, 読めたもの)
+pub type AudioClipLoaded = (String, f64, Option<Arc<AudioClipData>>);
+
+/// lane に置いた AudioClip を鳴らす
+pub struct AudioClipPlayer {
+    cache: HashMap<String, Arc<AudioClipData>>,
+    sample_rate: f64,
+    // 別スレッドで読み込み中のもの path -> sample_rate
+    loading: HashMap<String, f64>,
+    sender: Sender<AudioClipLoaded>,
+    receiver: Receiver<AudioClipLoaded>,
+    voices: Vec<Vec<Option<Voice>>>, // [track_index][lane_index]
+}
+
+impl AudioClipPlayer {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            cache: Default::default(),
+            sample_rate: 0.0,
+            loading: Default::default(),
+            sender,
+            receiver,
+            voices: vec![],
+        }
+    }
+
+    /// 録音し終わったものはここから届く
+    pub fn sender(&self) -> Sender<AudioClipLoaded> {
+        self.sender.clone()
+    }
+
+    /// 読み込み済みでも読み込み中でもないものだけ別スレッドで読み込む
+    /// デコードとリサンプルは重いので Singer のロックを持ったままやらない
+    /// 読み込み終わるまでその clip は鳴らない
+    pub fn load<'a>(&mut self, paths: impl Iterator<Item = &'a str>, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let mut paths = paths
+            .filter(|path| {
+                !self
+                    .cache
+                    .get(*path)
+                    .is_some_and(|x| x.sample_rate == sample_rate)
+                    && self.loading.get(*path) != Some(&sample_rate)
+            })
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        if paths.is_empty() {
+            return;
+        }
+        for path in paths.iter() {
+            self.loading.insert(path.clone(), sample_rate);
+        }
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            for path in paths {
+                let data = match AudioClipData::load(&path, sample_rate) {
+                    Ok(data) => Some(Arc::new(data)),
+                    Err(e) => {
+                        log::warn!("audio clip {} {:?}", path, e);
+                        None
+                    }
+                };
+                if sender.send((path, sample_rate, data)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// 読み込み終わったものを受け取る オーディオスレッドから呼ぶのでブロックしない
+    pub fn receive(&mut self) {
+        while let Ok((path, sample_rate, data)) = self.receiver.try_recv() {
+            if self.loading.get(&path) == Some(&sample_rate) {
+                self.loading.remove(&path);
+            }
+            // 読み込み中にサンプルレートが変わったものは捨てる
+            if let Some(data) = data.filter(|_| sample_rate == self.sample_rate) {
+                self.cache.insert(path, data);
+            }
+        }
+    }
+
+    pub fn start(&mut self, track_index: usize, lane_index: usize, clip: &AudioClip, frame: usize) {
+        let Some(data) = self.cache.get(&clip.path).cloned() else {
+            return;
+        };
+        if self.voices.len() <= track_index {
+            self.voices.resize_with(track_index + 1, Vec::new);
+        }
+        let voices = &mut self.voices[track_index];
+        if voices.len() <= lane_index {
+            voices.resize_with(lane_index + 1, || None);
+        }
+        let position = (clip.offset * data.sample_rate).round() as isize - frame as isize;
+        voices[lane_index] = Some(Voice {
+            data,
+            position,
+            gain: clip.gain_linear(),
+            stop_frame: None,
+        });
+    }
+
+    pub fn stop(&mut self) {
+        self.voices.clear();
+    }
+
+    /// ループで戻るときなど このブロックの途中で全部止める
+    pub fn stop_at(&mut self, frame: usize) {
+        for voice in self.voices.iter_mut().flatten().flatten() {
+            voice.stop_frame = Some(frame);
+        }
+    }
+
+    pub fn track_delete(&mut self, track_index: usize) {
+        if track_index < self.voices.len() {
+            self.voices.remove(track_index);
+        }
+    }
+
+    pub fn track_insert(&mut self, track_index: usize) {
+        if track_index <= self.voices.len() {
+            self.voices.insert(track_index, vec![]);
+        }
+    }
+
+    /// track で鳴っている clip を out に書く 鳴っていなければ false で out はそのまま
+    pub fn render(
+        &mut self,
+        track_index: usize,
+        out: &mut [Vec<f32>],
+        nchannels: usize,
+        nframes: usize,
+    ) -> bool {
+        let Some(voices) = self.voices.get_mut(track_index) else {
+            return false;
+        };
+        if voices.iter().all(|x| x.is_none()) {
+            return false;
+        }
+        for buffer in out.iter_mut().take(nchannels) {
+            buffer[..nframes].fill(0.0);
+        }
+        for slot in voices.iter_mut() {
+            let Some(voice) = slot else {
+                continue;
+            };
+            let frame_end = voice.stop_frame.unwrap_or(nframes).min(nframes);
+            for channel in 0..nchannels {
+                let Some(src) = voice
+                    .data
+                    .channels
+                    .get(channel % voice.data.channels.len().max(1))
+                else {
+                    continue;
+                };
+                let buffer = &mut out[channel];
+                for frame in 0..frame_end {
+                    let position = voice.position + frame as isize;
+                    if position < 0 {
+                        continue;
+                    }
+                    if let Some(sample) = src.get(position as usize) {
+                        buffer[frame] += sample * voice.gain;
+                    }
+                }
+            }
+            voice.position += nframes as isize;
+            if voice.stop_frame.is_some() || voice.position >= voice.data.len() as isize {
+                *slot = None;
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+
+use common::process_data::MAX_FRAMES;
+
+/// 入力デバイスから届いたサンプルを Singer::process に渡す
+pub struct AudioIn {
+    pub nchannels: usize,
+    // interleaved
+    samples: VecDeque<f32>,
+    /// 入力のレイテンシー(秒)
+    pub latency: f64,
+}
+
+impl AudioIn {
+    pub fn new() -> Self {
+        Self {
+            nchannels: 0,
+            samples: Default::default(),
+            latency: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, data: impl Iterator<Item = f32>) {
+        self.samples.extend(data);
+        // 取り出されないときにたまり続けないように
+        // BUFFER_SIZES の最大 8192 のコールバックでも足りるように
+        let max = MAX_FRAMES * 8 * self.nchannels;
+        if self.samples.len() > max {
+            let overflow = self.samples.len() - max;
+            self.samples.drain(..overflow);
+        }
+    }
+
+    /// チャンネルごとに nframes 取り出す 足りない分は 0
+    pub fn take(&mut self, nframes: usize) -> Vec<Vec<f32>> {
+        let mut channels = vec![vec![0.0; nframes]; self.nchannels];
+        let nframes_available = (self.samples.len() / self.nchannels.max(1)).min(nframes);
+        let offset = nframes - nframes_available;
+        for frame in offset..nframes {
+            for channel in channels.iter_mut() {
+                channel[frame] = self.samples.pop_front().unwrap_or(0.0);
+            }
+        }
+        channels
+    }
+
+    pub fn reset(&mut self, nchannels: usize) {
+        self.nchannels = nchannels;
+        self.samples.clear();
+    }
+}
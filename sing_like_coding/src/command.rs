@@ -2,6 +2,8 @@ use anyhow::Result;
 
 use crate::app_state::AppState;
 
+pub mod audio_clip_insert;
+pub mod audio_device;
 pub mod midi_device_input;
 pub mod plugin_load;
 pub mod plugin_scan;
@@ -0,0 +1,22 @@
+use crate::app_state::AppState;
+
+use super::Command;
+
+pub struct AudioClipInsert {}
+
+impl Command for AudioClipInsert {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.audio_clip_insert()?;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Audio Clip Insert"
+    }
+}
+
+impl AudioClipInsert {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
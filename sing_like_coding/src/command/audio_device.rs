@@ -0,0 +1,22 @@
+use crate::{app_state::AppState, view::root_view::Route};
+
+use super::Command;
+
+pub struct AudioDevice {}
+
+impl Command for AudioDevice {
+    fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
+        state.route = Route::AudioDevice;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Audio Device"
+    }
+}
+
+impl AudioDevice {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
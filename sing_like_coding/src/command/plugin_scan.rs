@@ -1,5 +1,3 @@
-use common::protocol::MainToPlugin;
-
 use crate::app_state::AppState;
 
 use super::Command;
@@ -8,8 +6,7 @@ pub struct PluginScan {}
 
 impl Command for PluginScan {
     fn call(&mut self, state: &mut AppState) -> anyhow::Result<()> {
-        state.send_to_plugin(MainToPlugin::Scan, Box::new(|_, _| Ok(())))?;
-        Ok(())
+        state.plugin_scan()
     }
 
     fn name(&self) -> &str {
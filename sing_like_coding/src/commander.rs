@@ -13,6 +13,10 @@ impl Commander {
     pub fn new() -> Self {
         Self {
             commands: vec![
+                Arc::new(Mutex::new(
+                    command::audio_clip_insert::AudioClipInsert::new(),
+                )),
+                Arc::new(Mutex::new(command::audio_device::AudioDevice::new())),
                 Arc::new(Mutex::new(
                     command::midi_device_input::MidiDeviceInput::new(),
                 )),
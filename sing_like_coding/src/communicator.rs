@@ -1,9 +1,17 @@
-use std::process::{Command, Stdio};
+use std::collections::HashMap;
+use std::process::Stdio;
 use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
 
+use anyhow::anyhow;
+use common::module::ModuleId;
 use common::protocol::{receive, send, MainToPlugin, PluginToMain};
-use common::PIPE_CTRL_NAME;
-use tokio::net::windows::named_pipe::ServerOptions;
+use common::{pipe_ctrl_name, PIPE_CTRL_NAME, PLUGIN_HOST_EXE};
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::process::{Child, Command};
+
+// 立ち上げ直す前に state を取るときハングしたホストで止まらないように
+const STATE_SAVE_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub struct Communicator {
     receiver_from_main: Receiver<MainToPlugin>,
@@ -25,20 +33,98 @@ impl Communicator {
     }
 
     pub async fn run(&mut self, hwnd: isize) -> anyhow::Result<()> {
-        let mut pipe = ServerOptions::new().create(PIPE_CTRL_NAME)?;
-        let _child = Command::new("sing_like_coding_plugin.exe")
-            .stdout(Stdio::inherit())
-            .spawn()?;
-        pipe.connect().await?;
-
-        send(&mut pipe, &MainToPlugin::Hwnd(hwnd)).await?;
-        let _did_hwnd: PluginToMain = receive(&mut pipe).await?;
+        let (mut pipe, mut child) = host_start(PIPE_CTRL_NAME, hwnd, None).await?;
+        // モジュール専用のプロセス
+        let mut isolateds: HashMap<ModuleId, (NamedPipeServer, Child)> = HashMap::new();
+        // 後から立ち上げたプロセスにも送る
+        let mut audio_config = None;
 
         loop {
             let message = self.receiver_from_main.recv()?;
-            send(&mut pipe, &message).await?;
+            let module_id = message.module_id();
+            let message = match message {
+                MainToPlugin::Restart => {
+                    // 共有のプロセスだけ ハングしていることもあるので殺してから
+                    let _ = child.kill().await;
+                    drop(pipe);
+                    (pipe, child) = host_start(PIPE_CTRL_NAME, hwnd, audio_config.as_ref()).await?;
+                    PluginToMain::DidRestart
+                }
+                MainToPlugin::Isolate(id) => {
+                    if let Some((_, mut child)) = isolateds.remove(&id) {
+                        let _ = child.kill().await;
+                    }
+                    match host_start(&pipe_ctrl_name(id), hwnd, audio_config.as_ref()).await {
+                        Ok(x) => {
+                            isolateds.insert(id, x);
+                            PluginToMain::DidIsolate
+                        }
+                        Err(e) => {
+                            log::error!("isolated plugin host {} failed to start {:?}", id, e);
+                            PluginToMain::HostDied(Some(id))
+                        }
+                    }
+                }
+                MainToPlugin::AudioConfig(..) | MainToPlugin::Quit => {
+                    let quit_p = message == MainToPlugin::Quit;
+                    for (id, (pipe, child)) in isolateds.iter_mut() {
+                        if let Err(e) = request_or_died(pipe, child, &message).await {
+                            if !quit_p {
+                                log::error!("isolated plugin host {} died {:?}", id, e);
+                            }
+                        }
+                    }
+                    if quit_p {
+                        isolateds.clear();
+                    } else {
+                        audio_config = Some(message.clone());
+                    }
+                    match request_or_died(&mut pipe, &mut child, &message).await {
+                        Ok(message) => message,
+                        Err(e) if quit_p => {
+                            log::debug!("plugin host quit {:?}", e);
+                            PluginToMain::Quit
+                        }
+                        Err(e) => {
+                            log::error!("plugin host died {:?}", e);
+                            PluginToMain::HostDied(None)
+                        }
+                    }
+                }
+                message => {
+                    let isolated = module_id.and_then(|id| isolateds.get_mut(&id));
+                    let (pipe, child) = match isolated {
+                        Some((pipe, child)) => (pipe, child),
+                        None => (&mut pipe, &mut child),
+                    };
+                    // 返事を待っている間にプロセスが落ちても必ず 1 つ返す
+                    let reply = match message {
+                        MainToPlugin::StateSave(_) => {
+                            request_or_timeout(pipe, child, &message, STATE_SAVE_TIMEOUT).await
+                        }
+                        _ => request_or_died(pipe, child, &message).await,
+                    };
+                    let reply = match reply {
+                        Ok(reply) => reply,
+                        Err(e) => {
+                            log::error!("plugin host died {:?}", e);
+                            if let Some(id) = module_id {
+                                isolateds.remove(&id);
+                            }
+                            PluginToMain::HostDied(module_id)
+                        }
+                    };
+                    // 専用のプロセスは外したら終わらせる
+                    if let MainToPlugin::Unload(id) = message {
+                        if let Some((mut pipe, mut child)) = isolateds.remove(&id) {
+                            let _ =
+                                request_or_died(&mut pipe, &mut child, &MainToPlugin::Quit).await;
+                        }
+                    }
+                    reply
+                }
+            };
 
-            let message: PluginToMain = receive(&mut pipe).await?;
             let break_p = message == PluginToMain::Quit;
             self.sender_communicator_to_main_thread.send(message)?;
             self.gui_context.request_repaint();
@@ -49,3 +135,60 @@ impl Communicator {
         }
     }
 }
+
+async fn host_start(
+    pipe_name: &str,
+    hwnd: isize,
+    audio_config: Option<&MainToPlugin>,
+) -> anyhow::Result<(NamedPipeServer, Child)> {
+    let mut pipe = ServerOptions::new().create(pipe_name)?;
+    let mut command = Command::new(PLUGIN_HOST_EXE);
+    if pipe_name != PIPE_CTRL_NAME {
+        command.arg(pipe_name);
+    }
+    let child = command.stdout(Stdio::inherit()).spawn()?;
+    pipe.connect().await?;
+
+    send(&mut pipe, &MainToPlugin::Hwnd(hwnd)).await?;
+    let _did_hwnd: PluginToMain = receive(&mut pipe).await?;
+    if let Some(audio_config) = audio_config {
+        send(&mut pipe, audio_config).await?;
+        let _did_audio_config: PluginToMain = receive(&mut pipe).await?;
+    }
+    Ok((pipe, child))
+}
+
+async fn request(
+    pipe: &mut NamedPipeServer,
+    message: &MainToPlugin,
+) -> anyhow::Result<PluginToMain> {
+    send(pipe, message).await?;
+    receive(pipe).await
+}
+
+async fn request_or_died(
+    pipe: &mut NamedPipeServer,
+    child: &mut Child,
+    message: &MainToPlugin,
+) -> anyhow::Result<PluginToMain> {
+    tokio::select! {
+        message = request(pipe, message) => message,
+        status = child.wait() => Err(anyhow!("plugin host exited {:?}", status)),
+    }
+}
+
+/// 遅れて来た返事で順番がずれないように時間切れならプロセスを殺す
+async fn request_or_timeout(
+    pipe: &mut NamedPipeServer,
+    child: &mut Child,
+    message: &MainToPlugin,
+    timeout: Duration,
+) -> anyhow::Result<PluginToMain> {
+    match tokio::time::timeout(timeout, request_or_died(pipe, child, message)).await {
+        Ok(reply) => reply,
+        Err(_) => {
+            let _ = child.kill().await;
+            Err(anyhow!("plugin host did not respond in {:?}", timeout))
+        }
+    }
+}
@@ -8,9 +8,24 @@ use anyhow::Result;
 use common::util::dir_user_setting;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub midi_device_input: Option<String>,
+    #[serde(default)]
+    pub audio_host: Option<String>,
+    #[serde(default)]
+    pub audio_device_output: Option<String>,
+    #[serde(default)]
+    pub audio_device_input: Option<String>,
+    #[serde(default)]
+    pub sample_rate: Option<u32>,
+    #[serde(default)]
+    pub buffer_size: Option<u32>,
+    #[serde(default)]
+    pub output_channels: Option<u16>,
+    /// 標準のほかに CLAP を探すディレクトリ
+    #[serde(default)]
+    pub clap_paths: Vec<String>,
 }
 
 impl Config {
@@ -37,6 +52,13 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             midi_device_input: None,
+            audio_host: None,
+            audio_device_output: None,
+            audio_device_input: None,
+            sample_rate: None,
+            buffer_size: None,
+            output_channels: None,
+            clap_paths: vec![],
         }
     }
 }
@@ -1,87 +1,335 @@
 use std::sync::{Arc, Mutex};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use common::process_data::MAX_FRAMES;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Sample, SampleFormat, Stream, StreamConfig};
+use cpal::{
+    BufferSize, FromSample, InputCallbackInfo, OutputCallbackInfo, Sample, SampleFormat,
+    SampleRate, SizedSample, Stream, StreamConfig, SupportedBufferSize, SupportedStreamConfig,
+};
 
+use crate::audio_in::AudioIn;
+use crate::config::Config;
 use crate::singer::Singer;
 
+// 先にあるものを優先する
+const SAMPLE_FORMATS: [SampleFormat; 3] = [SampleFormat::F32, SampleFormat::I32, SampleFormat::I16];
+pub const SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+pub const BUFFER_SIZES: [u32; 8] = [64, 128, 256, 512, 1024, 2048, 4096, 8192];
+pub const OUTPUT_CHANNELS: [u16; 4] = [2, 4, 6, 8];
+
 pub struct Device {
     device: cpal::Device,
     sample_format: SampleFormat,
     config: StreamConfig,
     stream: Option<Stream>,
+    input: Option<(cpal::Device, SampleFormat, StreamConfig)>,
+    input_stream: Option<Stream>,
+    audio_in: Arc<Mutex<AudioIn>>,
     singer: Arc<Mutex<Singer>>,
 }
 
 impl Device {
-    pub fn open_default(singer: Arc<Mutex<Singer>>) -> Result<Device> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .expect("no output device available");
-        println!("{:?}", device.name());
-        let mut supported_configs_range = device
-            .supported_output_configs()
-            .expect("error while querying configs");
-        let supported_stream_config = supported_configs_range
-            .next()
-            .expect("no supported config?!")
-            .with_max_sample_rate();
-        println!("{:?}", supported_stream_config);
-        let sample_format = supported_stream_config.sample_format();
-        let config: StreamConfig = supported_stream_config.clone().into();
-        log::info!("{:?}", &config);
-
+    pub fn open(singer: Arc<Mutex<Singer>>, config: &Config) -> Result<Device> {
+        let (device, sample_format, stream_config) = Self::open_device(config)?;
+        let input = Self::open_input_device(config, stream_config.sample_rate)?;
+        let audio_in = singer.lock().unwrap().audio_in.clone();
         Ok(Device {
             device,
             sample_format,
-            config,
+            config: stream_config,
             stream: None,
+            input,
+            input_stream: None,
+            audio_in,
             singer,
         })
     }
 
-    pub fn start(&mut self) -> Result<()> {
-        let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
+    /// 設定を変えたらストリームを作りなおす start は呼び出し側で
+    pub fn reopen(&mut self, config: &Config) -> Result<()> {
+        self.stop()?;
+        let (device, sample_format, stream_config) = Self::open_device(config)?;
+        self.input = Self::open_input_device(config, stream_config.sample_rate)?;
+        self.device = device;
+        self.sample_format = sample_format;
+        self.config = stream_config;
+        Ok(())
+    }
+
+    fn open_device(config: &Config) -> Result<(cpal::Device, SampleFormat, StreamConfig)> {
+        let host = host(config.audio_host.as_deref());
+        let device = config
+            .audio_device_output
+            .as_ref()
+            .and_then(|name| {
+                host.output_devices()
+                    .ok()?
+                    .find(|device| device.name().ok().as_ref() == Some(name))
+            })
+            .or_else(|| host.default_output_device())
+            .ok_or_else(|| anyhow!("no output device available"))?;
+        log::info!("{:?}", device.name());
+
+        let mut supported_configs = device
+            .supported_output_configs()?
+            .filter(|x| SAMPLE_FORMATS.contains(&x.sample_format()))
+            .collect::<Vec<_>>();
+        // サラウンド用にチャンネル数を指定されていて対応していればそれだけにする
+        if let Some(channels) = config.output_channels {
+            if supported_configs.iter().any(|x| x.channels() == channels) {
+                supported_configs.retain(|x| x.channels() == channels);
+            } else {
+                log::warn!("output device does not support {} channels", channels);
+            }
+        }
+        let format_rank = |x: &&cpal::SupportedStreamConfigRange| {
+            SAMPLE_FORMATS
+                .iter()
+                .position(|format| *format == x.sample_format())
+        };
+        let supported_stream_config = config
+            .sample_rate
+            .map(SampleRate)
+            .and_then(|sample_rate| {
+                supported_configs
+                    .iter()
+                    .filter(|x| {
+                        x.min_sample_rate() <= sample_rate && sample_rate <= x.max_sample_rate()
+                    })
+                    .min_by_key(format_rank)
+                    .map(|x| x.clone().with_sample_rate(sample_rate))
+            })
+            .or_else(|| {
+                device
+                    .default_output_config()
+                    .ok()
+                    .filter(|x| SAMPLE_FORMATS.contains(&x.sample_format()))
+                    .filter(|x| {
+                        config
+                            .output_channels
+                            .is_none_or(|channels| x.channels() == channels)
+                    })
+            })
+            .or_else(|| {
+                supported_configs
+                    .iter()
+                    .min_by_key(format_rank)
+                    .map(|x| x.clone().with_max_sample_rate())
+            })
+            .ok_or_else(|| anyhow!("no supported config"))?;
+        log::info!("{:?}", supported_stream_config);
+
+        let sample_format = supported_stream_config.sample_format();
+        let stream_config = stream_config(&supported_stream_config, config.buffer_size);
+        log::info!("{:?}", &stream_config);
+        Ok((device, sample_format, stream_config))
+    }
+
+    /// 入力は出力と同じサンプルレートで開く 対応していなければ使わない
+    fn open_input_device(
+        config: &Config,
+        sample_rate: SampleRate,
+    ) -> Result<Option<(cpal::Device, SampleFormat, StreamConfig)>> {
+        let Some(name) = &config.audio_device_input else {
+            return Ok(None);
+        };
+        let host = host(config.audio_host.as_deref());
+        let Some(device) = host
+            .input_devices()?
+            .find(|device| device.name().ok().as_ref() == Some(name))
+        else {
+            log::warn!("input device {} not found", name);
+            return Ok(None);
+        };
+        let Some(supported_stream_config) = device
+            .supported_input_configs()?
+            .filter(|x| {
+                SAMPLE_FORMATS.contains(&x.sample_format())
+                    && x.min_sample_rate() <= sample_rate
+                    && sample_rate <= x.max_sample_rate()
+            })
+            .min_by_key(|x| {
+                SAMPLE_FORMATS
+                    .iter()
+                    .position(|format| *format == x.sample_format())
+            })
+            .map(|x| x.with_sample_rate(sample_rate))
+        else {
+            log::warn!("input device {} does not support {:?}", name, sample_rate);
+            return Ok(None);
+        };
+        let sample_format = supported_stream_config.sample_format();
+        let stream_config = stream_config(&supported_stream_config, config.buffer_size);
+        log::info!("input {:?}", &stream_config);
+        Ok(Some((device, sample_format, stream_config)))
+    }
+
+    pub fn hosts() -> Vec<String> {
+        cpal::available_hosts()
+            .into_iter()
+            .map(|x| x.name().to_string())
+            .collect()
+    }
+
+    pub fn output_devices(host_name: Option<&str>) -> Vec<String> {
+        host(host_name)
+            .output_devices()
+            .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn input_devices(host_name: Option<&str>) -> Vec<String> {
+        host(host_name)
+            .input_devices()
+            .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn sample_rate(&self) -> f64 {
+        self.config.sample_rate.0 as f64
+    }
+
+    pub fn max_frames_count(&self) -> u32 {
+        match self.config.buffer_size {
+            // 大きいものは Singer::process で MAX_FRAMES ごとに分ける
+            BufferSize::Fixed(buffer_size) => buffer_size.min(MAX_FRAMES as u32),
+            BufferSize::Default => MAX_FRAMES as u32,
+        }
+    }
 
+    pub fn start(&mut self) -> Result<()> {
         {
-            let sample_rate = self.config.sample_rate.0 as f64;
-            self.singer.lock().unwrap().song.sample_rate = sample_rate;
+            let sample_rate = self.sample_rate();
+            let mut singer = self.singer.lock().unwrap();
+            singer.song.sample_rate = sample_rate;
+            // サンプルレートが変わったら読み直す
+            singer.audio_clips_load();
         }
 
-        let channels = self.config.channels as usize;
-        let singer = self.singer.clone();
         let stream = match self.sample_format {
-            SampleFormat::U8 => self.device.build_output_stream(
-                &self.config,
-                move |output: &mut [f32], _| {
-                    // log::debug!("callback output.len {}", output.len());
-                    singer.lock().unwrap().process(output, channels).unwrap();
-                },
-                err_fn,
-                None,
-            ),
-            sample_format => panic!("Unsupported sample format '{sample_format}'"),
-        }
-        .unwrap();
+            SampleFormat::F32 => self.build_output_stream::<f32>()?,
+            SampleFormat::I32 => self.build_output_stream::<i32>()?,
+            SampleFormat::I16 => self.build_output_stream::<i16>()?,
+            sample_format => return Err(anyhow!("Unsupported sample format '{sample_format}'")),
+        };
 
-        stream.play().unwrap();
+        stream.play()?;
         self.stream = Some(stream);
 
+        let input_stream = match &self.input {
+            Some((_, SampleFormat::F32, _)) => Some(self.build_input_stream::<f32>()?),
+            Some((_, SampleFormat::I32, _)) => Some(self.build_input_stream::<i32>()?),
+            Some((_, SampleFormat::I16, _)) => Some(self.build_input_stream::<i16>()?),
+            Some((_, sample_format, _)) => {
+                return Err(anyhow!("Unsupported sample format '{sample_format}'"));
+            }
+            None => None,
+        };
+        let nchannels = self
+            .input
+            .as_ref()
+            .map(|(_, _, config)| config.channels as usize)
+            .unwrap_or(0);
+        self.audio_in.lock().unwrap().reset(nchannels);
+        self.singer
+            .lock()
+            .unwrap()
+            .song_state_mut()
+            .audio_input_nchannels = nchannels;
+        if let Some(input_stream) = &input_stream {
+            input_stream.play()?;
+        }
+        self.input_stream = input_stream;
+
         Ok(())
     }
 
+    fn build_output_stream<T: SizedSample + FromSample<f32>>(&self) -> Result<Stream> {
+        let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
+        let channels = self.config.channels as usize;
+        let singer = self.singer.clone();
+        let mut buffer = vec![];
+        let stream = self.device.build_output_stream(
+            &self.config,
+            move |output: &mut [T], info: &OutputCallbackInfo| {
+                // log::debug!("callback output.len {}", output.len());
+                buffer.resize(output.len(), 0.0);
+                let mut singer = singer.lock().unwrap();
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                    singer.output_latency = latency.as_secs_f64();
+                }
+                singer.process(&mut buffer, channels).unwrap();
+                for (dst, src) in output.iter_mut().zip(buffer.iter()) {
+                    *dst = T::from_sample(*src);
+                }
+            },
+            err_fn,
+            None,
+        )?;
+        Ok(stream)
+    }
+
+    fn build_input_stream<T: SizedSample>(&self) -> Result<Stream>
+    where
+        f32: FromSample<T>,
+    {
+        let err_fn = |err| log::error!("an error occurred on the input audio stream: {}", err);
+        let (device, _, config) = self.input.as_ref().unwrap();
+        let audio_in = self.audio_in.clone();
+        let stream = device.build_input_stream(
+            config,
+            move |data: &[T], info: &InputCallbackInfo| {
+                let mut audio_in = audio_in.lock().unwrap();
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.callback.duration_since(&timestamp.capture) {
+                    audio_in.latency = latency.as_secs_f64();
+                }
+                audio_in.push(data.iter().map(|x| f32::from_sample(*x)));
+            },
+            err_fn,
+            None,
+        )?;
+        Ok(stream)
+    }
+
     pub fn start_p(&self) -> bool {
         self.stream.is_some()
     }
 
     pub fn stop(&mut self) -> Result<()> {
+        self.input_stream = None;
         self.stream = None;
         Ok(())
     }
 }
 
+fn host(name: Option<&str>) -> cpal::Host {
+    name.and_then(|name| {
+        cpal::available_hosts()
+            .into_iter()
+            .find(|x| x.name() == name)
+    })
+    .and_then(|id| cpal::host_from_id(id).ok())
+    .unwrap_or_else(cpal::default_host)
+}
+
+fn stream_config(
+    supported_stream_config: &SupportedStreamConfig,
+    buffer_size: Option<u32>,
+) -> StreamConfig {
+    let mut stream_config = supported_stream_config.config();
+    if let (Some(buffer_size), SupportedBufferSize::Range { min, max }) =
+        (buffer_size, supported_stream_config.buffer_size())
+    {
+        if (*min..=*max).contains(&buffer_size) {
+            stream_config.buffer_size = BufferSize::Fixed(buffer_size);
+        }
+    }
+    stream_config
+}
+
 #[allow(dead_code)]
 fn write_silence<T: Sample>(data: &mut [T], _: &cpal::OutputCallbackInfo) {
     for sample in data.iter_mut() {
@@ -35,6 +35,17 @@ impl Eval {
                         state.eval_call(word.to_string())?;
                     }
                 }
+                "gain" => {
+                    if let Some(Word::Number(db)) = stack.pop() {
+                        state.eval_audio_clip(|clip| clip.gain = db as f32)?;
+                    }
+                }
+                "offset" => {
+                    // ミリ秒
+                    if let Some(Word::Number(ms)) = stack.pop() {
+                        state.eval_audio_clip(|clip| clip.offset = ms.max(0) as f64 / 1000.0)?;
+                    }
+                }
                 "label" | "l" => {
                     if let Some(word) = stack.pop() {
                         state.eval_label(word.to_string())?;
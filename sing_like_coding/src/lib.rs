@@ -1,15 +1,25 @@
 pub mod app;
 mod app_state;
+mod audio_clip_player;
+mod audio_in;
 mod command;
 mod commander;
 mod communicator;
 mod config;
 mod device;
 mod eval;
+mod loudness_meter;
+mod master_safety;
+mod metronome;
 mod midi_device;
 mod model;
+mod plugin_library;
+mod plugin_preset;
+mod plugin_scanner;
+mod recorder;
 mod singer;
 mod song_state;
+mod tap;
 mod undo_history;
 mod util;
 mod view;
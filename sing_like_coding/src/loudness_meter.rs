@@ -0,0 +1,252 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use common::{
+    channel_layout::ChannelLayout,
+    dsp::{linear_to_db, true_peak},
+    process_data::MAX_CHANNELS,
+};
+
+use crate::view::stereo_peak_meter::DB_MIN;
+
+// EBU R128 (ITU-R BS.1770) は 100ms ごとに進める
+const BLOCK_SEC: f64 = 0.1;
+const MOMENTARY_BLOCKS: usize = 4;
+const SHORT_TERM_BLOCKS: usize = 30;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const RMS_SEC: f64 = 0.3;
+// integrated のゲートは 0.1 LU 刻みのヒストグラムで -70 から +10 まで
+const HISTOGRAM_STEP: f64 = 0.1;
+const HISTOGRAM_LEN: usize = 800;
+
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// K 特性のフィルター (ハイシェルフ + ハイパス)
+/// 係数は 48kHz 以外でも同じ特性になるように libebur128 と同じ式で求める
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / sample_rate).tan();
+    let vh = 10.0f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, highpass]
+}
+
+/// LFE は数えず、後ろのチャンネルは +1.5dB
+fn channel_weights(layout: ChannelLayout) -> Vec<f64> {
+    layout
+        .speaker_angles()
+        .iter()
+        .map(|angle| match angle {
+            None => 0.0,
+            Some(x) if x.abs() > 60.0 => 1.41,
+            Some(_) => 1.0,
+        })
+        .collect()
+}
+
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.max(1e-20).log10()
+}
+
+/// トラックごとのラウドネス、RMS、トゥルーピーク
+pub struct LoudnessMeter {
+    sample_rate: f64,
+    layout: Option<ChannelLayout>,
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    // 今の 100ms ブロックに溜めた重み付きの二乗和
+    block_sum: f64,
+    block_frames: usize,
+    // 直近の 100ms ブロックごとの重み付き平均二乗
+    blocks: VecDeque<f64>,
+    // 400ms のゲートブロックの (個数, 平均二乗の合計)
+    histogram: Vec<(u64, f64)>,
+    mean_squares: [f64; MAX_CHANNELS],
+    histories: [[f32; 4]; MAX_CHANNELS],
+    pub rms: [f32; MAX_CHANNELS],
+    pub true_peaks: [f32; MAX_CHANNELS],
+    /// リセットするまでの最大
+    pub true_peak_max: f32,
+    pub momentary: f32,
+    pub short_term: f32,
+    pub integrated: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> Self {
+        Self {
+            sample_rate: 0.0,
+            layout: None,
+            filters: vec![],
+            weights: vec![],
+            block_sum: 0.0,
+            block_frames: 0,
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS + 1),
+            histogram: vec![(0, 0.0); HISTOGRAM_LEN],
+            mean_squares: [0.0; MAX_CHANNELS],
+            histories: [[0.0; 4]; MAX_CHANNELS],
+            rms: [DB_MIN; MAX_CHANNELS],
+            true_peaks: [DB_MIN; MAX_CHANNELS],
+            true_peak_max: DB_MIN,
+            momentary: DB_MIN,
+            short_term: DB_MIN,
+            integrated: DB_MIN,
+        }
+    }
+
+    /// integrated とトゥルーピークの最大をやり直す
+    pub fn reset(&mut self) {
+        self.histogram.fill((0, 0.0));
+        self.integrated = DB_MIN;
+        self.true_peak_max = DB_MIN;
+    }
+
+    fn configure(&mut self, layout: ChannelLayout, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.layout = Some(layout);
+        self.filters = vec![k_weighting(sample_rate); layout.nchannels()];
+        self.weights = channel_weights(layout);
+        self.block_sum = 0.0;
+        self.block_frames = 0;
+        self.blocks.clear();
+        self.mean_squares = [0.0; MAX_CHANNELS];
+        self.histories = [[0.0; 4]; MAX_CHANNELS];
+        self.reset();
+    }
+
+    /// buffer は [channel][frame] で layout のチャンネル数だけ使う
+    pub fn process(
+        &mut self,
+        buffer: &[Vec<f32>],
+        layout: ChannelLayout,
+        nframes: usize,
+        sample_rate: f64,
+    ) {
+        if self.layout != Some(layout) || self.sample_rate != sample_rate {
+            self.configure(layout, sample_rate);
+        }
+        let nchannels = layout.nchannels();
+        let block_len = ((BLOCK_SEC * sample_rate) as usize).max(1);
+        let rms_coef = 1.0 - (-1.0 / (RMS_SEC * sample_rate)).exp();
+        let mut true_peaks = [0.0f32; MAX_CHANNELS];
+
+        for frame in 0..nframes {
+            for channel in 0..nchannels {
+                let x = buffer[channel][frame];
+
+                let history = &mut self.histories[channel];
+                history.rotate_left(1);
+                history[3] = x;
+                true_peaks[channel] = true_peaks[channel].max(true_peak(*history));
+
+                let square = (x as f64) * (x as f64);
+                self.mean_squares[channel] += (square - self.mean_squares[channel]) * rms_coef;
+
+                let [shelf, highpass] = &mut self.filters[channel];
+                let y = highpass.process(shelf.process(x as f64));
+                self.block_sum += y * y * self.weights[channel];
+            }
+
+            self.block_frames += 1;
+            if self.block_frames >= block_len {
+                self.block_end();
+            }
+        }
+
+        for channel in 0..nchannels {
+            self.rms[channel] = linear_to_db(self.mean_squares[channel].sqrt() as f32).max(DB_MIN);
+            self.true_peaks[channel] = linear_to_db(true_peaks[channel]).max(DB_MIN);
+            self.true_peak_max = self.true_peak_max.max(self.true_peaks[channel]);
+        }
+        // モノラルでもメーターは左右に出す
+        if nchannels == 1 {
+            self.rms[1] = self.rms[0];
+            self.true_peaks[1] = self.true_peaks[0];
+        }
+    }
+
+    fn block_end(&mut self) {
+        self.blocks
+            .push_back(self.block_sum / self.block_frames as f64);
+        if self.blocks.len() > SHORT_TERM_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.block_sum = 0.0;
+        self.block_frames = 0;
+
+        // 足りない分は無音とする
+        let momentary =
+            self.blocks.iter().rev().take(MOMENTARY_BLOCKS).sum::<f64>() / MOMENTARY_BLOCKS as f64;
+        let short_term = self.blocks.iter().sum::<f64>() / SHORT_TERM_BLOCKS as f64;
+        self.momentary = (loudness(momentary) as f32).max(DB_MIN);
+        self.short_term = (loudness(short_term) as f32).max(DB_MIN);
+
+        // 75% 重ねた 400ms のゲートブロックは momentary と同じ
+        let block_loudness = loudness(momentary);
+        if self.blocks.len() >= MOMENTARY_BLOCKS && block_loudness > ABSOLUTE_GATE {
+            let bin = (((block_loudness - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize)
+                .min(HISTOGRAM_LEN - 1);
+            self.histogram[bin].0 += 1;
+            self.histogram[bin].1 += momentary;
+            self.integrated_compute();
+        }
+    }
+
+    fn integrated_compute(&mut self) {
+        let (count, sum) = self
+            .histogram
+            .iter()
+            .fold((0, 0.0), |acc, x| (acc.0 + x.0, acc.1 + x.1));
+        if count == 0 {
+            return;
+        }
+        let threshold = loudness(sum / count as f64) + RELATIVE_GATE;
+        let bin_start = ((threshold - ABSOLUTE_GATE) / HISTOGRAM_STEP).max(0.0) as usize;
+        let (count, sum) = self
+            .histogram
+            .iter()
+            .skip(bin_start)
+            .fold((0, 0.0), |acc, x| (acc.0 + x.0, acc.1 + x.1));
+        if count == 0 {
+            return;
+        }
+        self.integrated = (loudness(sum / count as f64) as f32).max(DB_MIN);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use common::dsp::{linear_to_db, true_peak};
+
+// DC ブロッカーのカットオフ
+const DC_BLOCKER_HZ: f64 = 10.0;
+// リミッターの先読み
+const LOOKAHEAD_SEC: f64 = 0.0015;
+const RELEASE_SEC: f64 = 0.05;
+
+/// マスターの最後に必ず通す
+/// NaN/Inf を 0 にして DC を切り、指定があればトゥルーピークで制限する
+pub struct MasterSafety {
+    sample_rate: f64,
+    nchannels: usize,
+    // チャンネルごとの (前の入力, 前の出力)
+    dc_states: Vec<(f32, f32)>,
+    // トゥルーピークを推定するための直近 4 サンプル
+    histories: Vec<[f32; 4]>,
+    // 先読みの分だけ遅らせる
+    // トゥルーピークがわかるのは 1 フレーム後なのでさらに 1 フレーム
+    delays: Vec<VecDeque<f32>>,
+    // 出力するフレームから先読みの最後までのフレームごとに必要なゲイン
+    gains_required: VecDeque<f32>,
+    gain: f32,
+    // 前のブロックでリミッターを使ったか
+    limiter_p: bool,
+    /// 直前のブロックで一番下げたときの dB
+    pub gain_reduction: f32,
+}
+
+impl MasterSafety {
+    pub fn new() -> Self {
+        Self {
+            sample_rate: 0.0,
+            nchannels: 0,
+            dc_states: vec![],
+            histories: vec![],
+            delays: vec![],
+            gains_required: Default::default(),
+            gain: 1.0,
+            limiter_p: false,
+            gain_reduction: 0.0,
+        }
+    }
+
+    fn reset(&mut self, nchannels: usize, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.nchannels = nchannels;
+        self.dc_states = vec![(0.0, 0.0); nchannels];
+        self.limiter_reset();
+    }
+
+    /// 切り替えたときに前に使っていたときの遅延やゲインを持ち越さない
+    fn limiter_reset(&mut self) {
+        let lookahead = self.lookahead(self.sample_rate);
+        self.histories = vec![[0.0; 4]; self.nchannels];
+        self.delays = vec![VecDeque::from(vec![0.0; lookahead + 1]); self.nchannels];
+        self.gains_required = VecDeque::from(vec![1.0; lookahead]);
+        self.gain = 1.0;
+    }
+
+    fn lookahead(&self, sample_rate: f64) -> usize {
+        ((LOOKAHEAD_SEC * sample_rate) as usize).max(1)
+    }
+
+    /// output は interleaved ceiling はリミッターを使うときの上限 (リニア)
+    /// NaN/Inf があったら true
+    pub fn process(
+        &mut self,
+        output: &mut [f32],
+        nchannels: usize,
+        sample_rate: f64,
+        ceiling: Option<f32>,
+    ) -> bool {
+        if self.nchannels != nchannels || self.sample_rate != sample_rate {
+            self.reset(nchannels, sample_rate);
+        } else if self.limiter_p != ceiling.is_some() {
+            self.limiter_reset();
+        }
+        self.limiter_p = ceiling.is_some();
+        let nframes = output.len() / nchannels.max(1);
+        let r = (1.0 - 2.0 * PI * DC_BLOCKER_HZ / sample_rate) as f32;
+        let lookahead = self.lookahead(sample_rate);
+        let attack = (4.0 / lookahead as f32).min(1.0);
+        let release = 1.0 / (RELEASE_SEC * sample_rate) as f32;
+        let mut bad_p = false;
+        let mut gain_min = 1.0f32;
+
+        for frame in 0..nframes {
+            let mut peak = 0.0f32;
+            for channel in 0..nchannels {
+                let index = frame * nchannels + channel;
+                let mut x = output[index];
+                if !x.is_finite() {
+                    x = 0.0;
+                    bad_p = true;
+                }
+
+                let (x1, y1) = self.dc_states[channel];
+                let y = x - x1 + r * y1;
+                self.dc_states[channel] = (x, y);
+
+                if ceiling.is_some() {
+                    let history = &mut self.histories[channel];
+                    history.rotate_left(1);
+                    history[3] = y;
+                    peak = peak.max(true_peak(*history));
+                    self.delays[channel].push_back(y);
+                } else {
+                    output[index] = y;
+                }
+            }
+
+            let Some(ceiling) = ceiling else {
+                continue;
+            };
+            // true_peak は history[2] までなので 1 フレーム前のもの
+            // 先頭がいま出力する lookahead + 1 フレーム前のもの
+            self.gains_required
+                .push_back(if peak > ceiling { ceiling / peak } else { 1.0 });
+            let gain_output = self.gains_required.pop_front().unwrap_or(1.0);
+            // 先読みの間に下げきって、戻すときはゆっくり
+            let target = self
+                .gains_required
+                .iter()
+                .fold(gain_output, |acc, x| acc.min(*x));
+            if target < self.gain {
+                self.gain -= (self.gain - target) * attack;
+            } else {
+                self.gain = (self.gain + release).min(target);
+            }
+            // 下げきれていなければ出力するフレームの分だけは下げる
+            let gain = self.gain.min(gain_output);
+            gain_min = gain_min.min(gain);
+
+            // トゥルーピークは推定なのでサンプルの値は最後に必ず上限に収める
+            for channel in 0..nchannels {
+                let y = self.delays[channel].pop_front().unwrap_or(0.0);
+                output[frame * nchannels + channel] = (y * gain).clamp(-ceiling, ceiling);
+            }
+        }
+
+        self.gain_reduction = linear_to_db(gain_min);
+        bad_p
+    }
+}
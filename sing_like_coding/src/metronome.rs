@@ -0,0 +1,102 @@
+use std::{f32::consts::PI, ops::Range};
+
+const CLICK_SEC: f32 = 0.03;
+const FREQ_BEAT: f32 = 1000.0;
+const FREQ_BAR: f32 = 1500.0;
+
+/// Singer::process 内でクリック音を作る
+pub struct Metronome {
+    // (クリック開始からのフレーム数, 小節頭)
+    click: Option<(usize, bool)>,
+}
+
+impl Metronome {
+    pub fn new() -> Self {
+        Self { click: None }
+    }
+
+    /// ranges は曲中の位置 (line * 0x100 + delay) でブロックの前から順に並ぶ
+    /// ループの折り返しで 2 つになる
+    pub fn clicks_by_position(
+        ranges: &[Range<usize>],
+        delays_per_beat: usize,
+        beats_per_bar: usize,
+        nframes: usize,
+    ) -> Vec<(usize, bool)> {
+        let span = ranges.iter().map(|x| x.len()).sum::<usize>();
+        if span == 0 || delays_per_beat == 0 {
+            return vec![];
+        }
+        let mut clicks = vec![];
+        let mut offset = 0;
+        for range in ranges {
+            let mut time = range.start.div_ceil(delays_per_beat) * delays_per_beat;
+            while time < range.end {
+                let frame = (offset + time - range.start) * nframes / span;
+                let beat = time / delays_per_beat;
+                clicks.push((frame, beat % beats_per_bar.max(1) == 0));
+                time += delays_per_beat;
+            }
+            offset += range.len();
+        }
+        clicks
+    }
+
+    /// カウントイン用 frame はカウントイン開始からのフレーム数
+    pub fn clicks_by_frame(
+        frame: usize,
+        frames_per_beat: f64,
+        beats_per_bar: usize,
+        nframes: usize,
+    ) -> Vec<(usize, bool)> {
+        if frames_per_beat <= 0.0 {
+            return vec![];
+        }
+        let mut clicks = vec![];
+        let mut beat = (frame as f64 / frames_per_beat).ceil() as usize;
+        loop {
+            let beat_frame = (beat as f64 * frames_per_beat).round() as usize;
+            if beat_frame >= frame + nframes {
+                break;
+            }
+            if beat_frame >= frame {
+                clicks.push((beat_frame - frame, beat % beats_per_bar.max(1) == 0));
+            }
+            beat += 1;
+        }
+        clicks
+    }
+
+    pub fn render(
+        &mut self,
+        clicks: &[(usize, bool)],
+        output: &mut [f32],
+        nchannels: usize,
+        sample_rate: f64,
+        volume: f32,
+    ) {
+        let sample_rate = sample_rate as f32;
+        let click_frames = (CLICK_SEC * sample_rate) as usize;
+        let nframes = output.len() / nchannels;
+        let mut clicks = clicks.iter().peekable();
+        for frame in 0..nframes {
+            while let Some((_, bar_p)) = clicks.next_if(|(x, _)| *x <= frame) {
+                self.click = Some((0, *bar_p));
+            }
+            let Some((i, bar_p)) = self.click.as_mut() else {
+                continue;
+            };
+            let freq = if *bar_p { FREQ_BAR } else { FREQ_BEAT };
+            let t = *i as f32 / sample_rate;
+            let envelope = 1.0 - *i as f32 / click_frames as f32;
+            let value = (2.0 * PI * freq * t).sin() * envelope * envelope * volume;
+            for channel in 0..nchannels {
+                output[nchannels * frame + channel] += value;
+            }
+            *i += 1;
+            if *i >= click_frames {
+                self.click = None;
+            }
+        }
+    }
+}
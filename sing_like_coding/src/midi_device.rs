@@ -1,4 +1,4 @@
-use std::sync::mpsc::Sender;
+use std::{sync::mpsc::Sender, time::Instant};
 
 use anyhow::{anyhow, Result};
 use common::event::Event;
@@ -19,7 +19,7 @@ impl MidiDevice {
             .collect()
     }
 
-    pub fn new(name: &str, sender_midi: Sender<Event>) -> Result<Self> {
+    pub fn new(name: &str, sender_midi: Sender<(Instant, Event)>) -> Result<Self> {
         let input = MidiInput::new("SLC")?;
         let port = input
             .ports()
@@ -30,17 +30,25 @@ impl MidiDevice {
             &port,
             "SLC",
             move |_timestamp, data, ()| {
+                // ブロック内の位置を決めるため届いた時刻を記録する
+                let instant = Instant::now();
                 let Ok(message) = MidiMessage::try_from(data) else {
                     return;
                 };
                 let event = match message {
+                    // velocity 0 の NoteOn は NoteOff
+                    MidiMessage::NoteOn(_channel, key, velocity) if u8::from(velocity) == 0 => {
+                        Event::NoteOff(key as i16, 64.0, 0)
+                    }
                     MidiMessage::NoteOn(_channel, key, velocity) => {
                         Event::NoteOn(key as i16, u8::from(velocity) as f64, 0)
                     }
-                    MidiMessage::NoteOff(_channel, key, _velocity) => Event::NoteOff(key as i16, 0),
+                    MidiMessage::NoteOff(_channel, key, velocity) => {
+                        Event::NoteOff(key as i16, u8::from(velocity) as f64, 0)
+                    }
                     _ => return,
                 };
-                let _ = sender_midi.send(event);
+                let _ = sender_midi.send((instant, event));
             },
             (),
         )?;
@@ -1,6 +1,9 @@
+pub mod audio_clip;
 pub mod lane;
 pub mod lane_item;
 pub mod note;
+pub mod pan_law;
 pub mod point;
 pub mod song;
+pub mod take;
 pub mod track;
@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// WAV/FLAC ファイルを鳴らす
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AudioClip {
+    pub path: String,
+    /// ファイルの先頭から飛ばす秒数
+    pub offset: f64,
+    /// dB
+    pub gain: f32,
+    pub delay: u8,
+}
+
+impl AudioClip {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            ..Default::default()
+        }
+    }
+
+    pub fn gain_linear(&self) -> f32 {
+        10.0f32.powf(self.gain / 20.0)
+    }
+
+    pub fn name(&self) -> String {
+        Path::new(&self.path)
+            .file_stem()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
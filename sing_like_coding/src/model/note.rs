@@ -7,6 +7,11 @@ pub struct Note {
     pub delay: u8,
     pub off: bool,
     pub channel: i16,
+    // 同じ line 内でノートを止める delay
+    #[serde(default)]
+    pub cut: Option<u8>,
+    #[serde(default)]
+    pub off_velocity: f64,
 }
 
 impl Note {
@@ -32,6 +37,8 @@ impl Default for Note {
             delay: 0,
             off: false,
             channel: 0,
+            cut: None,
+            off_velocity: 0.0,
         }
     }
 }
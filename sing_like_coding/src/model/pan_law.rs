@@ -0,0 +1,64 @@
+use std::f32::consts::FRAC_PI_2;
+
+use serde::{Deserialize, Serialize};
+
+/// センターにしたときの減衰量でパンの左右のゲインを決める
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PanLaw {
+    /// コンスタントパワーをセンター 0dB に持ち上げたもの
+    Db0,
+    /// コンスタントパワー
+    #[default]
+    Db3,
+    Db4_5,
+    /// リニア
+    Db6,
+    /// 反対側だけ下げるバランス
+    Balance,
+}
+
+pub const PAN_LAWS: [PanLaw; 5] = [
+    PanLaw::Db0,
+    PanLaw::Db3,
+    PanLaw::Db4_5,
+    PanLaw::Db6,
+    PanLaw::Balance,
+];
+
+impl PanLaw {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PanLaw::Db0 => "0 dB",
+            PanLaw::Db3 => "-3 dB",
+            PanLaw::Db4_5 => "-4.5 dB",
+            PanLaw::Db6 => "-6 dB",
+            PanLaw::Balance => "Balance",
+        }
+    }
+
+    /// pan 0.0 が左 1.0 が右 (左のゲイン, 右のゲイン)
+    pub fn gains(&self, pan: f32) -> (f32, f32) {
+        let pan = pan.clamp(0.0, 1.0);
+        let angle = pan * FRAC_PI_2;
+        match self {
+            PanLaw::Db0 => (
+                angle.cos() * std::f32::consts::SQRT_2,
+                angle.sin() * std::f32::consts::SQRT_2,
+            ),
+            PanLaw::Db3 => (angle.cos(), angle.sin()),
+            PanLaw::Db4_5 => (
+                (angle.cos() * (1.0 - pan)).sqrt(),
+                (angle.sin() * pan).sqrt(),
+            ),
+            PanLaw::Db6 => (1.0 - pan, pan),
+            PanLaw::Balance => ((2.0 - pan * 2.0).min(1.0), (pan * 2.0).min(1.0)),
+        }
+    }
+
+    /// ステレオの音源はセンターで 0dB になるようにして持ち上げない
+    pub fn gains_stereo(&self, pan: f32) -> (f32, f32) {
+        let (center_l, center_r) = self.gains(0.5);
+        let (l, r) = self.gains(pan);
+        ((l / center_l).min(1.0), (r / center_r).min(1.0))
+    }
+}
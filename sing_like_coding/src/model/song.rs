@@ -14,6 +14,9 @@ pub struct Song {
     pub bpm: f64,
     pub sample_rate: f64,
     pub lpb: u16,
+    /// 拍子 (分子, 分母)
+    #[serde(default = "time_signature_default")]
+    pub time_signature: (u16, u16),
     pub tracks: Vec<Track>,
 }
 
@@ -24,10 +27,39 @@ impl Song {
             bpm: 128.0,
             sample_rate: 48000.0,
             lpb: 4,
+            time_signature: time_signature_default(),
             tracks: vec![],
         }
     }
 
+    pub fn beats_per_bar(&self) -> usize {
+        self.time_signature.0.max(1) as usize
+    }
+
+    /// 1 拍の長さ lpb は 4 分音符あたりの line 数
+    pub fn delays_per_beat(&self) -> usize {
+        self.lpb as usize * 0x100 * 4 / self.time_signature.1.max(1) as usize
+    }
+
+    pub fn module_by_id(&self, id: ModuleId) -> Option<&Module> {
+        self.tracks
+            .iter()
+            .find_map(|track| track.modules.iter().find(|module| module.id == id))
+    }
+
+    pub fn module_index_by_id(&self, id: ModuleId) -> Option<ModuleIndex> {
+        self.tracks
+            .iter()
+            .enumerate()
+            .find_map(|(track_index, track)| {
+                track
+                    .modules
+                    .iter()
+                    .position(|module| module.id == id)
+                    .map(|module_index| (track_index, module_index))
+            })
+    }
+
     pub fn module_by_id_mut(&mut self, id: ModuleId) -> Option<&mut Module> {
         self.tracks
             .iter_mut()
@@ -206,3 +238,7 @@ pub fn topological_levels(song: &Song) -> anyhow::Result<Vec<Vec<ModuleIndex>>>
 
     Ok(levels)
 }
+
+fn time_signature_default() -> (u16, u16) {
+    (4, 4)
+}
@@ -0,0 +1,57 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use super::lane::Lane;
+
+/// ループ録音の 1 周分
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Take {
+    pub lanes: Vec<Lane>,
+    /// 再生する line の範囲
+    pub active_ranges: Vec<Range<usize>>,
+}
+
+impl Take {
+    pub fn new(lines: Range<usize>) -> Self {
+        Self {
+            lanes: vec![Lane::new()],
+            active_ranges: vec![lines],
+        }
+    }
+
+    pub fn active_p(&self, line: usize) -> bool {
+        self.active_ranges.iter().any(|x| x.contains(&line))
+    }
+
+    pub fn active_add(&mut self, lines: Range<usize>) {
+        self.active_remove(lines.clone());
+        self.active_ranges.push(lines);
+        self.active_ranges.sort_by_key(|x| x.start);
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for range in self.active_ranges.drain(..) {
+            match ranges.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => ranges.push(range),
+            }
+        }
+        self.active_ranges = ranges;
+    }
+
+    pub fn active_remove(&mut self, lines: Range<usize>) {
+        let mut ranges = vec![];
+        for range in self.active_ranges.drain(..) {
+            if range.end <= lines.start || lines.end <= range.start {
+                ranges.push(range);
+                continue;
+            }
+            if range.start < lines.start {
+                ranges.push(range.start..lines.start);
+            }
+            if lines.end < range.end {
+                ranges.push(lines.end..range.end);
+            }
+        }
+        self.active_ranges = ranges;
+    }
+}
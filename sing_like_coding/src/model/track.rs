@@ -7,26 +7,54 @@ use std::{
 use anyhow::Result;
 use clap_sys::id::clap_id;
 use common::{
-    dsp::db_to_norm, event::Event, module::Module, process_track_context::ProcessTrackContext,
+    channel_layout::ChannelLayout, dsp::db_to_norm, event::Event, module::Module,
+    process_data::MAX_CHANNELS, process_track_context::ProcessTrackContext,
 };
 use serde::{Deserialize, Serialize};
 
 use crate::view::stereo_peak_meter::{DB_MAX, DB_MIN};
 
-use super::{lane::Lane, lane_item::LaneItem, note::Note};
+use super::{
+    audio_clip::AudioClip, lane::Lane, lane_item::LaneItem, note::Note, pan_law::PanLaw, take::Take,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Track {
     pub name: String,
     pub volume: f32,
     pub pan: f32,
+    /// サラウンドの前後 0.0 が前 1.0 が後ろ
+    #[serde(default)]
+    pub pan_rear: f32,
+    /// メイントラックの layout がバスのチャンネル構成になる
+    #[serde(default)]
+    pub layout: ChannelLayout,
+    #[serde(default)]
+    pub pan_law: PanLaw,
+    /// 0.0 でモノラル 1.0 でそのまま 2.0 で広げる
+    #[serde(default = "width_default")]
+    pub width: f32,
+    /// ステレオの左右を pan と pan_right で別々に振る
+    #[serde(default)]
+    pub dual_pan_p: bool,
+    #[serde(default = "pan_right_default")]
+    pub pan_right: f32,
     pub mute: bool,
     pub solo: bool,
     pub modules: Vec<Module>,
     pub lanes: Vec<Lane>,
     pub automation_params: Vec<(usize, clap_id)>, // (module_index, param_id)
+    #[serde(default)]
+    pub takes: Vec<Take>,
+    // 入力デバイスのチャンネル 空なら入力なし
+    #[serde(default)]
+    pub input_channels: Vec<usize>,
     #[serde(skip_serializing, skip_deserializing)]
     on_key_lane_map: HashMap<i16, usize>,
+    #[serde(skip_serializing, skip_deserializing)]
+    take_rec: Option<usize>,
+    #[serde(skip_serializing, skip_deserializing)]
+    pub audio_rec_id: Option<usize>,
 }
 
 impl Track {
@@ -35,13 +63,88 @@ impl Track {
             name: "T01".to_string(),
             volume: db_to_norm(0.0, DB_MIN, DB_MAX),
             pan: 0.5,
+            pan_rear: 0.0,
+            layout: ChannelLayout::Stereo,
+            pan_law: PanLaw::Db3,
+            width: width_default(),
+            dual_pan_p: false,
+            pan_right: pan_right_default(),
             solo: false,
             mute: false,
             modules: vec![],
             lanes: vec![Lane::new()],
             automation_params: vec![],
+            takes: vec![],
+            input_channels: vec![],
             on_key_lane_map: Default::default(),
+            take_rec: None,
+            audio_rec_id: None,
+        }
+    }
+
+    /// 出力の各チャンネルを bus の各チャンネルに送るゲイン [src][dst]
+    pub fn mix_gains(
+        &self,
+        nchannels_src: usize,
+        bus: ChannelLayout,
+    ) -> [[f32; MAX_CHANNELS]; MAX_CHANNELS] {
+        let mut gains = [[0.0; MAX_CHANNELS]; MAX_CHANNELS];
+        let nchannels_src = nchannels_src.min(MAX_CHANNELS);
+        match bus {
+            _ if nchannels_src > 2 => {
+                // スピーカーの役割で合わせる パンはしない
+                gains = ChannelLayout::from_nchannels(nchannels_src)
+                    .downmix_gains(bus)
+                    .map(|dsts| dsts.map(|gain| self.volume * gain));
+            }
+            ChannelLayout::Mono => {
+                for src in 0..nchannels_src {
+                    gains[src][0] = self.volume / nchannels_src as f32;
+                }
+            }
+            ChannelLayout::Stereo if nchannels_src == 1 => {
+                let (gain_l, gain_r) = self.pan_law.gains(self.pan);
+                gains[0][0] = self.volume * gain_l;
+                gains[0][1] = self.volume * gain_r;
+            }
+            ChannelLayout::Stereo if self.dual_pan_p => {
+                for (src, pan) in [(0, self.pan), (1, self.pan_right)] {
+                    let (gain_l, gain_r) = self.pan_law.gains(pan);
+                    gains[src][0] = self.volume * gain_l;
+                    gains[src][1] = self.volume * gain_r;
+                }
+            }
+            ChannelLayout::Stereo => {
+                // width で M/S の S を増減してから左右のバランス
+                let (gain_l, gain_r) = self.pan_law.gains_stereo(self.pan);
+                let same = (1.0 + self.width) / 2.0;
+                let cross = (1.0 - self.width) / 2.0;
+                gains[0][0] = self.volume * same * gain_l;
+                gains[0][1] = self.volume * cross * gain_r;
+                gains[1][0] = self.volume * cross * gain_l;
+                gains[1][1] = self.volume * same * gain_r;
+            }
+            _ => {
+                // x は -1.0 が左 1.0 が右、y は 1.0 が前 -1.0 が後ろ
+                let x = self.pan * 2.0 - 1.0;
+                let y = 1.0 - self.pan_rear * 2.0;
+                let xs = if nchannels_src == 1 {
+                    vec![x]
+                } else if self.dual_pan_p {
+                    vec![x, self.pan_right * 2.0 - 1.0]
+                } else {
+                    let spread = 0.5 * self.width;
+                    vec![(x - spread).max(-1.0), (x + spread).min(1.0)]
+                };
+                for (src, x) in xs.into_iter().enumerate() {
+                    let angle = x.atan2(y).to_degrees();
+                    for (dst, gain) in bus.pan_gains(angle).into_iter().enumerate() {
+                        gains[src][dst] = self.volume * gain;
+                    }
+                }
+            }
         }
+        gains
     }
 
     pub fn process_module(
@@ -79,15 +182,35 @@ impl Track {
         let line_end = range.end / 0x100;
         for line in line_start..=line_end {
             let mut events = vec![];
-            for (lane_index, lane) in self.lanes.iter().enumerate() {
+            let lanes = self.lanes.iter().map(|lane| (None, lane)).chain(
+                self.takes
+                    .iter()
+                    .flat_map(|take| take.lanes.iter().map(move |lane| (Some(take), lane))),
+            );
+            for (lane_index, (take, lane)) in lanes.enumerate() {
+                if take.is_some_and(|take| !take.active_p(line)) {
+                    // 非アクティブになった take で鳴っているノートを止める
+                    let time = line * 0x100;
+                    if range.contains(&time) {
+                        if let Some(Some(key)) =
+                            context.on_keys.get_mut(lane_index).map(|x| x.take())
+                        {
+                            events.push(Event::NoteOff(key, 0.0, time - range.start));
+                        }
+                    }
+                    continue;
+                }
                 if let Some((line, item)) = lane.items.get_key_value(&line) {
                     let time = *line * 0x100 + item.delay() as usize;
                     match item {
                         LaneItem::Note(note) => {
                             if range.contains(&time) {
                                 let delay = time - range.start;
-                                if let Some(Some(key)) = context.on_keys.get(lane_index).take() {
-                                    events.push(Event::NoteOff(*key, delay));
+                                if let Some(Some(key)) =
+                                    context.on_keys.get_mut(lane_index).map(|x| x.take())
+                                {
+                                    let velocity = if note.off { note.off_velocity } else { 0.0 };
+                                    events.push(Event::NoteOff(key, velocity, delay));
                                 }
                                 if !note.off {
                                     events.push(Event::NoteOn(note.key, note.velocity, delay));
@@ -97,6 +220,19 @@ impl Track {
                                     context.on_keys[lane_index] = Some(note.key);
                                 }
                             }
+                            if let (false, Some(cut)) = (note.off, note.cut) {
+                                let time_cut = *line * 0x100 + cut as usize;
+                                if range.contains(&time_cut)
+                                    && context.on_keys.get(lane_index) == Some(&Some(note.key))
+                                {
+                                    context.on_keys[lane_index] = None;
+                                    events.push(Event::NoteOff(
+                                        note.key,
+                                        note.off_velocity,
+                                        time_cut - range.start,
+                                    ));
+                                }
+                            }
                         }
                         LaneItem::Point(point) => {
                             if range.contains(&time) {
@@ -129,6 +265,16 @@ impl Track {
                                 return;
                             }
                         }
+                        LaneItem::AudioClip(_) => {
+                            if range.contains(&time) {
+                                // Call/Ret のずれを戻して play_position 上の時刻にする
+                                context.audio_clip_starts.push((
+                                    lane_index,
+                                    *line,
+                                    r.start + (time - range.start),
+                                ));
+                            }
+                        }
                     }
                 }
             }
@@ -136,53 +282,136 @@ impl Track {
         }
     }
 
-    pub fn events_append(
+    /// events は (line * 0x100 + delay, Event) で時刻順
+    pub fn events_append(&mut self, events: &[(usize, Event)]) -> Result<()> {
+        lanes_events_append(&mut self.lanes, &mut self.on_key_lane_map, events);
+        Ok(())
+    }
+
+    /// ループ録音用 1 周ごとに take を分けて記録する
+    pub fn events_append_take(
         &mut self,
-        events: &Vec<Event>,
-        play_position: &Range<usize>,
+        events: &[(usize, Event)],
+        loop_range: Range<usize>,
     ) -> Result<()> {
-        let line = play_position.start / 0x100;
-        let delay = (play_position.start % 0x100) as u8;
-        for event in events {
-            match event {
-                Event::NoteOn(key, velocity, _) => {
-                    let lane_item = LaneItem::Note(Note {
-                        key: *key,
-                        velocity: *velocity,
-                        delay,
-                        ..Default::default()
-                    });
-                    for lane_index in 0..usize::MAX {
-                        if self.lanes.len() - 1 < lane_index {
-                            self.lane_add();
-                        }
-                        if !self.lanes[lane_index].items.contains_key(&line) {
-                            self.lanes[lane_index].items.insert(line, lane_item);
-                            self.on_key_lane_map.insert(*key, lane_index);
-                            break;
-                        }
-                    }
-                }
-                Event::NoteOff(key, _) => {
-                    // TODO 1 line で On, Off あると Off だけになる
-                    // Cut FX コマンドの実装が必要
-                    if let Some(lane_index) = self.on_key_lane_map.get(key) {
-                        let lane_item = LaneItem::Note(Note {
-                            key: *key,
-                            off: true,
-                            delay,
-                            ..Default::default()
-                        });
-                        self.lanes[*lane_index].items.insert(line, lane_item);
-                    }
-                }
-                Event::NoteAllOff => continue,
-                Event::ParamValue(_, _, _, _) => continue,
-            }
+        if events.is_empty() {
+            return Ok(());
         }
+        let take_index = match self.take_rec {
+            Some(take_index) => take_index,
+            None => self.take_new(loop_range),
+        };
+        lanes_events_append(
+            &mut self.takes[take_index].lanes,
+            &mut self.on_key_lane_map,
+            events,
+        );
         Ok(())
     }
 
+    fn take_new(&mut self, loop_range: Range<usize>) -> usize {
+        let lines = (loop_range.start / 0x100)..loop_range.end.div_ceil(0x100);
+        for take in self.takes.iter_mut() {
+            take.active_remove(lines.clone());
+        }
+        self.takes.push(Take::new(lines));
+        self.on_key_lane_map.clear();
+        let take_index = self.takes.len() - 1;
+        self.take_rec = Some(take_index);
+        take_index
+    }
+
+    /// 録音中の take を閉じる
+    pub fn take_close(&mut self, time: usize) -> bool {
+        if self.take_rec.is_none() {
+            return false;
+        }
+        self.notes_release(time);
+        self.take_rec = None;
+        true
+    }
+
+    /// 押さえたままのノートを time で止める
+    pub fn notes_release(&mut self, time: usize) -> bool {
+        let events = self
+            .on_key_lane_map
+            .keys()
+            .map(|key| (time, Event::NoteOff(*key, 0.0, 0)))
+            .collect::<Vec<_>>();
+        if events.is_empty() {
+            return false;
+        }
+        let lanes = match self.take_rec {
+            Some(take_index) => &mut self.takes[take_index].lanes,
+            None => &mut self.lanes,
+        };
+        lanes_events_append(lanes, &mut self.on_key_lane_map, &events);
+        true
+    }
+
+    /// line で再生される take
+    pub fn take_active_at(&self, line: usize) -> Option<usize> {
+        self.takes.iter().rposition(|take| take.active_p(line))
+    }
+
+    /// lines の範囲で再生する take を選ぶ None ならどの take も再生しない
+    pub fn take_activate(&mut self, take_index: Option<usize>, lines: Range<usize>) {
+        for take in self.takes.iter_mut() {
+            take.active_remove(lines.clone());
+        }
+        if let Some(take) = take_index.and_then(|x| self.takes.get_mut(x)) {
+            take.active_add(lines);
+        }
+    }
+
+    /// 録音中の take を消したら次のイベントで新しい take を作る
+    pub fn take_delete(&mut self, take_index: usize) {
+        if take_index >= self.takes.len() {
+            return;
+        }
+        self.takes.remove(take_index);
+        match self.take_rec {
+            Some(x) if x == take_index => {
+                self.take_rec = None;
+                self.on_key_lane_map.clear();
+            }
+            Some(x) if x > take_index => self.take_rec = Some(x - 1),
+            _ => {}
+        }
+    }
+
+    /// undo などで take を丸ごと置き換える 録音中の take は対応が取れないので閉じる
+    pub fn takes_set(&mut self, takes: Vec<Take>) {
+        self.takes = takes;
+        if self.take_rec.take().is_some() {
+            self.on_key_lane_map.clear();
+        }
+    }
+
+    /// 置き換え録音用に range 内のノートを消す
+    pub fn notes_clear(&mut self, range: Range<usize>) -> bool {
+        if range.is_empty() {
+            return false;
+        }
+        let mut cleared_p = false;
+        for lane in self.lanes.iter_mut() {
+            let lines = lane
+                .items
+                .range((range.start / 0x100)..=(range.end / 0x100))
+                .filter(|(line, item)| {
+                    matches!(item, LaneItem::Note(_))
+                        && range.contains(&(*line * 0x100 + item.delay() as usize))
+                })
+                .map(|(line, _)| *line)
+                .collect::<Vec<_>>();
+            for line in lines {
+                lane.items.remove(&line);
+                cleared_p = true;
+            }
+        }
+        cleared_p
+    }
+
     fn label_find(&self, label: &str) -> Option<usize> {
         for lane in self.lanes.iter() {
             for (line, item) in lane.items.iter() {
@@ -194,10 +423,33 @@ impl Track {
         None
     }
 
+    /// take の lane も含めた lane_index で引く
+    pub fn lane_at(&self, lane_index: usize) -> Option<&Lane> {
+        self.lanes
+            .iter()
+            .chain(self.takes.iter().flat_map(|take| take.lanes.iter()))
+            .nth(lane_index)
+    }
+
     pub fn lane_add(&mut self) {
         self.lanes.push(Lane::new());
     }
 
+    /// 録音したファイルを空いている lane に置く なければ lane を足す
+    pub fn audio_clip_place(&mut self, time: usize, mut clip: AudioClip) {
+        clip.delay = (time % 0x100) as u8;
+        let lane_index = match self.lanes.iter().position(|x| x.items.is_empty()) {
+            Some(lane_index) => lane_index,
+            None => {
+                self.lane_add();
+                self.lanes.len() - 1
+            }
+        };
+        self.lanes[lane_index]
+            .items
+            .insert(time / 0x100, LaneItem::AudioClip(clip));
+    }
+
     fn prepare_module_audio(
         &self,
         track_index: usize,
@@ -300,10 +552,12 @@ impl Track {
                 Event::NoteOn(key, velocity, delay) => {
                     data.input_note_on(*key, *velocity, 0, *delay)
                 }
-                Event::NoteOff(key, delay) => data.input_note_off(*key, 0, *delay),
+                Event::NoteOff(key, velocity, delay) => {
+                    data.input_note_off(*key, *velocity, 0, *delay)
+                }
                 Event::NoteAllOff => {
                     for key in context.on_keys.drain(..).filter_map(|x| x) {
-                        data.input_note_off(key, 0, 0);
+                        data.input_note_off(key, 0.0, 0, 0);
                     }
                 }
                 Event::ParamValue(mindex, param_id, value, delay) => {
@@ -316,3 +570,72 @@ impl Track {
         Ok(())
     }
 }
+
+/// events は (line * 0x100 + delay, Event) で時刻順
+fn lanes_events_append(
+    lanes: &mut Vec<Lane>,
+    on_key_lane_map: &mut HashMap<i16, usize>,
+    events: &[(usize, Event)],
+) {
+    for (time, event) in events {
+        let line = time / 0x100;
+        let delay = (time % 0x100) as u8;
+        match event {
+            Event::NoteOn(key, velocity, _) => {
+                let lane_item = LaneItem::Note(Note {
+                    key: *key,
+                    velocity: *velocity,
+                    delay,
+                    ..Default::default()
+                });
+                for lane_index in 0..usize::MAX {
+                    if lanes.len() <= lane_index {
+                        lanes.push(Lane::new());
+                    }
+                    // 押さえたままのノートがある lane には入れない
+                    if !lanes[lane_index].items.contains_key(&line)
+                        && !on_key_lane_map.values().any(|x| *x == lane_index)
+                    {
+                        lanes[lane_index].items.insert(line, lane_item);
+                        on_key_lane_map.insert(*key, lane_index);
+                        break;
+                    }
+                }
+            }
+            Event::NoteOff(key, velocity, _) => {
+                let Some(lane_index) = on_key_lane_map.remove(key) else {
+                    continue;
+                };
+                let items = &mut lanes[lane_index].items;
+                match items.get_mut(&line) {
+                    // 同じ line で On したノートは cut で止める
+                    Some(LaneItem::Note(note)) if !note.off && note.key == *key => {
+                        note.cut = Some(delay.max(note.delay));
+                        note.off_velocity = *velocity;
+                    }
+                    Some(_) => {}
+                    None => {
+                        let lane_item = LaneItem::Note(Note {
+                            key: *key,
+                            off: true,
+                            delay,
+                            off_velocity: *velocity,
+                            ..Default::default()
+                        });
+                        items.insert(line, lane_item);
+                    }
+                }
+            }
+            Event::NoteAllOff => continue,
+            Event::ParamValue(_, _, _, _) => continue,
+        }
+    }
+}
+
+fn width_default() -> f32 {
+    1.0
+}
+
+fn pan_right_default() -> f32 {
+    1.0
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    io::{BufReader, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use common::util::dir_user_setting;
+use serde::{Deserialize, Serialize};
+
+const RECENTS_MAX: usize = 32;
+
+/// プラグインブラウザーのお気に入り、タグ、最近使ったもの
+/// キーは Description の id
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginLibrary {
+    #[serde(default)]
+    pub favorites: Vec<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, Vec<String>>,
+    /// 新しい順
+    #[serde(default)]
+    pub recents: Vec<String>,
+}
+
+impl PluginLibrary {
+    fn file() -> PathBuf {
+        dir_user_setting().join("plugin_library.json")
+    }
+
+    pub fn load() -> Result<Self> {
+        let file = File::open(Self::file())?;
+        let reader = BufReader::new(file);
+        let library = serde_json::from_reader(reader)?;
+        Ok(library)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::file();
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let mut file = File::create(path)?;
+        let json = serde_json::to_string_pretty(&self)?;
+        file.write_all(json.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn favorite_p(&self, id: &str) -> bool {
+        self.favorites.iter().any(|x| x == id)
+    }
+
+    pub fn favorite_toggle(&mut self, id: &str) {
+        if self.favorite_p(id) {
+            self.favorites.retain(|x| x != id);
+        } else {
+            self.favorites.push(id.to_string());
+        }
+    }
+
+    pub fn recent_push(&mut self, id: &str) {
+        self.recents.retain(|x| x != id);
+        self.recents.insert(0, id.to_string());
+        self.recents.truncate(RECENTS_MAX);
+    }
+
+    /// 使ったことがなければ None
+    pub fn recent_rank(&self, id: &str) -> Option<usize> {
+        self.recents.iter().position(|x| x == id)
+    }
+
+    pub fn tags_of(&self, id: &str) -> &[String] {
+        self.tags.get(id).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn tag_add(&mut self, id: &str, tag: &str) {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            return;
+        }
+        let tags = self.tags.entry(id.to_string()).or_default();
+        if !tags.iter().any(|x| x == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+
+    pub fn tag_remove(&mut self, id: &str, tag: &str) {
+        if let Some(tags) = self.tags.get_mut(id) {
+            tags.retain(|x| x != tag);
+            if tags.is_empty() {
+                self.tags.remove(id);
+            }
+        }
+    }
+
+    /// 使われているタグを名前順で
+    pub fn tags_all(&self) -> Vec<String> {
+        let mut tags = self.tags.values().flatten().cloned().collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    fs::{create_dir_all, read, read_dir, remove_file, write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use common::util::dir_user_setting;
+
+const EXTENSION: &str = "bin";
+
+/// ユーザーのプリセット Plugin::state_save の中身をそのまま
+/// presets/<プラグインの id>/<名前>.bin に置く
+fn dir(plugin_id: &str) -> PathBuf {
+    dir_user_setting()
+        .join("presets")
+        .join(file_name_sanitize(plugin_id))
+}
+
+fn file(plugin_id: &str, name: &str) -> PathBuf {
+    dir(plugin_id).join(format!("{}.{}", file_name_sanitize(name), EXTENSION))
+}
+
+fn file_name_sanitize(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| {
+            if c.is_control() || "<>:\"/\\|?*".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// 名前順
+pub fn names(plugin_id: &str) -> Vec<String> {
+    let Ok(entries) = read_dir(dir(plugin_id)) else {
+        return vec![];
+    };
+    let mut names = entries
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| x.extension().and_then(|x| x.to_str()) == Some(EXTENSION))
+        .filter_map(|x| x.file_stem().map(|x| x.to_string_lossy().to_string()))
+        .collect::<Vec<_>>();
+    names.sort_by_key(|x| x.to_lowercase());
+    names
+}
+
+/// 同じ名前があれば上書きする
+pub fn save(plugin_id: &str, name: &str, state: &[u8]) -> Result<()> {
+    create_dir_all(dir(plugin_id))?;
+    write(file(plugin_id, name), state)?;
+    Ok(())
+}
+
+pub fn load(plugin_id: &str, name: &str) -> Result<Vec<u8>> {
+    Ok(read(file(plugin_id, name))?)
+}
+
+pub fn delete(plugin_id: &str, name: &str) -> Result<()> {
+    remove_file(file(plugin_id, name))?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::{
+    path::Path,
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread,
+};
+
+use common::{
+    clap_manager::{ClapManager, ScanReport},
+    PLUGIN_HOST_EXE,
+};
+
+/// ClapManager のスキャンを別スレッドで回す
+/// 1 ファイルずつ子プロセスでスキャンするのでメイン側のプロセスで回しても巻き込まれない
+pub struct PluginScanner {
+    // (結果, claps.json が変わったか) スキャン中だけ Some
+    receiver: Option<Receiver<(Vec<ScanReport>, bool)>>,
+}
+
+impl PluginScanner {
+    pub fn new() -> Self {
+        Self { receiver: None }
+    }
+
+    pub fn scanning_p(&self) -> bool {
+        self.receiver.is_some()
+    }
+
+    /// スキャン中なら何もしない
+    pub fn start(&mut self, clap_paths: Vec<String>, gui_context: Option<eframe::egui::Context>) {
+        if self.scanning_p() {
+            return;
+        }
+        let (sender, receiver) = channel();
+        self.receiver = Some(receiver);
+        thread::spawn(move || {
+            log::debug!("plugin scan start...");
+            let mut clap_manager = ClapManager::new();
+            let result = clap_manager
+                .scan(Path::new(PLUGIN_HOST_EXE), &clap_paths)
+                .unwrap_or_else(|e| {
+                    log::error!("plugin scan failed {:?}", e);
+                    (vec![], false)
+                });
+            log::debug!("plugin scan end");
+            let _ = sender.send(result);
+            if let Some(gui_context) = gui_context {
+                gui_context.request_repaint();
+            }
+        });
+    }
+
+    pub fn receive(&mut self) -> Option<(Vec<ScanReport>, bool)> {
+        let receiver = self.receiver.as_ref()?;
+        match receiver.try_recv() {
+            Ok(result) => {
+                self.receiver = None;
+                Some(result)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                log::error!("plugin scan thread died");
+                self.receiver = None;
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    io::BufWriter,
+    path::PathBuf,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use anyhow::Result;
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::audio_clip_player::{AudioClipData, AudioClipLoaded};
+
+/// オーディオスレッドではファイルを書かないので別スレッドに送る
+#[derive(Debug)]
+pub enum RecorderMessage {
+    Start(usize, PathBuf, u16, u32), // id, path, nchannels, sample_rate
+    Data(usize, Vec<f32>),           // id, interleaved samples
+    Stop(usize),
+}
+
+/// 書き終わったファイルは読み込んで sender_to_player に送る
+pub fn start_recorder(sender_to_player: Sender<AudioClipLoaded>) -> Sender<RecorderMessage> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        if let Err(e) = recorder_loop(receiver, sender_to_player) {
+            log::error!("recorder_loop {:?}", e);
+        }
+    });
+    sender
+}
+
+fn recorder_loop(
+    receiver: Receiver<RecorderMessage>,
+    sender_to_player: Sender<AudioClipLoaded>,
+) -> Result<()> {
+    // id -> (writer, path, sample_rate)
+    let mut writers: HashMap<usize, (WavWriter<BufWriter<File>>, PathBuf, u32)> =
+        Default::default();
+    while let Ok(message) = receiver.recv() {
+        match message {
+            RecorderMessage::Start(id, path, nchannels, sample_rate) => {
+                if let Some(dir) = path.parent() {
+                    create_dir_all(dir)?;
+                }
+                let spec = WavSpec {
+                    channels: nchannels,
+                    sample_rate,
+                    bits_per_sample: 32,
+                    sample_format: SampleFormat::Float,
+                };
+                writers.insert(id, (WavWriter::create(&path, spec)?, path, sample_rate));
+            }
+            RecorderMessage::Data(id, samples) => {
+                if let Some((writer, _, _)) = writers.get_mut(&id) {
+                    for sample in samples {
+                        writer.write_sample(sample)?;
+                    }
+                }
+            }
+            RecorderMessage::Stop(id) => {
+                if let Some((writer, path, sample_rate)) = writers.remove(&id) {
+                    writer.finalize()?;
+                    let path = path.to_string_lossy().to_string();
+                    let sample_rate = sample_rate as f64;
+                    let data = match AudioClipData::load(&path, sample_rate) {
+                        Ok(data) => Some(Arc::new(data)),
+                        Err(e) => {
+                            log::warn!("recording {} {:?}", path, e);
+                            None
+                        }
+                    };
+                    let _ = sender_to_player.send((path, sample_rate, data));
+                }
+            }
+        }
+    }
+    for (_, (writer, _, _)) in writers {
+        writer.finalize()?;
+    }
+    Ok(())
+}
@@ -1,8 +1,9 @@
 use std::{
-    f32::consts::PI,
+    env::current_exe,
     fs::File,
     io::BufReader,
     ops::Range,
+    path::{Path, PathBuf},
     sync::{
         mpsc::{Receiver, Sender},
         Arc, Mutex,
@@ -12,30 +13,43 @@ use std::{
 
 use crate::{
     app_state::CursorTrack,
+    audio_clip_player::AudioClipPlayer,
+    audio_in::AudioIn,
+    loudness_meter::LoudnessMeter,
+    master_safety::MasterSafety,
+    metronome::Metronome,
     model::{
+        audio_clip::AudioClip,
         lane_item::LaneItem,
+        pan_law::PanLaw,
         point::Point,
         song::{topological_levels, Song},
+        take::Take,
         track::Track,
     },
-    song_state::SongState,
+    recorder::{start_recorder, RecorderMessage},
+    song_state::{SongState, MAX_LEVELS, MAX_MODULES},
+    tap::{TapSource, TapState},
     undo_history::UndoHistory,
     util::next_id,
     view::stereo_peak_meter::DB_MIN,
 };
 
 use anyhow::Result;
+use chrono::Local;
 use clap_sys::{
     fixedpoint::{clap_beattime, clap_sectime},
     id::clap_id,
 };
 use common::{
+    channel_layout::ChannelLayout,
+    dsp::linear_to_db,
     event::Event,
     module::{AudioInput, Module, ModuleId, ModuleIndex},
     plugin_ref::PluginRef,
-    process_data::{EventKind, ProcessData},
+    process_data::{EventKind, ProcessData, MAX_CHANNELS, MAX_FRAMES, MAX_PORTS},
     process_track_context::ProcessTrackContext,
-    shmem::{create_shared_memory, process_data_name, SONG_STATE_NAME},
+    shmem::{create_shared_memory, process_data_name, SONG_STATE_NAME, TAP_STATE_NAME},
 };
 use rayon::prelude::*;
 use shared_memory::Shmem;
@@ -48,36 +62,64 @@ pub enum MainToAudio {
     Stop,
     Loop,
     LoopRange(Range<usize>),
+    Punch,
+    PunchRange(Range<usize>),
+    CountInBars(usize),
     LaneAdd(usize),
     LaneItem(Vec<(CursorTrack, Option<LaneItem>)>),
+    MetronomeToggle,
+    MetronomeRecOnlyToggle,
+    MetronomeVolume(f32),
+    BadSamplesClear,
+    LimiterToggle,
+    LimiterCeiling(f32),
+    LoudnessReset,
+    TapSource(Option<TapSource>),
     #[allow(dead_code)]
     NoteOn(usize, i16, i16, f64, usize),
     #[allow(dead_code)]
     NoteOff(usize, i16, i16, f64, usize),
+    ModuleIsolated(ModuleIndex, bool),
+    PluginHostRestart,
+    PluginIsolated(usize, bool),
     PluginLatency(usize, u32),
     PluginLoad(usize, String, String),
     PluginDelete(ModuleIndex),
+    PluginReset(usize),
     PluginSidechain(ModuleIndex, AudioInput),
     PointNew(CursorTrack, usize, clap_id),
     Quit,
     RecToggle,
+    RecReplaceToggle,
     Redo,
+    TimeSignature(u16, u16),
     TrackAdd,
     TrackDelete(usize),
     TrackInsert(usize, Track),
     TrackMove(usize, isize),
+    TrackInputChannels(usize, Vec<usize>),
+    TrackDualPan(usize, bool),
+    TrackLayout(usize, ChannelLayout),
+    TrackMonitor(usize, bool),
     TrackMute(usize, bool),
     TrackSolo(usize, bool),
     TrackPan(usize, f32),
+    TrackPanLaw(usize, PanLaw),
+    TrackPanRear(usize, f32),
+    TrackPanRight(usize, f32),
     TrackRecOn(usize),
     TrackRecOff(usize),
     TrackRename(usize, String),
     TrackVolume(usize, f32),
+    TrackWidth(usize, f32),
     Undo,
     #[allow(dead_code)]
     Song,
     SongFile(String),
     SongOpen(String),
+    TakeActivate(usize, Option<usize>, Range<usize>),
+    TakeDelete(usize, usize),
+    TakesSet(usize, Vec<Take>),
 }
 
 #[derive(Debug)]
@@ -92,10 +134,32 @@ pub struct Singer {
     pub play_position: Range<usize>,
     play_position_start_last: usize,
     all_notef_off_p: bool,
-    midi_buffer: Arc<Mutex<Vec<Event>>>,
+    midi_buffer: Arc<Mutex<Vec<(Instant, Event)>>>,
+    process_start_last: Instant,
+    metronome: Metronome,
+    // カウントイン開始からのフレーム数
+    count_in_frame: Option<usize>,
+    pub audio_in: Arc<Mutex<AudioIn>>,
+    /// 出力のレイテンシー(秒)
+    pub output_latency: f64,
+    sender_to_recorder: Sender<RecorderMessage>,
+    // 録音の置き場所 曲のファイルが決まったときにパスだけ決める ディレクトリは録音のスレッドで作る
+    recording_dir: PathBuf,
+    audio_clip_player: AudioClipPlayer,
+    master_safety: MasterSafety,
+    loudness_meters: Vec<LoudnessMeter>,
+    // バスとトラックごとのミックス用 [channel][frame]
+    bus_buffer: Vec<Vec<f32>>,
+    track_buffer: Vec<Vec<f32>>,
+    clip_buffer: Vec<Vec<f32>>,
+    // メイントラックにモジュールがないときに使う 1 MiB 以上あるのでスタックに置かない
+    dummy_process_data: Box<ProcessData>,
     pub song: Song,
     _song_state_shmem: Shmem,
     song_state_ptr: *mut SongState,
+    _tap_state_shmem: Shmem,
+    tap_state_ptr: *const TapState,
+    tap_source: Option<TapSource>,
     sender_to_main: Sender<AudioToMain>,
     process_track_contexts: Vec<Arc<Mutex<ProcessTrackContext>>>,
     shmems: Vec<Vec<Shmem>>,
@@ -103,6 +167,10 @@ pub struct Singer {
 
     cpu_usages: Vec<f64>,
     process_elaspeds: Vec<f64>,
+    // 集計中のブロックの長さの合計(秒)
+    block_sec_sum: f64,
+    // topological_levels ごとの (秒数の合計, 最大)
+    level_elapseds: Vec<(f64, f64)>,
     process_elasped_last: Instant,
 }
 
@@ -113,16 +181,36 @@ impl Singer {
     pub fn new(sender_to_main: Sender<AudioToMain>) -> Self {
         let song_state_shmem = create_shared_memory::<SongState>(SONG_STATE_NAME).unwrap();
         let song_state_ptr = song_state_shmem.as_ptr() as *mut SongState;
+        let tap_state_shmem = create_shared_memory::<TapState>(TAP_STATE_NAME).unwrap();
+        let tap_state_ptr = tap_state_shmem.as_ptr() as *const TapState;
         let song = Song::new();
+        let audio_clip_player = AudioClipPlayer::new();
         let mut this = Self {
             steady_time: 0,
             play_position: 0..0,
             play_position_start_last: 0,
             all_notef_off_p: false,
             midi_buffer: Arc::new(Mutex::new(vec![])),
+            process_start_last: Instant::now(),
+            metronome: Metronome::new(),
+            count_in_frame: None,
+            audio_in: Arc::new(Mutex::new(AudioIn::new())),
+            output_latency: 0.0,
+            sender_to_recorder: start_recorder(audio_clip_player.sender()),
+            recording_dir: recording_dir(None),
+            audio_clip_player,
+            master_safety: MasterSafety::new(),
+            loudness_meters: vec![],
+            bus_buffer: vec![vec![0.0; MAX_FRAMES]; MAX_CHANNELS],
+            track_buffer: vec![vec![0.0; MAX_FRAMES]; MAX_CHANNELS],
+            clip_buffer: vec![vec![0.0; MAX_FRAMES]; MAX_CHANNELS],
+            dummy_process_data: ProcessData::new_boxed(),
             song,
             _song_state_shmem: song_state_shmem,
             song_state_ptr,
+            _tap_state_shmem: tap_state_shmem,
+            tap_state_ptr,
+            tap_source: None,
             sender_to_main,
             process_track_contexts: vec![],
             shmems: vec![],
@@ -130,11 +218,14 @@ impl Singer {
 
             cpu_usages: vec![],
             process_elaspeds: vec![],
+            block_sec_sum: 0.0,
+            level_elapseds: vec![],
             process_elasped_last: Instant::now(),
         };
         this.track_add();
         this.track_add();
         this.song_state_mut().init();
+        this.tap_state().init();
         this
     }
 
@@ -213,6 +304,8 @@ impl Singer {
                 .find(|plugin_ref| plugin_ref.id == id)
             {
                 plugin_ref.latency = latency;
+                // DidLoad のあとに来るのでここから process してよい
+                plugin_ref.loaded_p = true;
                 break;
             }
         }
@@ -236,8 +329,56 @@ impl Singer {
         Ok(id)
     }
 
+    /// ProcessData のバッファより大きいコールバックは MAX_FRAMES ごとに分けて処理する
     pub fn process(&mut self, output: &mut [f32], nchannels: usize) -> Result<()> {
         let this_start = Instant::now();
+        let nframes = output.len() / nchannels;
+
+        let mut midi_buffer = {
+            let midi_buffer = {
+                let mut x = self.midi_buffer.lock().unwrap();
+                std::mem::take(&mut *x)
+            };
+            self.midi_events_frame(midi_buffer, nframes)
+        };
+        self.process_start_last = this_start;
+
+        let mut frame_start = 0;
+        for output in output.chunks_mut(MAX_FRAMES * nchannels) {
+            // カウントインが途中で終わるならそこでブロックを分けて次の頭から再生する
+            let nframes = output.len() / nchannels;
+            let split = self
+                .count_in_frames_left()
+                .filter(|x| *x < nframes)
+                .unwrap_or(nframes);
+            let (head, tail) = output.split_at_mut(split * nchannels);
+            for output in [head, tail] {
+                if output.is_empty() {
+                    continue;
+                }
+                let frame_end = frame_start + output.len() / nchannels;
+                let (midi_block, rest): (Vec<_>, Vec<_>) = midi_buffer
+                    .into_iter()
+                    .partition(|(frame, _)| *frame < frame_end);
+                midi_buffer = rest;
+                let midi_block = midi_block
+                    .into_iter()
+                    .map(|(frame, event)| (frame - frame_start, event))
+                    .collect();
+                self.process_block(output, nchannels, midi_block)?;
+                frame_start = frame_end;
+            }
+        }
+        Ok(())
+    }
+
+    fn process_block(
+        &mut self,
+        output: &mut [f32],
+        nchannels: usize,
+        midi_buffer: Vec<(usize, Event)>,
+    ) -> Result<()> {
+        let this_start = Instant::now();
 
         //log::debug!("AudioProcess process steady_time {}", self.steady_time);
         let nframes = output.len() / nchannels;
@@ -245,11 +386,36 @@ impl Singer {
         self.compute_play_position(nframes);
 
         {
-            let midi_buffer = {
-                let mut x = self.midi_buffer.lock().unwrap();
-                std::mem::take(&mut *x)
+            let midi_buffer = self.midi_events_timed(midi_buffer);
+            let (audio_input, audio_input_latency) = {
+                let mut audio_in = self.audio_in.lock().unwrap();
+                (audio_in.take(nframes), audio_in.latency)
+            };
+
+            let play_ranges = self.play_ranges();
+            let song_state = self.song_state();
+            let punch_p = song_state.punch_p;
+            let punch_range = song_state.punch_start..song_state.punch_end;
+            let punch_clip = |range: &Range<usize>| {
+                if punch_p {
+                    range.start.max(punch_range.start)..range.end.min(punch_range.end)
+                } else {
+                    range.clone()
+                }
             };
+            // パンチイン/アウトの範囲外は録音しない モニターはする
+            let midi_buffer_rec = midi_buffer
+                .iter()
+                .filter(|(time, _)| !punch_p || punch_range.contains(time))
+                .cloned()
+                .collect::<Vec<_>>();
+            let punch_out_p = punch_p
+                && song_state.play_p
+                && play_ranges
+                    .iter()
+                    .any(|x| x.start < punch_range.end && punch_range.end <= x.end);
 
+            let mut audio_rec_ps = Vec::with_capacity(self.process_track_contexts.len());
             for track_index in 0..self.process_track_contexts.len() {
                 let mut context = self.process_track_contexts[track_index].lock().unwrap();
                 for module_index in 0..context.plugins.len() {
@@ -290,33 +456,136 @@ impl Singer {
                 context.loop_range = song_state.loop_start..song_state.loop_end;
                 context.prepare();
 
+                let track_rec_p = song_state.tracks[track_index].rec_p;
+                let rec_p = song_state.rec_p;
+
+                let input_channels = &self.song.tracks[track_index].input_channels;
+                if song_state.tracks[track_index].monitor_p && !audio_input.is_empty() {
+                    if let Some(plugin_ref) = context.plugins.first_mut() {
+                        let process_data = plugin_ref.process_data_mut();
+                        for channel in 0..process_data.nchannels_in[0] {
+                            if let Some(src) = input_channels
+                                .get(channel % input_channels.len().max(1))
+                                .and_then(|x| audio_input.get(*x))
+                            {
+                                process_data.buffer_in[0][channel][..nframes].copy_from_slice(src);
+                                process_data.constant_mask_in[0] &= !(1 << channel);
+                            }
+                        }
+                    }
+                }
+                audio_rec_ps.push(
+                    rec_p
+                        && track_rec_p
+                        && song_state.play_p
+                        && (!punch_p || punch_range.contains(&self.play_position.start)),
+                );
+                // ループ中の録音は 1 周ごとに take にする
+                let loop_rec_p = track_rec_p && rec_p && song_state.loop_p && song_state.play_p;
+                if track_rec_p
+                    && rec_p
+                    && song_state.rec_replace_p
+                    && song_state.play_p
+                    && !loop_rec_p
+                {
+                    let track = &mut self.song.tracks[track_index];
+                    let cleared_p = play_ranges.iter().fold(false, |cleared_p, range| {
+                        track.notes_clear(punch_clip(range)) || cleared_p
+                    });
+                    if cleared_p {
+                        self.song_state_mut().song_dirty_p = true;
+                    }
+                }
+
+                if loop_rec_p {
+                    let track = &mut self.song.tracks[track_index];
+                    let take_range = punch_clip(&context.loop_range);
+                    if self.play_position.start <= self.play_position.end {
+                        track.events_append_take(&midi_buffer_rec, take_range)?;
+                    } else {
+                        let (before, after): (Vec<_>, Vec<_>) = midi_buffer_rec
+                            .iter()
+                            .cloned()
+                            .partition(|(time, _)| *time >= self.play_position.start);
+                        track.events_append_take(&before, take_range.clone())?;
+                        if track.take_close(context.loop_range.end.saturating_sub(1)) {
+                            self.song_state_mut().song_dirty_p = true;
+                        }
+                        self.song.tracks[track_index].events_append_take(&after, take_range)?;
+                    }
+                } else if self.song.tracks[track_index].take_close(self.play_position.start) {
+                    self.song_state_mut().song_dirty_p = true;
+                }
+
                 if !midi_buffer.is_empty() {
-                    if song_state.tracks[track_index].rec_p {
-                        context.event_list_input.append(&mut midi_buffer.clone());
-                        if song_state.rec_p {
-                            self.song.tracks[track_index]
-                                .events_append(&midi_buffer, &self.play_position)?;
+                    if track_rec_p {
+                        context
+                            .event_list_input
+                            .extend(midi_buffer.iter().map(|(_, event)| event.clone()));
+                        if rec_p && !loop_rec_p {
+                            self.song.tracks[track_index].events_append(&midi_buffer_rec)?;
                         }
                     }
                     self.song_state_mut().song_dirty_p = true;
                 }
 
+                // パンチアウトで押さえたままのノートを止める
+                if punch_out_p
+                    && track_rec_p
+                    && rec_p
+                    && self.song.tracks[track_index]
+                        .notes_release(punch_range.end.saturating_sub(1))
+                {
+                    self.song_state_mut().song_dirty_p = true;
+                }
+
                 if self.all_notef_off_p {
                     context.event_list_input.push(Event::NoteAllOff);
                 }
             }
+
+            for (track_index, audio_rec_p) in audio_rec_ps.into_iter().enumerate() {
+                self.audio_record(
+                    track_index,
+                    &audio_input,
+                    audio_input_latency,
+                    nframes,
+                    audio_rec_p,
+                );
+            }
         }
 
         self.all_notef_off_p = false;
 
         // tracks process
+        self.audio_clip_player.receive();
+        if !self.song_state().play_p {
+            self.audio_clip_player.stop();
+        } else if self.play_position.start > self.play_position.end {
+            let frame = self.frame_at(self.song_state().loop_end, nframes);
+            self.audio_clip_player.stop_at(frame);
+        }
         for track_index in 0..self.song.tracks.len() {
             let mut context = self.process_track_contexts[track_index].lock().unwrap();
-            self.song.tracks[track_index].compute_midi(&mut context);
+            let track = &self.song.tracks[track_index];
+            track.compute_midi(&mut context);
+            for (lane_index, line, time) in context.audio_clip_starts.iter() {
+                if let Some(LaneItem::AudioClip(clip)) =
+                    track.lane_at(*lane_index).and_then(|lane| lane.item(*line))
+                {
+                    let frame = self.frame_at(*time, nframes);
+                    self.audio_clip_player
+                        .start(track_index, *lane_index, clip, frame);
+                }
+            }
         }
         // TODO topological_levels は必要な時だけ行う
         let levels = topological_levels(&self.song)?;
-        for level in levels {
+        if self.level_elapseds.len() < levels.len() {
+            self.level_elapseds.resize(levels.len(), (0.0, 0.0));
+        }
+        for (level_index, level) in levels.into_iter().enumerate() {
+            let level_start = Instant::now();
             level
                 .into_par_iter()
                 .try_for_each(|(track_index, module_index)| {
@@ -329,101 +598,95 @@ impl Singer {
                         &self.process_track_contexts,
                     )
                 })?;
+            let elapsed = level_start.elapsed().as_secs_f64();
+            let (sum, max) = &mut self.level_elapseds[level_index];
+            *sum += elapsed;
+            *max = max.max(elapsed);
+            self.plugins_dead_check();
         }
 
-        // prepare mixing paramss
-        let mut data = self
-            .process_track_contexts
-            .iter_mut()
-            .map(|x| {
-                x.lock()
-                    .unwrap()
-                    .plugins
-                    .last_mut()
-                    .map(|plugin_ref: &mut PluginRef| {
-                        let pd = plugin_ref.process_data();
-                        let constant_mask = pd.constant_mask_out;
-                        (plugin_ref.ptr, constant_mask, pd.nports_in, pd.nports_out)
-                    })
-            })
-            .zip(self.song.tracks.iter().map(|track| {
-                if (track.pan - 0.5).abs() < 0.001 {
-                    (
-                        track.mute,
-                        track.solo,
-                        track.volume,
-                        track.volume,
-                        track.volume,
-                    )
-                } else {
-                    let normalized_pan = (track.pan - 0.5) * 2.0;
-                    let pan_angle = (normalized_pan + 1.0) * PI / 4.0;
-                    (
-                        track.mute,
-                        track.solo,
-                        track.volume * pan_angle.cos(),
-                        track.volume * pan_angle.sin(),
-                        track.volume,
-                    )
+        // tracks volume pan -> main track
+        let bus_layout = self.song.tracks[0].layout;
+        let nchannels_bus = bus_layout.nchannels();
+        let solo_any = self.song.tracks.iter().any(|t| t.solo);
+        for buffer in self.bus_buffer.iter_mut().take(nchannels_bus) {
+            buffer[..nframes].fill(0.0);
+        }
+        for track_index in 1..self.song.tracks.len() {
+            let track = &self.song.tracks[track_index];
+            let context = self.process_track_contexts[track_index].lock().unwrap();
+            if let Some(plugin_ref) = context.plugins.last() {
+                let process_data = plugin_ref.process_data();
+                let gains = track.mix_gains(process_data.nchannels_out[0], bus_layout);
+                mix(
+                    process_data,
+                    &gains,
+                    nchannels_bus,
+                    nframes,
+                    &mut self.track_buffer,
+                );
+            } else {
+                // モジュールがなくてもラウドネスは無音として進める
+                for buffer in self.track_buffer.iter_mut().take(nchannels_bus) {
+                    buffer[..nframes].fill(0.0);
                 }
-            }))
-            .collect::<Vec<_>>();
-
-        let mut buffers = data
-            .iter()
-            .enumerate()
-            .map(|(track_index, x)| {
-                x.0.map(|x| {
-                    let process_data = unsafe { &mut *(x.0) };
-                    if track_index == 0 {
-                        &mut process_data.buffer_in
-                    } else {
-                        &mut process_data.buffer_out
-                    }
-                })
-            })
-            .collect::<Vec<_>>();
-
-        let main_gains = [data[0].1 .2, data[0].1 .3, data[0].1 .4];
+            }
+            // clip はモジュールを通さずトラックの出力に足す ボリュームとパンはかける
+            if self.audio_clip_player.render(
+                track_index,
+                &mut self.clip_buffer,
+                nchannels_bus,
+                nframes,
+            ) {
+                let gains = track.mix_gains(nchannels_bus, bus_layout);
+                mix_add(
+                    &self.clip_buffer,
+                    nchannels_bus,
+                    &gains,
+                    nchannels_bus,
+                    nframes,
+                    &mut self.track_buffer,
+                );
+            }
+            self.song_state_mut().tracks[track_index].peaks =
+                peaks(&self.track_buffer, nchannels_bus, nframes);
+            self.loudness_meters[track_index].process(
+                &self.track_buffer,
+                bus_layout,
+                nframes,
+                self.song.sample_rate,
+            );
+            self.song_state_mut().tracks[track_index]
+                .loudness_set(&self.loudness_meters[track_index]);
+            self.tap_track(track_index, nchannels_bus, nframes);
+            if track.mute || (solo_any && !track.solo) {
+                continue;
+            }
+            for (dst, src) in self.bus_buffer.iter_mut().zip(self.track_buffer.iter()) {
+                for frame in 0..nframes {
+                    dst[frame] += src[frame];
+                }
+            }
+        }
 
-        // tracks pan pan volume
-        for ((buffer_constant_mask, (_mute, _solo, gain_ch0, gain_ch1, gain_ch_restg)), buffer) in
-            data[1..].iter_mut().zip(buffers[1..].iter_mut())
+        // マスタートラックの clip はマスターのモジュールの前に足す
+        if self
+            .audio_clip_player
+            .render(0, &mut self.clip_buffer, nchannels_bus, nframes)
         {
-            if let (Some((process_data, constant_mask, _nports_in, nports_out)), Some(buffer)) =
-                (buffer_constant_mask, buffer)
-            {
-                let process_data = unsafe { &**process_data };
-                for port in 0..*nports_out {
-                    for channel in 0..process_data.nchannels_out[port] {
-                        let constp = (constant_mask[port] & (1 << channel)) != 0;
-                        let gain = if channel == 0 {
-                            *gain_ch0
-                        } else if channel == 1 {
-                            *gain_ch1
-                        } else {
-                            *gain_ch_restg
-                        };
-
-                        if constp {
-                            buffer[port][channel][0] *= gain;
-                        } else {
-                            for frame in 0..nframes {
-                                buffer[port][channel][frame] *= gain;
-                            }
-                        }
-                    }
+            for (dst, src) in self.bus_buffer.iter_mut().zip(self.clip_buffer.iter()) {
+                for frame in 0..nframes {
+                    dst[frame] += src[frame];
                 }
             }
         }
 
-        // tracks mute solo -> main track
-        let mut dummy = ProcessData::new();
-        dummy.prepare();
         let dummy_p = self.song.tracks[0].modules.is_empty();
 
         let main_process_data = if dummy_p {
-            &mut dummy
+            self.dummy_process_data.prepare();
+            let ptr: *mut ProcessData = &mut *self.dummy_process_data;
+            unsafe { &mut *(ptr) }
         } else {
             let ptr = self.process_track_contexts[0]
                 .lock()
@@ -436,43 +699,20 @@ impl Singer {
             process_data
         };
 
-        let solo_any = self.song.tracks.iter().any(|t| t.solo);
-        for frame in 0..nframes {
-            for channel in 0..nchannels {
-                let value = data[1..]
-                    .iter()
-                    .zip(buffers[1..].iter())
-                    .map(|((buffer_constant_mask, (mute, solo, _, _, _)), buffer)| {
-                        if let (Some((process_data, constant_mask, _, _)), Some(buffer)) =
-                            (&buffer_constant_mask, buffer)
-                        {
-                            let process_data = unsafe { &**process_data };
-                            let channel = channel % process_data.nchannels_out[0];
-                            if *mute || (solo_any && !*solo) {
-                                0.0
-                            } else {
-                                let constp = (constant_mask[0] & (1 << channel)) != 0;
-                                if constp {
-                                    buffer[0][channel][0]
-                                } else {
-                                    buffer[0][channel][frame]
-                                }
-                            }
-                        } else {
-                            0.0
-                        }
-                    })
-                    .sum();
-
-                if dummy_p {
-                    main_process_data.buffer_out[0][channel][frame] = value;
-                    main_process_data.constant_mask_out[0] = 0;
-                } else {
-                    main_process_data.buffer_in[0][channel][frame] = value;
-                    main_process_data.constant_mask_in[0] = 0;
-                }
+        for channel in 0..nchannels_bus {
+            let src = &self.bus_buffer[channel][..nframes];
+            if dummy_p {
+                main_process_data.buffer_out[0][channel][..nframes].copy_from_slice(src);
+            } else {
+                main_process_data.buffer_in[0][channel][..nframes].copy_from_slice(src);
             }
         }
+        if dummy_p {
+            main_process_data.nchannels_out[0] = nchannels_bus;
+            main_process_data.constant_mask_out[0] = 0;
+        } else {
+            main_process_data.constant_mask_in[0] = 0;
+        }
 
         // main track process
         if !dummy_p {
@@ -484,38 +724,71 @@ impl Singer {
                     &self.process_track_contexts,
                 )?;
             }
+            self.plugins_dead_check();
         }
 
-        // main track pan volume -> audio device
+        // main track volume pan -> audio device
         let main_track = &self.song.tracks[0];
+        let gains = main_track.mix_gains(main_process_data.nchannels_out[0], bus_layout);
+        mix(
+            main_process_data,
+            &gains,
+            nchannels_bus,
+            nframes,
+            &mut self.track_buffer,
+        );
+        self.tap_track(0, nchannels_bus, nframes);
+        self.tap_module(nframes);
         for frame in 0..nframes {
             for channel in 0..nchannels {
+                // モノラルは全チャンネルに、それ以外はバスにないチャンネルは無音
+                let src = if nchannels_bus == 1 { 0 } else { channel };
                 // いまは solo はいらない
-                let value = if main_track.mute {
+                output[nchannels * frame + channel] = if main_track.mute || src >= nchannels_bus {
                     0.0
                 } else {
-                    let gain = if channel == 0 {
-                        main_gains[0]
-                    } else if channel == 1 {
-                        main_gains[1]
-                    } else {
-                        main_gains[2]
-                    };
-                    let constp = (main_process_data.constant_mask_out[0] & (1 << channel)) != 0;
-                    if constp {
-                        main_process_data.buffer_out[0][channel][0] *= gain;
-                        main_process_data.buffer_out[0][channel][0]
-                    } else {
-                        main_process_data.buffer_out[0][channel][frame] *= gain;
-                        main_process_data.buffer_out[0][channel][frame]
-                    }
+                    self.track_buffer[src][frame]
+                };
+            }
+        }
+
+        self.metronome_process(output, nchannels, nframes);
+
+        let ceiling = self
+            .song_state()
+            .limiter_p
+            .then(|| 10.0f32.powf(self.song_state().limiter_ceiling / 20.0));
+        let bad_p = self
+            .master_safety
+            .process(output, nchannels, self.song.sample_rate, ceiling);
+        let song_state = self.song_state_mut();
+        if bad_p {
+            song_state.bad_samples_p = true;
+        }
+        song_state.limiter_gain_reduction = self.master_safety.gain_reduction;
+
+        // マスターのメーターはメトロノームとリミッターの後の実際に出る音で
+        for channel in 0..nchannels_bus {
+            let buffer = &mut self.track_buffer[channel];
+            for frame in 0..nframes {
+                buffer[frame] = if channel < nchannels {
+                    output[nchannels * frame + channel]
+                } else {
+                    0.0
                 };
-                output[nchannels * frame + channel] = value;
             }
         }
+        self.song_state_mut().tracks[0].peaks = peaks(&self.track_buffer, nchannels_bus, nframes);
+        self.loudness_meters[0].process(
+            &self.track_buffer,
+            bus_layout,
+            nframes,
+            self.song.sample_rate,
+        );
+        self.song_state_mut().tracks[0].loudness_set(&self.loudness_meters[0]);
 
         self.song_state_mut().param_track_index = usize::MAX;
-        self.compute_song_state(main_process_data);
+        self.compute_song_state();
 
         self.steady_time += nframes as i64;
 
@@ -524,7 +797,9 @@ impl Singer {
         self.process_elaspeds.push(elasped);
         self.cpu_usages
             .push(elasped / (nframes as f64 / self.song.sample_rate));
+        self.block_sec_sum += nframes as f64 / self.song.sample_rate;
         if self.process_elasped_last.elapsed() >= Duration::from_secs(1) {
+            self.compute_cpu_usage_detail();
             let song_state = self.song_state_mut();
             song_state.process_elasped_avg = self.process_elaspeds.iter().sum::<f64>()
                 / self.process_elaspeds.len().max(1) as f64;
@@ -538,8 +813,145 @@ impl Singer {
         Ok(())
     }
 
+    /// 入力を WAV に録音して AudioClip として lane に置く
+    fn audio_record(
+        &mut self,
+        track_index: usize,
+        audio_input: &[Vec<f32>],
+        audio_input_latency: f64,
+        nframes: usize,
+        rec_p: bool,
+    ) {
+        let track = &self.song.tracks[track_index];
+        let channels = track
+            .input_channels
+            .iter()
+            .filter(|x| **x < audio_input.len())
+            .cloned()
+            .collect::<Vec<_>>();
+        let rec_p = rec_p && !channels.is_empty();
+        match (track.audio_rec_id, rec_p) {
+            (None, true) => {
+                let id = next_id();
+                let path = self.recording_path(track_index, id);
+                // 入力は 1 ブロック遅れて届く
+                let latency_frames = ((audio_input_latency + self.output_latency)
+                    * self.song.sample_rate)
+                    .round() as usize
+                    + nframes;
+                let _ = self.sender_to_recorder.send(RecorderMessage::Start(
+                    id,
+                    path.clone(),
+                    channels.len() as u16,
+                    self.song.sample_rate as u32,
+                ));
+                // 先頭のレイテンシー分を飛ばして鳴らす
+                let mut clip = AudioClip::new(path.to_string_lossy().to_string());
+                clip.offset = latency_frames as f64 / self.song.sample_rate;
+                let track = &mut self.song.tracks[track_index];
+                track.audio_clip_place(self.play_position.start, clip);
+                track.audio_rec_id = Some(id);
+                self.song_state_mut().song_dirty_p = true;
+            }
+            (Some(id), false) => {
+                let _ = self.sender_to_recorder.send(RecorderMessage::Stop(id));
+                self.song.tracks[track_index].audio_rec_id = None;
+                return;
+            }
+            _ => {}
+        }
+        if let Some(id) = self.song.tracks[track_index].audio_rec_id {
+            let mut samples = Vec::with_capacity(nframes * channels.len());
+            for frame in 0..nframes {
+                for channel in channels.iter() {
+                    samples.push(audio_input[*channel][frame]);
+                }
+            }
+            let _ = self
+                .sender_to_recorder
+                .send(RecorderMessage::Data(id, samples));
+        }
+    }
+
+    fn count_in_start(&mut self) -> bool {
+        let song_state = self.song_state_mut();
+        if !song_state.rec_p || song_state.count_in_bars == 0 {
+            return false;
+        }
+        song_state.count_in_p = true;
+        self.count_in_frame = Some(0);
+        true
+    }
+
+    fn frames_per_beat(&self) -> f64 {
+        let sec_per_delay = 60.0 / (self.song.bpm * self.song.lpb as f64 * 256.0);
+        self.song.delays_per_beat() as f64 * sec_per_delay * self.song.sample_rate
+    }
+
+    /// カウントインが終わるまでのフレーム数
+    fn count_in_frames_left(&self) -> Option<usize> {
+        let frame = self.count_in_frame?;
+        let frames_total = self.frames_per_beat()
+            * (self.song_state().count_in_bars * self.song.beats_per_bar()) as f64;
+        Some((frames_total - frame as f64).ceil().max(0.0) as usize)
+    }
+
+    fn metronome_process(&mut self, output: &mut [f32], nchannels: usize, nframes: usize) {
+        let song_state = self.song_state();
+        let clicks = if let Some(frame) = self.count_in_frame {
+            let clicks = Metronome::clicks_by_frame(
+                frame,
+                self.frames_per_beat(),
+                self.song.beats_per_bar(),
+                nframes,
+            );
+            if self.count_in_frames_left().is_some_and(|x| x <= nframes) {
+                // ブロックはカウントインの終わりで分けてあるので次のブロックの頭から再生する
+                self.count_in_frame = None;
+                let song_state = self.song_state_mut();
+                song_state.count_in_p = false;
+                song_state.play_p = true;
+                self.play_position.end = self.play_position_start_last;
+            } else {
+                self.count_in_frame = Some(frame + nframes);
+            }
+            clicks
+        } else if song_state.play_p
+            && song_state.metronome_p
+            && (!song_state.metronome_rec_only_p || song_state.rec_p)
+        {
+            Metronome::clicks_by_position(
+                &self.play_ranges(),
+                self.song.delays_per_beat(),
+                self.song.beats_per_bar(),
+                nframes,
+            )
+        } else {
+            vec![]
+        };
+        let volume = self.song_state().metronome_volume;
+        self.metronome
+            .render(&clicks, output, nchannels, self.song.sample_rate, volume);
+    }
+
+    /// ループの折り返しで 2 つに分かれる
+    fn play_ranges(&self) -> Vec<Range<usize>> {
+        if self.play_position.start <= self.play_position.end {
+            vec![self.play_position.clone()]
+        } else {
+            let song_state = self.song_state();
+            vec![
+                self.play_position.start..song_state.loop_end,
+                song_state.loop_start..self.play_position.end,
+            ]
+        }
+    }
+
     pub fn play(&mut self) {
-        if self.song_state().play_p {
+        if self.song_state().play_p || self.count_in_frame.is_some() {
+            return;
+        }
+        if self.count_in_start() {
             return;
         }
         self.song_state_mut().play_p = true;
@@ -547,13 +959,16 @@ impl Singer {
     }
 
     pub fn play_line(&mut self, line: usize) {
-        if self.song_state().play_p {
+        if self.song_state().play_p || self.count_in_frame.is_some() {
             return;
         }
-        self.song_state_mut().play_p = true;
         let position = line * 0x100;
-        self.play_position.end = position;
         self.play_position_start_last = position;
+        if self.count_in_start() {
+            return;
+        }
+        self.song_state_mut().play_p = true;
+        self.play_position.end = position;
     }
 
     pub fn plugin_delete(&mut self, module_index: ModuleIndex) -> Result<()> {
@@ -612,6 +1027,105 @@ impl Singer {
         song_state.rec_p = !song_state.rec_p;
     }
 
+    fn rec_replace_toggle(&mut self) {
+        let song_state = self.song_state_mut();
+        song_state.rec_replace_p = !song_state.rec_replace_p;
+    }
+
+    /// 前回のコールバックからの経過時間でコールバック内のフレーム位置にする
+    fn midi_events_frame(
+        &self,
+        midi_buffer: Vec<(Instant, Event)>,
+        nframes: usize,
+    ) -> Vec<(usize, Event)> {
+        midi_buffer
+            .into_iter()
+            .map(|(instant, event)| {
+                let sec = instant
+                    .saturating_duration_since(self.process_start_last)
+                    .as_secs_f64();
+                let frame = ((sec * self.song.sample_rate) as usize).min(nframes.saturating_sub(1));
+                (frame, event)
+            })
+            .collect()
+    }
+
+    /// ブロック内のフレーム位置を曲の時間と delay にする
+    fn midi_events_timed(&self, midi_buffer: Vec<(usize, Event)>) -> Vec<(usize, Event)> {
+        let song_state = self.song_state();
+        let sec_per_delay = 60.0 / (self.song.bpm * self.song.lpb as f64 * 256.0);
+        midi_buffer
+            .into_iter()
+            .map(|(frame, event)| {
+                let delay = (frame as f64 / self.song.sample_rate / sec_per_delay).round() as usize;
+                let time = if song_state.play_p {
+                    let time = self.play_position.start + delay;
+                    if song_state.loop_p && time >= song_state.loop_end {
+                        song_state.loop_start + (time - song_state.loop_end)
+                    } else {
+                        time
+                    }
+                } else {
+                    self.play_position.start
+                };
+                let event = match event {
+                    Event::NoteOn(key, velocity, _) => Event::NoteOn(key, velocity, delay),
+                    Event::NoteOff(key, velocity, _) => Event::NoteOff(key, velocity, delay),
+                    event => event,
+                };
+                (time, event)
+            })
+            .collect()
+    }
+
+    pub fn audio_clips_load(&mut self) {
+        let paths = self
+            .song
+            .tracks
+            .iter()
+            .flat_map(|track| {
+                track
+                    .lanes
+                    .iter()
+                    .chain(track.takes.iter().flat_map(|x| x.lanes.iter()))
+            })
+            .flat_map(|lane| lane.items.values())
+            .filter_map(|item| match item {
+                LaneItem::AudioClip(clip) => Some(clip.path.as_str()),
+                _ => None,
+            });
+        self.audio_clip_player.load(paths, self.song.sample_rate);
+    }
+
+    /// play_position 上の時刻がこのブロックの何フレーム目か
+    fn frame_at(&self, time: usize, nframes: usize) -> usize {
+        let delays = if time >= self.play_position.start {
+            time - self.play_position.start
+        } else {
+            let song_state = self.song_state();
+            song_state.loop_end.saturating_sub(self.play_position.start)
+                + time.saturating_sub(song_state.loop_start)
+        };
+        let sec_per_delay = 60.0 / (self.song.bpm * self.song.lpb as f64 * 256.0);
+        ((delays as f64 * sec_per_delay * self.song.sample_rate).round() as usize)
+            .min(nframes.saturating_sub(1))
+    }
+
+    /// 同じ秒に複数のトラックで録音を始めても重ならないように録音の id を付ける
+    fn recording_path(&self, track_index: usize, id: usize) -> PathBuf {
+        self.recording_dir.join(format!(
+            "{}_{}_{}.wav",
+            self.song.tracks[track_index].name,
+            Local::now().format("%Y%m%d_%H%M%S"),
+            id
+        ))
+    }
+
+    fn song_file_set(&mut self, song_file: &str) {
+        self.song_state_mut().song_file_set(song_file);
+        self.recording_dir = recording_dir(Some(song_file));
+    }
+
     pub fn song_close(&mut self) -> Result<()> {
         for track_index in (0..self.song.tracks.len()).rev() {
             for module_index in (0..self.song.tracks[track_index].modules.len()).rev() {
@@ -621,6 +1135,7 @@ impl Singer {
         self.song = Song::new();
         self.process_track_contexts.clear();
         self.shmems.clear();
+        self.loudness_meters.clear();
         self.track_add();
 
         Ok(())
@@ -631,12 +1146,16 @@ impl Singer {
         let reader = BufReader::new(file);
         let song = serde_json::from_reader(reader)?;
 
+        // サンプルレートはデバイスに合わせる
+        let sample_rate = self.song.sample_rate;
         self.song = song;
+        self.song.sample_rate = sample_rate;
 
         for track_index in 0..self.song.tracks.len() {
             self.process_track_contexts
                 .push(Arc::new(Mutex::new(ProcessTrackContext::default())));
             self.shmems.push(vec![]);
+            self.loudness_meters.push(LoudnessMeter::new());
 
             for module_index in 0..self.song.tracks[track_index].modules.len() {
                 let id = self.plugin_load(track_index)?;
@@ -645,7 +1164,7 @@ impl Singer {
             }
         }
 
-        self.song_state_mut().song_file_set(&song_file);
+        self.song_file_set(&song_file);
         Ok(())
     }
 
@@ -658,7 +1177,52 @@ impl Singer {
         unsafe { &mut *(self.song_state_ptr) }
     }
 
+    fn tap_state(&self) -> &TapState {
+        unsafe { &*(self.tap_state_ptr) }
+    }
+
+    fn tap_track(&self, track_index: usize, nchannels: usize, nframes: usize) {
+        if self.tap_source == Some(TapSource::Track(track_index)) {
+            self.tap_state().write(
+                nchannels,
+                nframes,
+                self.song.sample_rate,
+                |channel, frame| self.track_buffer[channel][frame],
+            );
+        }
+    }
+
+    fn tap_module(&self, nframes: usize) {
+        let Some(TapSource::Module((track_index, module_index), port)) = self.tap_source else {
+            return;
+        };
+        let Some(context) = self.process_track_contexts.get(track_index) else {
+            return;
+        };
+        let context = context.lock().unwrap();
+        let Some(plugin_ref) = context.plugins.get(module_index) else {
+            return;
+        };
+        let process_data = plugin_ref.process_data();
+        if port >= process_data.nports_out.min(MAX_PORTS) {
+            return;
+        }
+        let constp = |channel: usize| (process_data.constant_mask_out[port] & (1 << channel)) != 0;
+        self.tap_state().write(
+            process_data.nchannels_out[port],
+            nframes,
+            self.song.sample_rate,
+            |channel, frame| {
+                process_data.buffer_out[port][channel][if constp(channel) { 0 } else { frame }]
+            },
+        );
+    }
+
     pub fn stop(&mut self) {
+        if self.count_in_frame.take().is_some() {
+            self.song_state_mut().count_in_p = false;
+            return;
+        }
         if !self.song_state().play_p {
             return;
         }
@@ -672,7 +1236,7 @@ impl Singer {
         });
     }
 
-    pub fn start_listener_midi(singer: Arc<Mutex<Self>>, receiver: Receiver<Event>) {
+    pub fn start_listener_midi(singer: Arc<Mutex<Self>>, receiver: Receiver<(Instant, Event)>) {
         let singer = singer.lock().unwrap();
         let midi_buffer = singer.midi_buffer.clone();
         tokio::spawn(async move {
@@ -680,28 +1244,24 @@ impl Singer {
         });
     }
 
-    fn compute_song_state(&mut self, main_process_data: &ProcessData) {
+    fn compute_song_state(&mut self) {
         let song_state = self.song_state_mut();
 
-        for track_index in 0..self.process_track_contexts.len() {
-            let context = self.process_track_contexts[track_index].lock().unwrap();
-            if let Some(plugin_ref) = context.plugins.last() {
-                let process_data = plugin_ref.process_data();
-                let nchannels = process_data.nchannels_out[0];
-                for channel in 0..2 {
-                    song_state.tracks[track_index].peaks[channel] =
-                        process_data.peak(0, channel % nchannels);
+        for (track_index, context) in self.process_track_contexts.iter().enumerate() {
+            for (module_index, plugin) in context.lock().unwrap().plugins.iter_mut().enumerate() {
+                let process_data = plugin.process_data_mut();
+                // 後ろのモジュールにも伝わるので最初のものだけ
+                let track_state = &mut song_state.tracks[track_index];
+                if track_state.bad_module_index == usize::MAX && !process_data.finite_p() {
+                    track_state.bad_module_index = module_index;
                 }
-            } else if track_index == 0 {
-                let process_data = main_process_data;
-                let nchannels = process_data.nchannels_out[0];
-                for channel in 0..process_data.nchannels_out[0] {
-                    song_state.tracks[track_index].peaks[channel] =
-                        process_data.peak(0, channel % nchannels);
-                }
-            } else {
-                for channel in 0..2 {
-                    song_state.tracks[track_index].peaks[channel] = DB_MIN;
+                song_state.events_dropped_input += process_data.nevents_input_dropped;
+                song_state.events_dropped_output += process_data.nevents_output_dropped;
+                process_data.nevents_input_dropped = 0;
+                process_data.nevents_output_dropped = 0;
+                if process_data.note_names_changed_p != 0 {
+                    process_data.note_names_changed_p = 0;
+                    song_state.note_names_version += 1;
                 }
             }
         }
@@ -728,11 +1288,127 @@ impl Singer {
         }
     }
 
+    fn plugin_ref_mut(
+        &self,
+        id: usize,
+        f: impl FnOnce(&mut PluginRef) -> Result<()>,
+    ) -> Result<()> {
+        for context in self.process_track_contexts.iter() {
+            if let Some(plugin_ref) = context
+                .lock()
+                .unwrap()
+                .plugins
+                .iter_mut()
+                .find(|plugin_ref| plugin_ref.id == id)
+            {
+                return f(plugin_ref);
+            }
+        }
+        Ok(())
+    }
+
+    /// 1 つでも応答がなければホストごと立ち上げ直すので、ほかも待たずに無音にする
+    /// 専用のプロセスで動いているものはそのモジュールだけ立ち上げ直す
+    fn plugins_dead_check(&self) {
+        if self.song_state().plugin_host_dead_p {
+            return;
+        }
+        let dead_plugin = |isolated_p: bool| {
+            self.process_track_contexts.iter().find_map(|context| {
+                context
+                    .lock()
+                    .unwrap()
+                    .plugins
+                    .iter()
+                    .find(|plugin_ref| plugin_ref.dead_p && plugin_ref.isolated_p == isolated_p)
+                    .map(|plugin_ref| plugin_ref.id)
+            })
+        };
+        let Some(dead_id) = dead_plugin(false) else {
+            if self.song_state().isolated_dead_module_id == usize::MAX {
+                if let Some(dead_id) = dead_plugin(true) {
+                    self.song_state_mut().isolated_dead_module_id = dead_id;
+                }
+            }
+            return;
+        };
+        // 専用のプロセスのものは巻き込まない
+        for context in self.process_track_contexts.iter() {
+            for plugin_ref in context.lock().unwrap().plugins.iter_mut() {
+                if !plugin_ref.isolated_p {
+                    plugin_ref.dead_p = true;
+                }
+            }
+        }
+        let song_state = self.song_state_mut();
+        song_state.plugin_host_dead_p = true;
+        song_state.dead_module_id = dead_id;
+    }
+
+    /// 共有のプロセスのものだけ 専用のプロセスはそのまま動いている
+    fn plugin_host_reset(&mut self) -> Result<()> {
+        for context in self.process_track_contexts.iter() {
+            for plugin_ref in context.lock().unwrap().plugins.iter_mut() {
+                if !plugin_ref.isolated_p {
+                    plugin_ref.reset()?;
+                }
+            }
+        }
+        let song_state = self.song_state_mut();
+        song_state.plugin_host_dead_p = false;
+        song_state.dead_module_id = usize::MAX;
+        Ok(())
+    }
+
+    /// 1 つのモジュールだけ読み込み直す
+    fn plugin_reset(&mut self, id: usize) -> Result<()> {
+        self.plugin_ref_mut(id, |plugin_ref| plugin_ref.reset())?;
+        let song_state = self.song_state_mut();
+        if song_state.isolated_dead_module_id == id {
+            song_state.isolated_dead_module_id = usize::MAX;
+        }
+        Ok(())
+    }
+
+    /// モジュールごと、level ごとの処理時間を SongState に出して集計をやり直す
+    fn compute_cpu_usage_detail(&mut self) {
+        let block_sec_sum = self.block_sec_sum.max(f64::EPSILON);
+        let song_state = self.song_state_mut();
+        for (track_index, context) in self.process_track_contexts.iter().enumerate() {
+            let mut context = context.lock().unwrap();
+            let track_state = &mut song_state.tracks[track_index];
+            track_state.cpu_usage = 0.0;
+            track_state.module_cpu_usages = [0.0; MAX_MODULES];
+            track_state.module_elapsed_maxs = [0.0; MAX_MODULES];
+            for (module_index, plugin_ref) in context.plugins.iter_mut().enumerate() {
+                let cpu_usage = plugin_ref.elapsed_sum / block_sec_sum;
+                track_state.cpu_usage += cpu_usage;
+                if module_index < MAX_MODULES {
+                    track_state.module_cpu_usages[module_index] = cpu_usage;
+                    track_state.module_elapsed_maxs[module_index] = plugin_ref.elapsed_max;
+                }
+                plugin_ref.elapsed_sum = 0.0;
+                plugin_ref.elapsed_max = 0.0;
+            }
+        }
+
+        song_state.nlevels = self.level_elapseds.len().min(MAX_LEVELS);
+        song_state.level_cpu_usages = [0.0; MAX_LEVELS];
+        song_state.level_elapsed_maxs = [0.0; MAX_LEVELS];
+        for (level_index, (sum, max)) in self.level_elapseds.iter().take(MAX_LEVELS).enumerate() {
+            song_state.level_cpu_usages[level_index] = sum / block_sec_sum;
+            song_state.level_elapsed_maxs[level_index] = *max;
+        }
+        self.level_elapseds.clear();
+        self.block_sec_sum = 0.0;
+    }
+
     fn track_add(&mut self) {
         self.song.track_add();
         self.process_track_contexts
             .push(Arc::new(Mutex::new(ProcessTrackContext::default())));
         self.shmems.push(vec![]);
+        self.loudness_meters.push(LoudnessMeter::new());
     }
 
     #[allow(dead_code)]
@@ -745,18 +1421,23 @@ impl Singer {
             self.plugin_delete((track_index, module_index))?;
         }
         self.song.track_delete(track_index);
+        self.audio_clip_player.track_delete(track_index);
         self.process_track_contexts.remove(track_index);
         self.shmems.remove(track_index);
+        self.loudness_meters.remove(track_index);
         Ok(())
     }
 
     fn track_insert(&mut self, track_index: usize, track: Track) -> Result<()> {
         self.song.track_insert(track_index, track);
+        self.audio_clip_player.track_insert(track_index);
         self.process_track_contexts.insert(
             track_index,
             Arc::new(Mutex::new(ProcessTrackContext::default())),
         );
         self.shmems.insert(track_index, vec![]);
+        self.loudness_meters
+            .insert(track_index, LoudnessMeter::new());
         for module_index in 0..self.song.tracks[track_index].modules.len() {
             let id = self.plugin_load(track_index)?;
             let module = &mut self.song.tracks[track_index].modules[module_index];
@@ -775,6 +1456,8 @@ impl Singer {
         self.process_track_contexts.insert(track_index_new, context);
         let shmem = self.shmems.remove(track_index);
         self.shmems.insert(track_index_new, shmem);
+        let loudness_meter = self.loudness_meters.remove(track_index);
+        self.loudness_meters.insert(track_index_new, loudness_meter);
 
         self.song.track_move(track_index, delta);
 
@@ -836,12 +1519,88 @@ fn run_main_to_audio(
             singer.song_state_mut().loop_end = range.end;
             Ok(AudioToMain::Ok)
         }
+        MainToAudio::CountInBars(bars) => {
+            singer.song_state_mut().count_in_bars = bars;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::MetronomeToggle => {
+            let song_state = singer.song_state_mut();
+            song_state.metronome_p = !song_state.metronome_p;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::MetronomeRecOnlyToggle => {
+            let song_state = singer.song_state_mut();
+            song_state.metronome_rec_only_p = !song_state.metronome_rec_only_p;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::MetronomeVolume(volume) => {
+            singer.song_state_mut().metronome_volume = volume;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::BadSamplesClear => {
+            let song_state = singer.song_state_mut();
+            song_state.bad_samples_p = false;
+            for track in song_state.tracks.iter_mut() {
+                track.bad_module_index = usize::MAX;
+            }
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::TapSource(tap_source) => {
+            singer.tap_source = tap_source;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::LoudnessReset => {
+            for loudness_meter in singer.loudness_meters.iter_mut() {
+                loudness_meter.reset();
+            }
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::LimiterToggle => {
+            let song_state = singer.song_state_mut();
+            song_state.limiter_p = !song_state.limiter_p;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::LimiterCeiling(ceiling) => {
+            singer.song_state_mut().limiter_ceiling = ceiling;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::Punch => {
+            singer.song_state_mut().punch_p = !singer.song_state().punch_p;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::PunchRange(range) => {
+            singer.song_state_mut().punch_start = range.start;
+            singer.song_state_mut().punch_end = range.end;
+            Ok(AudioToMain::Ok)
+        }
         MainToAudio::Song => Ok(AudioToMain::Song(singer.song.clone())),
         MainToAudio::LaneItem(items) => {
             let undo = singer.lane_items_set(items)?;
+            singer.audio_clips_load();
             undo_history.add(undo, redo);
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::ModuleIsolated(module_index, isolated_p) => {
+            if let Some(module) = singer.song.module_at_mut(module_index) {
+                module.isolated_p = isolated_p;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::PluginHostRestart => {
+            singer.plugin_host_reset()?;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::PluginIsolated(id, isolated_p) => {
+            singer.plugin_ref_mut(id, |plugin_ref| {
+                plugin_ref.isolated_p = isolated_p;
+                Ok(())
+            })?;
+            Ok(AudioToMain::Ok)
+        }
+        MainToAudio::PluginReset(id) => {
+            singer.plugin_reset(id)?;
+            Ok(AudioToMain::Ok)
+        }
         MainToAudio::PluginLatency(id, latency) => {
             singer.plugin_latency_set(id, latency)?;
             Ok(AudioToMain::Ok)
@@ -883,6 +1642,10 @@ fn run_main_to_audio(
             singer.rec_toggle();
             Ok(AudioToMain::Ok)
         }
+        MainToAudio::RecReplaceToggle => {
+            singer.rec_replace_toggle();
+            Ok(AudioToMain::Ok)
+        }
         MainToAudio::Redo => {
             if let Some(redo) = undo_history.redo() {
                 run_main_to_audio(singer, redo, undo_history)?;
@@ -897,14 +1660,18 @@ fn run_main_to_audio(
                 .push(Event::NoteOn(key, velocity, delay));
             Ok(AudioToMain::Ok)
         }
-        MainToAudio::NoteOff(track_index, key, _channel, _velocity, delay) => {
+        MainToAudio::NoteOff(track_index, key, _channel, velocity, delay) => {
             singer.process_track_contexts[track_index]
                 .lock()
                 .unwrap()
                 .event_list_input
-                .push(Event::NoteOff(key, delay));
+                .push(Event::NoteOff(key, velocity, delay));
             Ok(AudioToMain::Ok)
         }
+        MainToAudio::TimeSignature(numerator, denominator) => {
+            singer.song.time_signature = (numerator, denominator);
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
         MainToAudio::TrackAdd => {
             singer.track_add();
             Ok(AudioToMain::Song(singer.song.clone()))
@@ -921,6 +1688,28 @@ fn run_main_to_audio(
             singer.track_move(track_index, delta)?;
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::TrackInputChannels(track_index, input_channels) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.input_channels = input_channels;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackDualPan(track_index, dual_pan_p) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.dual_pan_p = dual_pan_p;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackLayout(track_index, layout) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.layout = layout;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackMonitor(track_index, monitor_p) => {
+            singer.song_state_mut().tracks[track_index].monitor_p = monitor_p;
+            Ok(AudioToMain::Ok)
+        }
         MainToAudio::TrackMute(track_index, mute) => {
             if let Some(track) = singer.song.tracks.get_mut(track_index) {
                 track.mute = mute;
@@ -939,6 +1728,24 @@ fn run_main_to_audio(
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::TrackPanLaw(track_index, pan_law) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.pan_law = pan_law;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackPanRear(track_index, pan_rear) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.pan_rear = pan_rear;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TrackPanRight(track_index, pan_right) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.pan_right = pan_right;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
         MainToAudio::TrackRecOn(track_index) => {
             singer.song_state_mut().tracks[track_index].rec_p = true;
             Ok(AudioToMain::Ok)
@@ -959,6 +1766,12 @@ fn run_main_to_audio(
             }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
+        MainToAudio::TrackWidth(track_index, width) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.width = width;
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
         MainToAudio::Undo => {
             if let Some(undo) = undo_history.undo() {
                 run_main_to_audio(singer, undo, undo_history)?;
@@ -972,22 +1785,152 @@ fn run_main_to_audio(
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::SongFile(song_file) => {
-            singer.song_state_mut().song_file_set(&song_file);
+            singer.song_file_set(&song_file);
             Ok(AudioToMain::Ok)
         }
         MainToAudio::SongOpen(song_file) => {
             singer.song_close()?;
             singer.song_open(song_file)?;
+            singer.audio_clips_load();
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TakeActivate(track_index, take_index, lines) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                undo_history.add(
+                    MainToAudio::TakesSet(track_index, track.takes.clone()),
+                    redo,
+                );
+                track.take_activate(take_index, lines);
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TakeDelete(track_index, take_index) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                undo_history.add(
+                    MainToAudio::TakesSet(track_index, track.takes.clone()),
+                    redo,
+                );
+                track.take_delete(take_index);
+                singer.process_track_contexts[track_index]
+                    .lock()
+                    .unwrap()
+                    .notes_release_from(track.lanes.len());
+            }
+            Ok(AudioToMain::Song(singer.song.clone()))
+        }
+        MainToAudio::TakesSet(track_index, takes) => {
+            if let Some(track) = singer.song.tracks.get_mut(track_index) {
+                track.takes_set(takes);
+                singer.process_track_contexts[track_index]
+                    .lock()
+                    .unwrap()
+                    .notes_release_from(track.lanes.len());
+            }
             Ok(AudioToMain::Song(singer.song.clone()))
         }
         MainToAudio::Quit => Ok(AudioToMain::Ok),
     }
 }
 
-async fn midi_loop(midi_buffer: Arc<Mutex<Vec<Event>>>, receiver: Receiver<Event>) -> Result<()> {
+async fn midi_loop(
+    midi_buffer: Arc<Mutex<Vec<(Instant, Event)>>>,
+    receiver: Receiver<(Instant, Event)>,
+) -> Result<()> {
     while let Ok(event) = receiver.recv() {
         let mut midi_buffer = midi_buffer.lock().unwrap();
         midi_buffer.push(event);
     }
     Ok(())
 }
+
+/// 曲のファイルの隣の audio 保存前なら user/song/audio
+fn recording_dir(song_file: Option<&str>) -> PathBuf {
+    song_file
+        .and_then(|x| Path::new(x).parent().map(|x| x.to_path_buf()))
+        .or_else(|| {
+            current_exe()
+                .ok()
+                .and_then(|x| x.parent().map(|x| x.join("user").join("song")))
+        })
+        .unwrap_or_default()
+        .join("audio")
+}
+
+/// process_data の出力を gains でバスのチャンネルに振り分けて out に入れる
+fn mix(
+    process_data: &ProcessData,
+    gains: &[[f32; MAX_CHANNELS]; MAX_CHANNELS],
+    nchannels_dst: usize,
+    nframes: usize,
+    out: &mut [Vec<f32>],
+) {
+    for buffer in out.iter_mut().take(nchannels_dst) {
+        buffer[..nframes].fill(0.0);
+    }
+    for src in 0..process_data.nchannels_out[0].min(MAX_CHANNELS) {
+        let constp = (process_data.constant_mask_out[0] & (1 << src)) != 0;
+        let buffer = &process_data.buffer_out[0][src];
+        for dst in 0..nchannels_dst {
+            let gain = gains[src][dst];
+            if gain == 0.0 {
+                continue;
+            }
+            // NaN/Inf は 1 トラックだけで止めてバスに混ぜない
+            if constp {
+                let value = finite_or_zero(buffer[0]) * gain;
+                for x in out[dst][..nframes].iter_mut() {
+                    *x += value;
+                }
+            } else {
+                for frame in 0..nframes {
+                    out[dst][frame] += finite_or_zero(buffer[frame]) * gain;
+                }
+            }
+        }
+    }
+}
+
+/// モジュールを通らない音を out に足す
+fn mix_add(
+    src: &[Vec<f32>],
+    nchannels_src: usize,
+    gains: &[[f32; MAX_CHANNELS]; MAX_CHANNELS],
+    nchannels_dst: usize,
+    nframes: usize,
+    out: &mut [Vec<f32>],
+) {
+    for (src_index, buffer) in src.iter().enumerate().take(nchannels_src.min(MAX_CHANNELS)) {
+        for dst in 0..nchannels_dst {
+            let gain = gains[src_index][dst];
+            if gain == 0.0 {
+                continue;
+            }
+            for frame in 0..nframes {
+                out[dst][frame] += buffer[frame] * gain;
+            }
+        }
+    }
+}
+
+fn finite_or_zero(x: f32) -> f32 {
+    if x.is_finite() {
+        x
+    } else {
+        0.0
+    }
+}
+
+fn peaks(buffer: &[Vec<f32>], nchannels: usize, nframes: usize) -> [f32; MAX_CHANNELS] {
+    let mut peaks = [DB_MIN; MAX_CHANNELS];
+    for channel in 0..nchannels {
+        let value = buffer[channel][..nframes]
+            .iter()
+            .fold(0.0, |acc: f32, x| acc.max(x.abs()));
+        peaks[channel] = linear_to_db(value);
+    }
+    // モノラルでもメーターは左右に出す
+    if nchannels == 1 {
+        peaks[1] = peaks[0];
+    }
+    peaks
+}
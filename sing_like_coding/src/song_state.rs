@@ -1,10 +1,13 @@
 use clap_sys::id::clap_id;
 use common::process_data::MAX_CHANNELS;
 
-use crate::view::stereo_peak_meter::DB_MIN;
+use crate::{loudness_meter::LoudnessMeter, view::stereo_peak_meter::DB_MIN};
 
 pub const MAX_PATH_LEN: usize = 1024;
 pub const MAX_TRACKS: usize = 0xff;
+/// これより後ろのモジュールと level は計測だけして表示しない
+pub const MAX_MODULES: usize = 0x20;
+pub const MAX_LEVELS: usize = 0x20;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -15,14 +18,47 @@ pub struct SongState {
     pub loop_p: bool,
     pub loop_start: usize,
     pub loop_end: usize,
+    pub punch_p: bool,
+    pub punch_start: usize,
+    pub punch_end: usize,
     pub process_elasped_avg: f64,
     pub cpu_usage: f64,
+    /// topological_levels ごとの処理時間 (ブロックの長さに対する割合, 最大の秒数)
+    pub level_cpu_usages: [f64; MAX_LEVELS],
+    pub level_elapsed_maxs: [f64; MAX_LEVELS],
+    pub nlevels: usize,
     pub tracks: [TrackState; MAX_TRACKS],
     pub param_track_index: usize,
     pub param_module_index: usize,
     pub param_id: clap_id,
     pub rec_p: bool,
+    pub rec_replace_p: bool,
     pub song_dirty_p: bool,
+    pub metronome_p: bool,
+    pub metronome_rec_only_p: bool,
+    pub metronome_volume: f32,
+    /// 録音待機中に Play したときのカウントインの小節数
+    pub count_in_bars: usize,
+    pub count_in_p: bool,
+    pub audio_input_nchannels: usize,
+    /// MAX_EVENTS を超えて捨てたイベントの累計
+    pub events_dropped_input: usize,
+    pub events_dropped_output: usize,
+    /// マスターの最後のトゥルーピークリミッター
+    pub limiter_p: bool,
+    /// dBTP
+    pub limiter_ceiling: f32,
+    pub limiter_gain_reduction: f32,
+    /// マスターの出力に NaN/Inf があった 消すまでそのまま
+    pub bad_samples_p: bool,
+    /// プラグインのプロセスが応答しなくなった 立ち上げ直すまでそのまま
+    pub plugin_host_dead_p: bool,
+    /// 最初に応答しなくなったモジュール なければ usize::MAX
+    pub dead_module_id: usize,
+    /// 専用のプロセスで動いていて応答しなくなったモジュール なければ usize::MAX
+    pub isolated_dead_module_id: usize,
+    /// どれかのプラグインの note-name が変わるたびに増やす
+    pub note_names_version: usize,
 }
 
 impl SongState {
@@ -33,17 +69,51 @@ impl SongState {
         self.loop_p = true;
         self.loop_start = 0;
         self.loop_end = 0x100 * 0x20;
+        self.punch_p = false;
+        self.punch_start = 0;
+        self.punch_end = 0;
         self.process_elasped_avg = 0.0;
         self.cpu_usage = 0.0;
+        self.level_cpu_usages = [0.0; MAX_LEVELS];
+        self.level_elapsed_maxs = [0.0; MAX_LEVELS];
+        self.nlevels = 0;
         for track in self.tracks.iter_mut() {
             for peak in track.peaks.iter_mut() {
                 *peak = DB_MIN;
             }
+            track.rms = [DB_MIN; MAX_CHANNELS];
+            track.true_peaks = [DB_MIN; MAX_CHANNELS];
+            track.true_peak_max = DB_MIN;
+            track.lufs_momentary = DB_MIN;
+            track.lufs_short_term = DB_MIN;
+            track.lufs_integrated = DB_MIN;
+            track.cpu_usage = 0.0;
+            track.module_cpu_usages = [0.0; MAX_MODULES];
+            track.module_elapsed_maxs = [0.0; MAX_MODULES];
             track.rec_p = false;
+            track.monitor_p = false;
+            track.bad_module_index = usize::MAX;
         }
         self.param_track_index = usize::MAX;
         self.rec_p = false;
+        self.rec_replace_p = false;
         self.song_dirty_p = false;
+        self.metronome_p = false;
+        self.metronome_rec_only_p = false;
+        self.metronome_volume = 0.5;
+        self.count_in_bars = 1;
+        self.count_in_p = false;
+        self.audio_input_nchannels = 0;
+        self.events_dropped_input = 0;
+        self.events_dropped_output = 0;
+        self.limiter_p = false;
+        self.limiter_ceiling = -1.0;
+        self.limiter_gain_reduction = 0.0;
+        self.bad_samples_p = false;
+        self.plugin_host_dead_p = false;
+        self.dead_module_id = usize::MAX;
+        self.isolated_dead_module_id = usize::MAX;
+        self.note_names_version = 0;
     }
 
     pub fn song_file_get(&self) -> Option<String> {
@@ -70,5 +140,32 @@ impl SongState {
 #[derive(Debug)]
 pub struct TrackState {
     pub peaks: [f32; MAX_CHANNELS],
+    pub rms: [f32; MAX_CHANNELS],
+    /// dBTP
+    pub true_peaks: [f32; MAX_CHANNELS],
+    pub true_peak_max: f32,
+    pub lufs_momentary: f32,
+    pub lufs_short_term: f32,
+    pub lufs_integrated: f32,
+    /// モジュールの合計
+    pub cpu_usage: f64,
+    /// process の往復にかかった時間のブロックの長さに対する割合の平均
+    pub module_cpu_usages: [f64; MAX_MODULES],
+    /// 秒
+    pub module_elapsed_maxs: [f64; MAX_MODULES],
     pub rec_p: bool,
+    pub monitor_p: bool,
+    /// 最初に NaN/Inf を出したモジュール なければ usize::MAX
+    pub bad_module_index: usize,
+}
+
+impl TrackState {
+    pub fn loudness_set(&mut self, loudness_meter: &LoudnessMeter) {
+        self.rms = loudness_meter.rms;
+        self.true_peaks = loudness_meter.true_peaks;
+        self.true_peak_max = loudness_meter.true_peak_max;
+        self.lufs_momentary = loudness_meter.momentary;
+        self.lufs_short_term = loudness_meter.short_term;
+        self.lufs_integrated = loudness_meter.integrated;
+    }
 }
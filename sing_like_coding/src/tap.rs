@@ -0,0 +1,93 @@
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use common::module::ModuleIndex;
+
+/// 2 のべき乗
+pub const TAP_LEN: usize = 1 << 14;
+/// 3 チャンネル目以降は見ない
+pub const TAP_CHANNELS: usize = 2;
+
+/// アナライザーで見る音
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TapSource {
+    /// ボリューム、パンのあと
+    Track(usize),
+    /// モジュールの出力ポート
+    Module(ModuleIndex, usize),
+}
+
+/// オーディオスレッドが書いて GUI が読むリングバッファ
+/// 書くのはオーディオスレッドだけでロックはしない
+#[repr(C)]
+pub struct TapState {
+    /// 書き始めたフレームの累計
+    position_writing: AtomicUsize,
+    /// 書き終えたフレームの累計
+    position: AtomicUsize,
+    nchannels: AtomicUsize,
+    sample_rate: AtomicU64,
+    buffer: [[AtomicU32; TAP_LEN]; TAP_CHANNELS],
+}
+
+impl TapState {
+    pub fn init(&self) {
+        self.position_writing.store(0, Ordering::Relaxed);
+        self.position.store(0, Ordering::Release);
+        self.nchannels.store(0, Ordering::Relaxed);
+        self.sample_rate.store(0, Ordering::Relaxed);
+    }
+
+    /// sample(channel, frame)
+    pub fn write(
+        &self,
+        nchannels: usize,
+        nframes: usize,
+        sample_rate: f64,
+        sample: impl Fn(usize, usize) -> f32,
+    ) {
+        let nchannels = nchannels.min(TAP_CHANNELS);
+        let position = self.position.load(Ordering::Relaxed);
+        self.position_writing
+            .store(position + nframes, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.nchannels.store(nchannels, Ordering::Relaxed);
+        self.sample_rate
+            .store(sample_rate.to_bits(), Ordering::Relaxed);
+        for frame in 0..nframes {
+            let index = (position + frame) & (TAP_LEN - 1);
+            for channel in 0..nchannels {
+                self.buffer[channel][index]
+                    .store(sample(channel, frame).to_bits(), Ordering::Relaxed);
+            }
+        }
+        self.position.store(position + nframes, Ordering::Release);
+    }
+
+    /// 直近の nframes を [channel][frame] で 書いている途中と重なったら None
+    pub fn read(&self, nframes: usize) -> Option<(Vec<Vec<f32>>, f64)> {
+        let nframes = nframes.min(TAP_LEN / 2);
+        let end = self.position.load(Ordering::Acquire);
+        if end < nframes {
+            return None;
+        }
+        let nchannels = self.nchannels.load(Ordering::Relaxed);
+        let sample_rate = f64::from_bits(self.sample_rate.load(Ordering::Relaxed));
+        let buffer = (0..nchannels)
+            .map(|channel| {
+                (end - nframes..end)
+                    .map(|position| {
+                        f32::from_bits(
+                            self.buffer[channel][position & (TAP_LEN - 1)].load(Ordering::Relaxed),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        fence(Ordering::Acquire);
+        let writing = self.position_writing.load(Ordering::Relaxed);
+        if writing.saturating_sub(end) + nframes > TAP_LEN {
+            return None;
+        }
+        Some((buffer, sample_rate))
+    }
+}
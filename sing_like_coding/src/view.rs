@@ -1,11 +1,16 @@
+mod analyzer_window;
+pub mod audio_device_view;
 mod command_view;
 mod db_slider;
 mod eval_window;
 mod knob;
 pub mod main_view;
 pub mod param_select_view;
+pub mod plugin_scan_view;
 pub mod plugin_select_view;
+pub mod preset_select_view;
 pub mod root_view;
+pub mod sampler_view;
 pub mod select_view;
 mod shortcut_key;
 pub mod sidechain_select_view;
@@ -0,0 +1,241 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use common::dsp::{fft, linear_to_db};
+use eframe::egui::{
+    pos2, vec2, Align2, Color32, Context, DragValue, FontId, Painter, Rect, Sense, Shape, Slider,
+    Stroke, Window,
+};
+
+use crate::{app_state::AppState, tap::TapSource};
+
+const FFT_LEN: usize = 4096;
+const FREQ_MIN: f32 = 20.0;
+const FREQ_MAX: f32 = 20000.0;
+const SPECTRUM_DB_MIN: f32 = -96.0;
+const SPECTRUM_DB_MAX: f32 = 6.0;
+const CHANNEL_COLORS: [Color32; 2] = [Color32::LIGHT_GREEN, Color32::LIGHT_BLUE];
+
+/// TapState のリングバッファを FFT スペクトラムかオシロスコープで表示する
+pub struct AnalyzerWindow {
+    scope_p: bool,
+    /// 前のフレームのスペクトラムをどれだけ残すか
+    smoothing: f32,
+    spectrum: Vec<f32>,
+    trigger_level: f32,
+    scope_ms: f32,
+}
+
+impl AnalyzerWindow {
+    pub fn new() -> Self {
+        Self {
+            scope_p: false,
+            smoothing: 0.7,
+            spectrum: vec![SPECTRUM_DB_MIN; FFT_LEN / 2],
+            trigger_level: 0.0,
+            scope_ms: 20.0,
+        }
+    }
+
+    pub fn view(&mut self, ctx: &Context, state: &mut AppState) -> Result<()> {
+        let Some(tap_source) = state.tap_source else {
+            return Ok(());
+        };
+        let mut open = true;
+        let mut tap_source_new = tap_source;
+        Window::new("Analyzer")
+            .open(&mut open)
+            .resizable(true)
+            .default_size([520.0, 300.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    match &mut tap_source_new {
+                        TapSource::Track(track_index) => {
+                            let name = state
+                                .song
+                                .tracks
+                                .get(*track_index)
+                                .map(|x| x.name.as_str())
+                                .unwrap_or("---");
+                            ui.label(name);
+                        }
+                        TapSource::Module((track_index, module_index), port) => {
+                            let name = state
+                                .song
+                                .tracks
+                                .get(*track_index)
+                                .and_then(|x| x.modules.get(*module_index))
+                                .map(|x| x.name.as_str())
+                                .unwrap_or("---");
+                            ui.label(name);
+                            ui.add(DragValue::new(port).range(0..=7).prefix("Out "));
+                        }
+                    }
+                    ui.separator();
+                    ui.selectable_value(&mut self.scope_p, false, "Spectrum");
+                    ui.selectable_value(&mut self.scope_p, true, "Scope");
+                    ui.separator();
+                    if self.scope_p {
+                        ui.add(Slider::new(&mut self.trigger_level, -1.0..=1.0).text("Trigger"));
+                        ui.add(
+                            DragValue::new(&mut self.scope_ms)
+                                .range(1.0..=200.0)
+                                .suffix(" ms"),
+                        );
+                    } else {
+                        ui.add(Slider::new(&mut self.smoothing, 0.0..=0.95).text("Smoothing"));
+                    }
+                });
+
+                let size = ui.available_size().max(vec2(200.0, 100.0));
+                let (response, painter) = ui.allocate_painter(size, Sense::hover());
+                let rect = response.rect;
+                painter.rect_filled(rect, 0.0, Color32::BLACK);
+                if self.scope_p {
+                    self.view_scope(&painter, rect, state);
+                } else {
+                    self.view_spectrum(&painter, rect, state);
+                }
+            });
+
+        if !open {
+            state.tap_source_set(None)?;
+        } else if tap_source_new != tap_source {
+            state.tap_source_set(Some(tap_source_new))?;
+        }
+        // 再生していなくても動きを見たい
+        ctx.request_repaint_after(Duration::from_secs_f64(1.0 / 30.0));
+        Ok(())
+    }
+
+    fn view_spectrum(&mut self, painter: &Painter, rect: Rect, state: &AppState) {
+        let x_of = |freq: f32| {
+            rect.left() + (freq / FREQ_MIN).log10() / (FREQ_MAX / FREQ_MIN).log10() * rect.width()
+        };
+        let y_of = |db: f32| {
+            let t = (db - SPECTRUM_DB_MIN) / (SPECTRUM_DB_MAX - SPECTRUM_DB_MIN);
+            rect.bottom() - t.clamp(0.0, 1.0) * rect.height()
+        };
+
+        for freq in [100.0, 1000.0, 10000.0] {
+            let x = x_of(freq);
+            painter.line_segment(
+                [pos2(x, rect.top()), pos2(x, rect.bottom())],
+                Stroke::new(1.0, Color32::from_gray(0x30)),
+            );
+            painter.text(
+                pos2(x + 2.0, rect.bottom()),
+                Align2::LEFT_BOTTOM,
+                format!("{}", freq),
+                FontId::monospace(10.0),
+                Color32::GRAY,
+            );
+        }
+        for db in [-72.0, -48.0, -24.0, 0.0] {
+            let y = y_of(db);
+            painter.line_segment(
+                [pos2(rect.left(), y), pos2(rect.right(), y)],
+                Stroke::new(1.0, Color32::from_gray(0x30)),
+            );
+            painter.text(
+                pos2(rect.left() + 2.0, y),
+                Align2::LEFT_BOTTOM,
+                format!("{}", db),
+                FontId::monospace(10.0),
+                Color32::GRAY,
+            );
+        }
+
+        let Some((buffer, sample_rate)) = state.tap_state.read(FFT_LEN) else {
+            return;
+        };
+        if buffer.is_empty() || sample_rate == 0.0 {
+            return;
+        }
+
+        // チャンネルを平均して Hann 窓をかける
+        let window_sum = FFT_LEN as f32 / 2.0;
+        let mut re = (0..FFT_LEN)
+            .map(|frame| {
+                let window =
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * frame as f32 / FFT_LEN as f32).cos();
+                let value = buffer.iter().map(|x| x[frame]).sum::<f32>() / buffer.len() as f32;
+                value * window
+            })
+            .collect::<Vec<_>>();
+        let mut im = vec![0.0; FFT_LEN];
+        fft(&mut re, &mut im);
+        for bin in 0..FFT_LEN / 2 {
+            let magnitude = (re[bin] * re[bin] + im[bin] * im[bin]).sqrt() / window_sum;
+            let db = linear_to_db(magnitude).max(SPECTRUM_DB_MIN);
+            self.spectrum[bin] = self.spectrum[bin] * self.smoothing + db * (1.0 - self.smoothing);
+        }
+
+        // 高い方は 1 ピクセルに何 bin も入るので最大をとる
+        let bin_width = sample_rate as f32 / FFT_LEN as f32;
+        let mut points = vec![];
+        let mut x_last = f32::MIN;
+        let mut db_max = SPECTRUM_DB_MIN;
+        for bin in 1..FFT_LEN / 2 {
+            let freq = bin as f32 * bin_width;
+            if !(FREQ_MIN..=FREQ_MAX).contains(&freq) {
+                continue;
+            }
+            db_max = db_max.max(self.spectrum[bin]);
+            let x = x_of(freq);
+            if x - x_last >= 1.0 {
+                points.push(pos2(x, y_of(db_max)));
+                x_last = x;
+                db_max = SPECTRUM_DB_MIN;
+            }
+        }
+        painter.add(Shape::line(points, Stroke::new(1.0, CHANNEL_COLORS[0])));
+    }
+
+    fn view_scope(&self, painter: &Painter, rect: Rect, state: &AppState) {
+        let y_of = |value: f32| rect.center().y - value.clamp(-1.0, 1.0) * rect.height() / 2.0;
+        painter.line_segment(
+            [pos2(rect.left(), y_of(0.0)), pos2(rect.right(), y_of(0.0))],
+            Stroke::new(1.0, Color32::from_gray(0x30)),
+        );
+        let y = y_of(self.trigger_level);
+        painter.line_segment(
+            [pos2(rect.left(), y), pos2(rect.left() + 8.0, y)],
+            Stroke::new(1.0, Color32::YELLOW),
+        );
+
+        let Some((buffer, sample_rate)) = state.tap_state.read(usize::MAX) else {
+            return;
+        };
+        if buffer.is_empty() || sample_rate == 0.0 {
+            return;
+        }
+        let len = buffer[0].len();
+        let nframes = ((self.scope_ms / 1000.0 * sample_rate as f32) as usize).clamp(2, len / 2);
+
+        // 1 チャンネル目が trigger_level を上に横切った最後の位置 なければフリーラン
+        let start = (1..=len - nframes)
+            .rev()
+            .find(|&frame| {
+                buffer[0][frame - 1] < self.trigger_level && buffer[0][frame] >= self.trigger_level
+            })
+            .unwrap_or(len - nframes);
+
+        for (channel, samples) in buffer.iter().enumerate() {
+            let points = samples[start..start + nframes]
+                .iter()
+                .enumerate()
+                .map(|(frame, value)| {
+                    pos2(
+                        rect.left() + frame as f32 / (nframes - 1) as f32 * rect.width(),
+                        y_of(*value),
+                    )
+                })
+                .collect::<Vec<_>>();
+            painter.add(Shape::line(
+                points,
+                Stroke::new(1.0, CHANNEL_COLORS[channel % CHANNEL_COLORS.len()]),
+            ));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use eframe::egui::{CentralPanel, ComboBox, Grid, Key, Ui};
+
+use crate::{
+    config::Config,
+    device::{Device, BUFFER_SIZES, OUTPUT_CHANNELS, SAMPLE_RATES},
+};
+
+pub enum ReturnState {
+    Apply(Config),
+    Continue,
+    Cancel,
+}
+
+pub struct AudioDeviceView {
+    config: Config,
+    hosts: Vec<String>,
+    devices: Vec<String>,
+    input_devices: Vec<String>,
+}
+
+impl AudioDeviceView {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            hosts: Device::hosts(),
+            devices: Device::output_devices(config.audio_host.as_deref()),
+            input_devices: Device::input_devices(config.audio_host.as_deref()),
+        }
+    }
+
+    pub fn view(&mut self, gui_context: &eframe::egui::Context) -> Result<ReturnState> {
+        CentralPanel::default()
+            .show(gui_context, |ui: &mut Ui| -> Result<ReturnState> {
+                let host_before = self.config.audio_host.clone();
+                Grid::new("audio_device_grid").show(ui, |ui| {
+                    ui.label("Host");
+                    combo_box(
+                        ui,
+                        "Host",
+                        &mut self.config.audio_host,
+                        &self.hosts,
+                        "Default",
+                    );
+                    ui.end_row();
+
+                    ui.label("Output");
+                    combo_box(
+                        ui,
+                        "Output",
+                        &mut self.config.audio_device_output,
+                        &self.devices,
+                        "Default",
+                    );
+                    ui.end_row();
+
+                    ui.label("Input");
+                    combo_box(
+                        ui,
+                        "Input",
+                        &mut self.config.audio_device_input,
+                        &self.input_devices,
+                        "None",
+                    );
+                    ui.end_row();
+
+                    ui.label("Sample Rate");
+                    combo_box(
+                        ui,
+                        "Sample Rate",
+                        &mut self.config.sample_rate,
+                        &SAMPLE_RATES,
+                        "Default",
+                    );
+                    ui.end_row();
+
+                    ui.label("Output Channels");
+                    combo_box(
+                        ui,
+                        "Output Channels",
+                        &mut self.config.output_channels,
+                        &OUTPUT_CHANNELS,
+                        "Default",
+                    );
+                    ui.end_row();
+
+                    ui.label("Buffer Size");
+                    combo_box(
+                        ui,
+                        "Buffer Size",
+                        &mut self.config.buffer_size,
+                        &BUFFER_SIZES,
+                        "Default",
+                    );
+                    ui.end_row();
+                });
+                if self.config.audio_host != host_before {
+                    self.config.audio_device_output = None;
+                    self.config.audio_device_input = None;
+                    self.devices = Device::output_devices(self.config.audio_host.as_deref());
+                    self.input_devices = Device::input_devices(self.config.audio_host.as_deref());
+                }
+
+                ui.separator();
+
+                if ui.button("Apply").clicked() {
+                    return Ok(ReturnState::Apply(self.config.clone()));
+                }
+                if ui.button("Cancel").clicked() || ui.input(|i| i.key_pressed(Key::Escape)) {
+                    return Ok(ReturnState::Cancel);
+                }
+
+                Ok(ReturnState::Continue)
+            })
+            .inner
+    }
+}
+
+fn combo_box<T: Clone + PartialEq + ToString>(
+    ui: &mut Ui,
+    id: &str,
+    value: &mut Option<T>,
+    items: &[T],
+    none_text: &str,
+) {
+    let text = value
+        .as_ref()
+        .map(|x| x.to_string())
+        .unwrap_or(none_text.to_string());
+    ComboBox::from_id_salt(id)
+        .selected_text(text)
+        .show_ui(ui, |ui| {
+            ui.selectable_value(value, None, none_text);
+            for item in items {
+                ui.selectable_value(value, Some(item.clone()), item.to_string());
+            }
+        });
+}
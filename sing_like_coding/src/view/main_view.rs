@@ -2,11 +2,13 @@ use std::{collections::HashMap, ops::Range};
 
 use anyhow::Result;
 use common::{
+    channel_layout::CHANNEL_LAYOUTS,
     dsp::{db_from_norm, db_to_norm},
-    protocol::MainToPlugin,
+    sampler::SamplerState,
 };
 use eframe::egui::{
-    CentralPanel, Color32, DragValue, DroppedFile, Key, TextEdit, TopBottomPanel, Ui,
+    pos2, CentralPanel, Color32, DragValue, DroppedFile, Key, RichText, Stroke, TextEdit,
+    TopBottomPanel, Ui,
 };
 
 use crate::{
@@ -15,7 +17,9 @@ use crate::{
         UiCommand,
     },
     device::Device,
-    model::lane_item::LaneItem,
+    model::{lane_item::LaneItem, pan_law::PAN_LAWS},
+    song_state::MAX_MODULES,
+    tap::TapSource,
     util::with_font_mono,
 };
 
@@ -29,6 +33,7 @@ use super::{
 };
 
 const DEFAULT_TRACK_WIDTH: f32 = 64.0;
+const TOP_OFFENDERS: usize = 8;
 
 pub struct MainView {
     bpm: Option<f64>,
@@ -55,6 +60,7 @@ impl MainView {
             ((Modifier::None, Key::P), UiCommand::Loop),
             ((Modifier::S, Key::P), UiCommand::Follow),
             ((Modifier::C, Key::P), UiCommand::LoopRange),
+            ((Modifier::CS, Key::P), UiCommand::PunchRange),
             ((Modifier::None, Key::S), UiCommand::TrackSolo(None, None)),
             ((Modifier::C, Key::S), UiCommand::SongSave),
             ((Modifier::C, Key::T), UiCommand::TrackAdd),
@@ -471,7 +477,7 @@ impl MainView {
                 );
                 ui.heading(song_name);
 
-                if state.song_state.play_p {
+                if state.song_state.play_p || state.song_state.count_in_p {
                     if ui.button("Stop").clicked() {
                         state.stop()?;
                     }
@@ -486,6 +492,46 @@ impl MainView {
                     commands.push(UiCommand::RecToggle);
                 }
 
+                let mut rec_replace_p = state.song_state.rec_replace_p;
+                if ui.toggle_value(&mut rec_replace_p, "Replace").clicked() {
+                    commands.push(UiCommand::RecReplaceToggle);
+                }
+
+                let mut count_in_bars = state.song_state.count_in_bars;
+                if ui
+                    .add(
+                        DragValue::new(&mut count_in_bars)
+                            .range(0..=4)
+                            .prefix("Count "),
+                    )
+                    .changed()
+                {
+                    state.count_in_bars_set(count_in_bars)?;
+                }
+
+                let mut metronome_p = state.song_state.metronome_p;
+                if ui.toggle_value(&mut metronome_p, "Click").clicked() {
+                    commands.push(UiCommand::MetronomeToggle);
+                }
+                let mut metronome_rec_only_p = state.song_state.metronome_rec_only_p;
+                if ui
+                    .toggle_value(&mut metronome_rec_only_p, "REC Only")
+                    .clicked()
+                {
+                    commands.push(UiCommand::MetronomeRecOnlyToggle);
+                }
+                let mut metronome_volume = state.song_state.metronome_volume;
+                if ui
+                    .add(
+                        DragValue::new(&mut metronome_volume)
+                            .speed(0.01)
+                            .range(0.0..=1.0),
+                    )
+                    .changed()
+                {
+                    state.metronome_volume_set(metronome_volume)?;
+                }
+
                 let mut bpm = self.bpm.unwrap_or(state.song.bpm);
                 let response = ui.add(DragValue::new(&mut bpm).speed(0.1).range(20.0..=999.9));
                 if response.has_focus() {
@@ -506,11 +552,28 @@ impl MainView {
                     play_position_text1(self.line_play, state.song.lpb)
                 ));
 
+                let (mut numerator, mut denominator) = state.song.time_signature;
+                let numerator_changed_p = ui
+                    .add(DragValue::new(&mut numerator).range(1..=32))
+                    .changed();
+                ui.label("/");
+                let denominator_changed_p = ui
+                    .add(DragValue::new(&mut denominator).range(1..=32))
+                    .changed();
+                if numerator_changed_p || denominator_changed_p {
+                    state.time_signature_set(numerator, denominator)?;
+                }
+
                 let mut loop_p = state.song_state.loop_p;
                 if ui.toggle_value(&mut loop_p, "Loop").clicked() {
                     state.loop_toggle()?;
                 }
 
+                let mut punch_p = state.song_state.punch_p;
+                if ui.toggle_value(&mut punch_p, "Punch").clicked() {
+                    commands.push(UiCommand::Punch);
+                }
+
                 ui.toggle_value(&mut state.follow_p, "Follow");
 
                 let mut device_start_p = device.as_mut().unwrap().start_p();
@@ -522,12 +585,88 @@ impl MainView {
                     }
                 }
 
+                let mut limiter_p = state.song_state.limiter_p;
+                if ui.toggle_value(&mut limiter_p, "Limit").clicked() {
+                    commands.push(UiCommand::LimiterToggle);
+                }
+                let mut limiter_ceiling = state.song_state.limiter_ceiling;
+                if ui
+                    .add(
+                        DragValue::new(&mut limiter_ceiling)
+                            .speed(0.1)
+                            .range(-24.0..=0.0)
+                            .suffix("dBTP"),
+                    )
+                    .changed()
+                {
+                    state.limiter_ceiling_set(limiter_ceiling)?;
+                }
+                if limiter_p {
+                    ui.label(format!(
+                        "GR {:.1}dB",
+                        state.song_state.limiter_gain_reduction
+                    ));
+                }
+
+                if state.song_state.bad_samples_p
+                    || state
+                        .song_state
+                        .tracks
+                        .iter()
+                        .any(|x| x.bad_module_index != usize::MAX)
+                {
+                    if ui
+                        .button(RichText::new("NaN").color(Color32::RED))
+                        .on_hover_text("Click to clear")
+                        .clicked()
+                    {
+                        state.bad_samples_clear()?;
+                    }
+                }
+
+                if let Some(name) = &state.plugin_failed {
+                    ui.label(RichText::new(format!("{} crashed", name)).color(Color32::RED))
+                        .on_hover_text(
+                            "The plugin host stopped responding. \
+                             If it keeps crashing, it is not restarted automatically.",
+                        );
+                    if ui.button("Restart").clicked() {
+                        state.plugin_failed_restart()?;
+                    }
+                    if ui.button("x").clicked() {
+                        state.plugin_failed = None;
+                    }
+                }
+
+                if let Some((name, error)) = &state.plugin_load_failed {
+                    ui.label(RichText::new(format!("{} not loaded", name)).color(Color32::RED))
+                        .on_hover_text(error);
+                    if ui.button("x").clicked() {
+                        state.plugin_load_failed = None;
+                    }
+                }
+
+                if state.plugin_scanner.scanning_p() {
+                    ui.label("Scanning plugins...");
+                }
+
                 ui.label(format!(
                     "{:.3}ms",
                     state.song_state.process_elasped_avg * 1000.0
                 ));
-                ui.label(format!("{:.3}%", state.song_state.cpu_usage * 100.0));
+                ui.label(format!("{:.3}%", state.song_state.cpu_usage * 100.0))
+                    .on_hover_text(cpu_usage_text(state));
                 ui.label(format!("{:.1}fps", 1.0 / state.elapsed));
+                let events_dropped =
+                    state.song_state.events_dropped_input + state.song_state.events_dropped_output;
+                if events_dropped != 0 {
+                    ui.label(format!("{} events dropped", events_dropped))
+                        .on_hover_text(format!(
+                            "input {} / output {}",
+                            state.song_state.events_dropped_input,
+                            state.song_state.events_dropped_output
+                        ));
+                }
                 Ok(())
             });
         });
@@ -693,9 +832,15 @@ impl MainView {
                 format!("{:<3}    {:02X}", note.note_name(), note.delay)
             }
             Some(LaneItem::Note(note)) => format!(
-                "{:<3} {:02X} {:02X}",
-                note.note_name(),
+                "{:<3.3} {:02X}{}{:02X}",
+                // ドラムはプラグインが付けた名前 (Kick など) の頭を出す
+                state
+                    .note_name(track_index, note.key, note.channel)
+                    .map(|x| x.to_string())
+                    .unwrap_or_else(|| note.note_name()),
                 note.velocity as i32,
+                // 同じ line 内で止まるノート
+                if note.cut.is_some() { "~" } else { " " },
                 note.delay
             ),
             Some(LaneItem::Point(point)) => {
@@ -716,6 +861,8 @@ impl MainView {
 
             Some(LaneItem::Ret) => "^        ".to_string(),
 
+            Some(LaneItem::AudioClip(clip)) => format!("~{:<8.8}", clip.name()),
+
             None => "         ".to_string(),
         }
     }
@@ -776,6 +923,44 @@ impl MainView {
                 LabelBuilder::new(ui, format!("{:.2}dB", x.hold_db)).build();
             }
 
+            let track_state = &state.song_state.tracks[track_index];
+            LabelBuilder::new(ui, format!("M {:.1}LUFS", track_state.lufs_momentary))
+                .build()
+                .on_hover_text("Momentary loudness");
+            LabelBuilder::new(
+                ui,
+                format!("RMS {:.1}", track_state.rms[0].max(track_state.rms[1])),
+            )
+            .build();
+            LabelBuilder::new(
+                ui,
+                format!(
+                    "TP {:.1}",
+                    track_state.true_peaks[0].max(track_state.true_peaks[1])
+                ),
+            )
+            .color(if track_state.true_peak_max > 0.0 {
+                Color32::RED
+            } else {
+                Color32::GRAY
+            })
+            .build()
+            .on_hover_text(format!(
+                "True peak max {:.1}dBTP",
+                track_state.true_peak_max
+            ));
+            if track_index == 0 {
+                LabelBuilder::new(ui, format!("S {:.1}LUFS", track_state.lufs_short_term))
+                    .build()
+                    .on_hover_text("Short-term loudness");
+                LabelBuilder::new(ui, format!("I {:.1}LUFS", track_state.lufs_integrated))
+                    .build()
+                    .on_hover_text("Integrated loudness");
+                if ui.button("Reset").clicked() {
+                    commands.push(UiCommand::LoudnessReset);
+                }
+            }
+
             ui.horizontal(|ui| -> anyhow::Result<()> {
                 let mut pan = track.pan;
                 let knob = ui.add(Knob { value: &mut pan });
@@ -785,6 +970,40 @@ impl MainView {
                     commands.push(UiCommand::TrackPan(track_index, 0.5));
                 }
 
+                if track.dual_pan_p {
+                    let mut pan_right = track.pan_right;
+                    let knob = ui.add(Knob {
+                        value: &mut pan_right,
+                    });
+                    if knob.dragged() {
+                        commands.push(UiCommand::TrackPanRight(track_index, pan_right));
+                    } else if knob.double_clicked() {
+                        commands.push(UiCommand::TrackPanRight(track_index, 1.0));
+                    }
+                } else {
+                    // ノブは 0.0 - 1.0 なので半分にする
+                    let mut width = track.width / 2.0;
+                    let knob = ui.add(Knob { value: &mut width });
+                    if knob.dragged() {
+                        commands.push(UiCommand::TrackWidth(track_index, width * 2.0));
+                    } else if knob.double_clicked() {
+                        commands.push(UiCommand::TrackWidth(track_index, 1.0));
+                    }
+                }
+
+                // バスがサラウンドのときは前後も
+                if state.song.tracks[0].layout.nchannels() > 2 {
+                    let mut pan_rear = track.pan_rear;
+                    let knob = ui.add(Knob {
+                        value: &mut pan_rear,
+                    });
+                    if knob.dragged() {
+                        commands.push(UiCommand::TrackPanRear(track_index, pan_rear));
+                    } else if knob.double_clicked() {
+                        commands.push(UiCommand::TrackPanRear(track_index, 0.0));
+                    }
+                }
+
                 ui.vertical(|ui| -> anyhow::Result<()> {
                     let width = 18.0;
 
@@ -811,14 +1030,76 @@ impl MainView {
                 Ok(())
             });
 
-            let mut rec = state.song_state.tracks[track_index].rec_p;
-            if ui.toggle_value(&mut rec, "REC").clicked() {
-                if rec {
-                    commands.push(UiCommand::TrackRecOn(track_index));
-                } else {
-                    commands.push(UiCommand::TrackRecOff(track_index));
+            ui.horizontal(|ui| {
+                let mut rec = state.song_state.tracks[track_index].rec_p;
+                if ui.toggle_value(&mut rec, "REC").clicked() {
+                    if rec {
+                        commands.push(UiCommand::TrackRecOn(track_index));
+                    } else {
+                        commands.push(UiCommand::TrackRecOff(track_index));
+                    }
                 }
-            }
+
+                let mut monitor_p = state.song_state.tracks[track_index].monitor_p;
+                let input = ui
+                    .toggle_value(&mut monitor_p, "IN")
+                    .on_hover_text(input_channels_text(&track.input_channels));
+                if input.clicked() {
+                    commands.push(UiCommand::TrackMonitor(track_index, monitor_p));
+                }
+                ui.menu_button(track.layout.name(), |ui| {
+                    for layout in CHANNEL_LAYOUTS {
+                        if ui.button(layout.name()).clicked() {
+                            commands.push(UiCommand::TrackLayout(track_index, layout));
+                            ui.close_menu();
+                        }
+                    }
+                    ui.separator();
+                    for pan_law in PAN_LAWS {
+                        if ui.radio(track.pan_law == pan_law, pan_law.name()).clicked() {
+                            commands.push(UiCommand::TrackPanLaw(track_index, pan_law));
+                            ui.close_menu();
+                        }
+                    }
+                    ui.separator();
+                    let mut dual_pan_p = track.dual_pan_p;
+                    if ui.checkbox(&mut dual_pan_p, "Dual Pan").clicked() {
+                        commands.push(UiCommand::TrackDualPan(track_index, dual_pan_p));
+                        ui.close_menu();
+                    }
+                    ui.separator();
+                    if ui.button("Analyzer").clicked() {
+                        commands.push(UiCommand::TapSource(Some(TapSource::Track(track_index))));
+                        ui.close_menu();
+                    }
+                });
+                input.context_menu(|ui| {
+                    let nchannels = state.song_state.audio_input_nchannels;
+                    if ui.button("Off").clicked() {
+                        commands.push(UiCommand::TrackInputChannels(track_index, vec![]));
+                        ui.close_menu();
+                    }
+                    for channel in 0..nchannels {
+                        if ui.button(format!("In {}", channel + 1)).clicked() {
+                            commands
+                                .push(UiCommand::TrackInputChannels(track_index, vec![channel]));
+                            ui.close_menu();
+                        }
+                    }
+                    for channel in (0..nchannels.saturating_sub(1)).step_by(2) {
+                        if ui
+                            .button(format!("In {}/{}", channel + 1, channel + 2))
+                            .clicked()
+                        {
+                            commands.push(UiCommand::TrackInputChannels(
+                                track_index,
+                                vec![channel, channel + 1],
+                            ));
+                            ui.close_menu();
+                        }
+                    }
+                });
+            });
 
             ui.horizontal(|ui| -> anyhow::Result<()> {
                 let height = 160.0;
@@ -924,19 +1205,27 @@ impl MainView {
             )
             .hovered()
         {
+            let mut audio_paths = vec![];
             for file in self.dropped_files.iter() {
                 if let Some(path) = &file.path {
-                    if path
+                    let ext = path
                         .extension()
                         .and_then(|ext| ext.to_str())
-                        .map_or(false, |ext| {
-                            matches!(ext.to_lowercase().as_str(), "mid" | "midi")
-                        })
-                    {
-                        state.midi_file_read(track_index, lane_index, &path)?;
+                        .map(|ext| ext.to_lowercase());
+                    match ext.as_deref() {
+                        Some("mid" | "midi") => {
+                            state.midi_file_read(track_index, lane_index, &path)?;
+                        }
+                        Some("wav" | "flac") => {
+                            audio_paths.push(path.to_string_lossy().to_string());
+                        }
+                        _ => {}
                     }
                 }
             }
+            if !audio_paths.is_empty() {
+                state.sampler_load(track_index, &SamplerState::from_paths(&audio_paths))?;
+            }
             self.dropped_files.clear();
         }
 
@@ -976,15 +1265,86 @@ impl MainView {
         } else {
             (Color32::GRAY, Color32::BLACK)
         };
+        // NaN/Inf を出したモジュール
+        let color = if state.song_state.tracks[track_index].bad_module_index == module_index {
+            Color32::RED
+        } else {
+            color
+        };
         let label = LabelBuilder::new(ui, &module.name)
             .color(color)
             .bg_color(bg_color)
             .size([DEFAULT_TRACK_WIDTH, 0.0])
             .build();
+        // process の往復にかかった時間を下に線で出す
+        let track_state = &state.song_state.tracks[track_index];
+        let (cpu_usage, elapsed_max) = if module_index < MAX_MODULES {
+            (
+                track_state.module_cpu_usages[module_index],
+                track_state.module_elapsed_maxs[module_index],
+            )
+        } else {
+            (0.0, 0.0)
+        };
+        let rect = label.rect;
+        ui.painter().line_segment(
+            [
+                pos2(rect.left(), rect.bottom()),
+                pos2(
+                    rect.left() + rect.width() * cpu_usage.min(1.0) as f32,
+                    rect.bottom(),
+                ),
+            ],
+            Stroke::new(
+                2.0,
+                if cpu_usage > 0.5 {
+                    Color32::RED
+                } else if cpu_usage > 0.2 {
+                    Color32::YELLOW
+                } else {
+                    Color32::DARK_GREEN
+                },
+            ),
+        );
+        let label = label.on_hover_text(format!(
+            "{:.1}% max {:.3}ms",
+            cpu_usage * 100.0,
+            elapsed_max * 1000.0
+        ));
         if label.clicked() {
-            state.send_to_plugin(MainToPlugin::GuiOpen(module.id), Box::new(|_, _| Ok(())))?;
+            state.module_open((track_index, module_index))?;
         }
         label.context_menu(|ui: &mut Ui| {
+            if ui.button("Analyzer").clicked() {
+                state
+                    .tap_source_set(Some(TapSource::Module((track_index, module_index), 0)))
+                    .unwrap();
+                ui.close_menu();
+            }
+            if ui.button("Presets...").clicked() {
+                state.presets_open((track_index, module_index)).unwrap();
+                ui.close_menu();
+            }
+            let mut isolated_p = state
+                .song
+                .module_at((track_index, module_index))
+                .is_some_and(|x| x.isolated_p);
+            if ui.checkbox(&mut isolated_p, "Own Process").clicked() {
+                state
+                    .module_isolated_set((track_index, module_index), isolated_p)
+                    .unwrap();
+                ui.close_menu();
+            }
+            ui.menu_button("All Modules", |ui| {
+                if ui.button("Own Process").clicked() {
+                    state.module_isolated_all_set(true).unwrap();
+                    ui.close_menu();
+                }
+                if ui.button("Shared Process").clicked() {
+                    state.module_isolated_all_set(false).unwrap();
+                    ui.close_menu();
+                }
+            });
             if ui.button("Delete").clicked() {
                 state.plugin_delete((track_index, module_index)).unwrap();
                 ui.close_menu();
@@ -1066,6 +1426,11 @@ impl MainView {
                 for line in line_range.clone() {
                     let color = if line == self.line_play {
                         Color32::DARK_GREEN
+                    } else if state.song_state.punch_p
+                        && (state.song_state.punch_start..state.song_state.punch_end)
+                            .contains(&(line * 0x100))
+                    {
+                        Color32::from_rgb(0x30, 0x00, 0x00)
                     } else if (state.song_state.loop_start..state.song_state.loop_end)
                         .contains(&(line * 0x100))
                     {
@@ -1134,10 +1499,44 @@ impl MainView {
             } else {
                 (Color32::GRAY, Color32::BLACK)
             };
-            LabelBuilder::new(ui, format!("{:<9}", state.song.tracks[track_index].name))
-                .color(color)
-                .bg_color(bg_color)
-                .build();
+            let label =
+                LabelBuilder::new(ui, format!("{:<9}", state.song.tracks[track_index].name))
+                    .color(color)
+                    .bg_color(bg_color)
+                    .build();
+            let ntakes = state.song.tracks[track_index].takes.len();
+            if ntakes > 0 {
+                let take_active = state.song.tracks[track_index]
+                    .take_active_at(state.cursor_track.line)
+                    .map(|x| format!("{}", x + 1))
+                    .unwrap_or("-".to_string());
+                let mut result = Ok(());
+                label
+                    .on_hover_text(format!("Take {}/{}", take_active, ntakes))
+                    .context_menu(|ui: &mut Ui| {
+                        for take_index in 0..ntakes {
+                            if ui.button(format!("Take {}", take_index + 1)).clicked() {
+                                result = state.take_activate(track_index, Some(take_index));
+                                ui.close_menu();
+                            }
+                        }
+                        if ui.button("Take Off").clicked() {
+                            result = state.take_activate(track_index, None);
+                            ui.close_menu();
+                        }
+                        ui.separator();
+                        for take_index in 0..ntakes {
+                            if ui
+                                .button(format!("Delete Take {}", take_index + 1))
+                                .clicked()
+                            {
+                                result = state.take_delete(track_index, take_index);
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                result?;
+            }
         }
         let height_after_track_header = ui.available_height();
         if self.height_track_header == 0.0 {
@@ -1249,3 +1648,57 @@ fn play_position_text2(line: usize, lpb: u16) -> String {
     let bar = lpb * 4;
     format!("{:03}.{:X}", line / bar + 1, line % bar / lpb + 1)
 }
+
+/// 重いモジュールの上位と level ごとの処理時間
+fn cpu_usage_text(state: &AppState) -> String {
+    let mut modules = vec![];
+    for (track_index, track) in state.song.tracks.iter().enumerate() {
+        let track_state = &state.song_state.tracks[track_index];
+        for (module_index, module) in track.modules.iter().enumerate().take(MAX_MODULES) {
+            modules.push((
+                track_state.module_cpu_usages[module_index],
+                track_state.module_elapsed_maxs[module_index],
+                format!("{}/{}", track.name, module.name),
+            ));
+        }
+    }
+    modules.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut lines = modules
+        .iter()
+        .take(TOP_OFFENDERS)
+        .map(|(cpu_usage, elapsed_max, name)| {
+            format!(
+                "{:>5.1}% max {:.3}ms {}",
+                cpu_usage * 100.0,
+                elapsed_max * 1000.0,
+                name
+            )
+        })
+        .collect::<Vec<_>>();
+    lines.push(String::new());
+    for level_index in 0..state.song_state.nlevels {
+        lines.push(format!(
+            "Level {} {:>5.1}% max {:.3}ms",
+            level_index,
+            state.song_state.level_cpu_usages[level_index] * 100.0,
+            state.song_state.level_elapsed_maxs[level_index] * 1000.0
+        ));
+    }
+    lines.join("\n")
+}
+
+fn input_channels_text(input_channels: &[usize]) -> String {
+    if input_channels.is_empty() {
+        "No input".to_string()
+    } else {
+        format!(
+            "In {}",
+            input_channels
+                .iter()
+                .map(|x| (x + 1).to_string())
+                .collect::<Vec<_>>()
+                .join("/")
+        )
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use anyhow::Result;
+use common::clap_manager::{clap_search_dirs, ScanReport, ScanStatus};
+use eframe::egui::{CentralPanel, Color32, Grid, Key, RichText, ScrollArea, Ui};
+use rfd::FileDialog;
+
+pub enum ReturnState {
+    /// ブロックリストから外してスキャンし直す
+    Retry(String),
+    Rescan,
+    /// 設定で足す CLAP のディレクトリが変わった
+    ClapPaths(Vec<String>),
+    Continue,
+    Close,
+}
+
+/// スキャンの結果 失敗したものとブロックリストでとばしたものも出す
+pub struct PluginScanView {}
+
+impl PluginScanView {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        reports: &[ScanReport],
+        clap_paths: &[String],
+        scanning_p: bool,
+    ) -> Result<ReturnState> {
+        CentralPanel::default()
+            .show(gui_context, |ui: &mut Ui| -> Result<ReturnState> {
+                let mut clap_paths_new = None;
+                ui.collapsing("Search Paths", |ui| {
+                    for dir in clap_search_dirs(clap_paths) {
+                        let dir = dir.to_string_lossy().to_string();
+                        ui.horizontal(|ui| {
+                            if let Some(index) = clap_paths.iter().position(|x| *x == dir) {
+                                ui.label(&dir);
+                                if ui.small_button("x").clicked() {
+                                    let mut clap_paths = clap_paths.to_vec();
+                                    clap_paths.remove(index);
+                                    clap_paths_new = Some(clap_paths);
+                                }
+                            } else {
+                                // CLAP_PATH と標準のディレクトリは消せない
+                                ui.label(RichText::new(&dir).color(Color32::GRAY));
+                            }
+                        });
+                    }
+                    if ui.button("Add Folder...").clicked() {
+                        if let Some(dir) = FileDialog::new().pick_folder() {
+                            let mut clap_paths = clap_paths.to_vec();
+                            clap_paths.push(dir.to_string_lossy().to_string());
+                            clap_paths_new = Some(clap_paths);
+                        }
+                    }
+                });
+                if let Some(clap_paths) = clap_paths_new {
+                    return Ok(ReturnState::ClapPaths(clap_paths));
+                }
+                ui.separator();
+
+                let count =
+                    |f: fn(&ScanStatus) -> bool| reports.iter().filter(|x| f(&x.status)).count();
+                if scanning_p {
+                    ui.label("Scanning...");
+                } else {
+                    ui.label(format!(
+                        "{} files: {} found, {} unchanged, {} failed, {} skipped, {} removed",
+                        reports.len(),
+                        count(|x| matches!(x, ScanStatus::Found(_))),
+                        count(|x| matches!(x, ScanStatus::Unchanged(_))),
+                        count(|x| matches!(x, ScanStatus::Failed(_))),
+                        count(|x| matches!(x, ScanStatus::Blocked(_))),
+                        count(|x| matches!(x, ScanStatus::Removed)),
+                    ));
+                }
+                ui.separator();
+
+                let mut retry = None;
+                ScrollArea::vertical()
+                    .max_height(ui.available_height() - 40.0)
+                    .show(ui, |ui| {
+                        Grid::new("plugin_scan_grid").striped(true).show(ui, |ui| {
+                            for report in reports.iter() {
+                                let file_name = Path::new(&report.path)
+                                    .file_name()
+                                    .map(|x| x.to_string_lossy().to_string())
+                                    .unwrap_or(report.path.clone());
+                                ui.label(file_name).on_hover_text(&report.path);
+                                match &report.status {
+                                    ScanStatus::Found(names) => {
+                                        ui.label(names.join(", "));
+                                        ui.label("");
+                                    }
+                                    ScanStatus::Unchanged(names) => {
+                                        ui.label(
+                                            RichText::new(names.join(", ")).color(Color32::GRAY),
+                                        );
+                                        ui.label("");
+                                    }
+                                    ScanStatus::Removed => {
+                                        ui.label(RichText::new("Removed").color(Color32::GRAY));
+                                        ui.label("");
+                                    }
+                                    ScanStatus::Failed(error) => {
+                                        ui.label(
+                                            RichText::new(format!("Failed: {}", error))
+                                                .color(Color32::RED),
+                                        );
+                                        if ui.button("Retry").clicked() {
+                                            retry = Some(report.path.clone());
+                                        }
+                                    }
+                                    ScanStatus::Blocked(error) => {
+                                        ui.label(
+                                            RichText::new(format!("Skipped: {}", error))
+                                                .color(Color32::YELLOW),
+                                        );
+                                        if ui.button("Retry").clicked() {
+                                            retry = Some(report.path.clone());
+                                        }
+                                    }
+                                }
+                                ui.end_row();
+                            }
+                        });
+                    });
+                if let Some(path) = retry {
+                    return Ok(ReturnState::Retry(path));
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| -> Result<ReturnState> {
+                    if ui.button("Rescan").clicked() {
+                        return Ok(ReturnState::Rescan);
+                    }
+                    if ui.button("Close").clicked() || ui.input(|i| i.key_pressed(Key::Escape)) {
+                        return Ok(ReturnState::Close);
+                    }
+                    Ok(ReturnState::Continue)
+                })
+                .inner
+            })
+            .inner
+    }
+}
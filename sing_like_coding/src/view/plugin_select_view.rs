@@ -1,61 +1,133 @@
+use std::collections::BTreeMap;
+
 use anyhow::Result;
 use common::{clap_manager::ClapManager, plugin::description::Description};
-use eframe::egui::{self, Button, CentralPanel, Key, TextEdit, Ui};
+use eframe::egui::{
+    self, CentralPanel, Color32, Key, RichText, ScrollArea, SelectableLabel, TextEdit, Ui,
+};
+
+use crate::{plugin_library::PluginLibrary, util::is_subsequence_case_insensitive};
 
-use crate::util::is_subsequence_case_insensitive;
+// CLAP の主なカテゴリーは先に出す
+const MAIN_FEATURES: [&str; 5] = [
+    "instrument",
+    "audio-effect",
+    "note-effect",
+    "note-detector",
+    "analyzer",
+];
+
+/// 左の一覧で絞り込むもの
+#[derive(Clone, PartialEq)]
+enum Facet {
+    All,
+    Favorites,
+    Recent,
+    Feature(String),
+    Vendor(String),
+    Tag(String),
+}
 
 pub struct PluginSelectView {
     focus_p: bool,
     buffer: String,
     descriptions: Vec<Description>,
     quried_items: Vec<Description>,
+    // 裏のスキャンで claps.json が変わったら読み直す
+    claps_version: usize,
+    library: PluginLibrary,
+    facet: Facet,
+    /// 上下キーで動かす quried_items の位置
+    cursor: usize,
+    // キーで動かしたときだけカーソルの位置までスクロールする
+    scroll_p: bool,
+    tag_buffer: String,
 }
 
 impl PluginSelectView {
-    pub fn new() -> Self {
-        let mut clap_manager = ClapManager::new();
-        clap_manager.load().unwrap();
-        let descriptions = clap_manager.descriptions;
+    pub fn new(claps_version: usize) -> Self {
         Self {
             focus_p: true,
             buffer: "".to_string(),
-            descriptions,
+            descriptions: descriptions_load(),
             quried_items: vec![],
+            claps_version,
+            library: PluginLibrary::load().unwrap_or_default(),
+            facet: Facet::All,
+            cursor: 0,
+            scroll_p: false,
+            tag_buffer: "".to_string(),
         }
     }
 
-    pub fn view(&mut self, gui_context: &eframe::egui::Context) -> Result<ReturnState> {
+    pub fn view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        claps_version: usize,
+    ) -> Result<ReturnState> {
+        if self.claps_version != claps_version {
+            self.claps_version = claps_version;
+            self.descriptions = descriptions_load();
+        }
         CentralPanel::default()
             .show(gui_context, |ui: &mut Ui| -> Result<ReturnState> {
-                let edit = TextEdit::singleline(&mut self.buffer);
-                let response = ui.add(edit);
-                if response.changed() || self.focus_p {
-                    self.quried_items = self
-                        .descriptions
-                        .iter_mut()
-                        .filter(|x| is_subsequence_case_insensitive(&x.name, &self.buffer))
-                        .map(|x| x.clone())
-                        .collect::<Vec<_>>()
-                }
-                if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
-                    return Ok(ReturnState::Selected(self.quried_items[0].clone()));
+                let response = ui.horizontal(|ui| {
+                    let edit = TextEdit::singleline(&mut self.buffer)
+                        .hint_text("name #tag @vendor :feature")
+                        .desired_width(320.0);
+                    let response = ui.add(edit);
+                    ui.label(
+                        RichText::new("Up/Down to move, Enter to load, Esc to cancel")
+                            .color(Color32::GRAY),
+                    );
+                    response
+                });
+                let response = response.inner;
+                if response.changed() {
+                    self.cursor = 0;
                 }
                 if self.focus_p {
                     self.focus_p = false;
                     gui_context.memory_mut(|x| x.request_focus(response.id));
                 }
 
-                let mut selected = None;
-                ui.horizontal_wrapped(|ui| {
-                    for item in self.quried_items.iter() {
-                        let button = Button::new(&item.name).wrap_mode(egui::TextWrapMode::Extend);
-                        if ui.add(button).clicked() {
-                            selected = Some(item.clone());
-                        }
+                // お気に入りやタグが変わるので毎フレーム絞り込む
+                self.quried_items = self.query();
+                if ui.input(|i| i.key_pressed(Key::ArrowDown)) {
+                    self.cursor += 1;
+                    self.scroll_p = true;
+                }
+                if ui.input(|i| i.key_pressed(Key::ArrowUp)) {
+                    self.cursor = self.cursor.saturating_sub(1);
+                    self.scroll_p = true;
+                }
+                self.cursor = self.cursor.min(self.quried_items.len().saturating_sub(1));
+                if response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter)) {
+                    if let Some(item) = self.quried_items.get(self.cursor).cloned() {
+                        return Ok(self.selected(item));
                     }
+                    self.focus_p = true;
+                }
+                ui.separator();
+
+                let mut selected = None;
+                let height = ui.available_height() - 40.0;
+                ui.horizontal_top(|ui| {
+                    ScrollArea::vertical()
+                        .id_salt("plugin_select_facets")
+                        .max_height(height)
+                        .max_width(200.0)
+                        .show(ui, |ui| self.view_facets(ui));
+                    ui.separator();
+                    ScrollArea::vertical()
+                        .id_salt("plugin_select_items")
+                        .max_height(height)
+                        .show(ui, |ui| {
+                            selected = self.view_items(ui);
+                        });
                 });
                 if let Some(item) = selected {
-                    return Ok(ReturnState::Selected(item));
+                    return Ok(self.selected(item));
                 }
 
                 ui.separator();
@@ -68,6 +140,200 @@ impl PluginSelectView {
             })
             .inner
     }
+
+    fn view_facets(&mut self, ui: &mut Ui) {
+        let mut facet = self.facet.clone();
+        let count =
+            |f: &dyn Fn(&Description) -> bool| self.descriptions.iter().filter(|x| f(x)).count();
+
+        ui.selectable_value(
+            &mut facet,
+            Facet::All,
+            format!("All ({})", self.descriptions.len()),
+        );
+        ui.selectable_value(
+            &mut facet,
+            Facet::Favorites,
+            format!("Favorites ({})", count(&|x| self.library.favorite_p(&x.id))),
+        );
+        ui.selectable_value(&mut facet, Facet::Recent, "Recent");
+
+        ui.separator();
+        ui.label(RichText::new("Category").color(Color32::GRAY));
+        let mut features = BTreeMap::<String, usize>::new();
+        for feature in self.descriptions.iter().flat_map(|x| x.features.iter()) {
+            *features.entry(feature.clone()).or_default() += 1;
+        }
+        let mut features = features.into_iter().collect::<Vec<_>>();
+        features.sort_by_key(|(feature, _)| {
+            MAIN_FEATURES
+                .iter()
+                .position(|x| x == feature)
+                .unwrap_or(MAIN_FEATURES.len())
+        });
+        for (feature, n) in features {
+            ui.selectable_value(
+                &mut facet,
+                Facet::Feature(feature.clone()),
+                format!("{} ({})", feature, n),
+            );
+        }
+
+        ui.separator();
+        ui.label(RichText::new("Vendor").color(Color32::GRAY));
+        let mut vendors = BTreeMap::<String, usize>::new();
+        for description in self.descriptions.iter() {
+            *vendors.entry(description.vender.clone()).or_default() += 1;
+        }
+        for (vendor, n) in vendors {
+            ui.selectable_value(
+                &mut facet,
+                Facet::Vendor(vendor.clone()),
+                format!("{} ({})", vendor, n),
+            );
+        }
+
+        let tags = self.library.tags_all();
+        if !tags.is_empty() {
+            ui.separator();
+            ui.label(RichText::new("Tag").color(Color32::GRAY));
+            for tag in tags {
+                ui.selectable_value(&mut facet, Facet::Tag(tag.clone()), format!("#{}", tag));
+            }
+        }
+
+        if facet != self.facet {
+            self.facet = facet;
+            self.cursor = 0;
+            self.focus_p = true;
+        }
+    }
+
+    fn view_items(&mut self, ui: &mut Ui) -> Option<Description> {
+        let mut selected = None;
+        let mut changed_p = false;
+        for (index, item) in self.quried_items.iter().enumerate() {
+            ui.horizontal(|ui| {
+                let favorite_p = self.library.favorite_p(&item.id);
+                let star = RichText::new(if favorite_p { "★" } else { "☆" }).color(if favorite_p {
+                    Color32::YELLOW
+                } else {
+                    Color32::GRAY
+                });
+                if ui.button(star).on_hover_text("Favorite").clicked() {
+                    self.library.favorite_toggle(&item.id);
+                    changed_p = true;
+                }
+
+                let label = ui.add(
+                    SelectableLabel::new(index == self.cursor, &item.name)
+                        .wrap_mode(egui::TextWrapMode::Extend),
+                );
+                if index == self.cursor && self.scroll_p {
+                    label.scroll_to_me(None);
+                    self.scroll_p = false;
+                }
+                if label.clicked() {
+                    selected = Some(item.clone());
+                }
+                label.context_menu(|ui| {
+                    for tag in self.library.tags_of(&item.id).to_vec() {
+                        if ui.button(format!("Remove #{}", tag)).clicked() {
+                            self.library.tag_remove(&item.id, &tag);
+                            changed_p = true;
+                        }
+                    }
+                    ui.horizontal(|ui| {
+                        let response = ui.add(
+                            TextEdit::singleline(&mut self.tag_buffer)
+                                .hint_text("tag")
+                                .desired_width(100.0),
+                        );
+                        let enter_p =
+                            response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                        if ui.button("Add Tag").clicked() || enter_p {
+                            self.library.tag_add(&item.id, &self.tag_buffer);
+                            self.tag_buffer.clear();
+                            changed_p = true;
+                            ui.close_menu();
+                        }
+                    });
+                });
+
+                ui.label(RichText::new(&item.vender).color(Color32::GRAY));
+                ui.label(
+                    RichText::new(item.features.join(" "))
+                        .small()
+                        .color(Color32::DARK_GRAY),
+                );
+                for tag in self.library.tags_of(&item.id) {
+                    ui.label(
+                        RichText::new(format!("#{}", tag))
+                            .small()
+                            .color(Color32::LIGHT_BLUE),
+                    );
+                }
+            });
+        }
+        if changed_p {
+            let _ = self.library.save();
+        }
+        selected
+    }
+
+    /// 検索の語は全部満たすものだけ #タグ @ベンダー :フィーチャー それ以外は名前かベンダー
+    fn query(&self) -> Vec<Description> {
+        let library = &self.library;
+        let tokens = self.buffer.split_whitespace().collect::<Vec<_>>();
+        let mut items = self
+            .descriptions
+            .iter()
+            .filter(|x| match &self.facet {
+                Facet::All => true,
+                Facet::Favorites => library.favorite_p(&x.id),
+                Facet::Recent => library.recent_rank(&x.id).is_some(),
+                Facet::Feature(feature) => x.features.contains(feature),
+                Facet::Vendor(vendor) => x.vender == *vendor,
+                Facet::Tag(tag) => library.tags_of(&x.id).contains(tag),
+            })
+            .filter(|x| {
+                tokens.iter().all(|token| {
+                    if let Some(tag) = token.strip_prefix('#') {
+                        library
+                            .tags_of(&x.id)
+                            .iter()
+                            .any(|x| is_subsequence_case_insensitive(x, tag))
+                    } else if let Some(vendor) = token.strip_prefix('@') {
+                        is_subsequence_case_insensitive(&x.vender, vendor)
+                    } else if let Some(feature) = token.strip_prefix(':') {
+                        x.features
+                            .iter()
+                            .any(|x| is_subsequence_case_insensitive(x, feature))
+                    } else {
+                        is_subsequence_case_insensitive(&x.name, token)
+                            || is_subsequence_case_insensitive(&x.vender, token)
+                    }
+                })
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        // 最近使った順 使っていないものは名前順 (claps.json が名前順)
+        items.sort_by_key(|x| library.recent_rank(&x.id).unwrap_or(usize::MAX));
+        items
+    }
+
+    fn selected(&mut self, item: Description) -> ReturnState {
+        self.library.recent_push(&item.id);
+        let _ = self.library.save();
+        ReturnState::Selected(item)
+    }
+}
+
+fn descriptions_load() -> Vec<Description> {
+    let mut clap_manager = ClapManager::new();
+    // 初回のスキャンが終わるまではない
+    let _ = clap_manager.load();
+    clap_manager.descriptions
 }
 
 pub enum ReturnState {
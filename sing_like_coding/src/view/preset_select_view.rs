@@ -0,0 +1,137 @@
+use anyhow::Result;
+use common::plugin::preset::FactoryPreset;
+use eframe::egui::{CentralPanel, Color32, Key, RichText, ScrollArea, TextEdit, Ui};
+
+use crate::{plugin_preset, util::is_subsequence_case_insensitive};
+
+pub enum ReturnState {
+    Save(String),
+    UserLoad(String),
+    UserDelete(String),
+    FactoryLoad(FactoryPreset),
+    Continue,
+    Close,
+}
+
+/// モジュールのプリセット ユーザーのものとプラグイン付属のもの
+/// 選ぶとすぐ読み込むので閉じるまで聴き比べられる
+pub struct PresetSelectView {
+    focus_p: bool,
+    plugin_id: String,
+    module_name: String,
+    buffer: String,
+    save_buffer: String,
+    user_presets: Vec<String>,
+    presets_version: usize,
+}
+
+impl PresetSelectView {
+    pub fn new(plugin_id: String, module_name: String, presets_version: usize) -> Self {
+        Self {
+            focus_p: true,
+            user_presets: plugin_preset::names(&plugin_id),
+            plugin_id,
+            module_name,
+            buffer: "".to_string(),
+            save_buffer: "".to_string(),
+            presets_version,
+        }
+    }
+
+    pub fn view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        factory_presets: &[FactoryPreset],
+        factory_presets_indexing_p: bool,
+        presets_version: usize,
+    ) -> Result<ReturnState> {
+        if self.presets_version != presets_version {
+            self.presets_version = presets_version;
+            self.user_presets = plugin_preset::names(&self.plugin_id);
+        }
+        CentralPanel::default()
+            .show(gui_context, |ui: &mut Ui| -> Result<ReturnState> {
+                ui.heading(format!("{} Presets", self.module_name));
+
+                let response = ui.add(TextEdit::singleline(&mut self.buffer).hint_text("filter"));
+                if self.focus_p {
+                    self.focus_p = false;
+                    gui_context.memory_mut(|x| x.request_focus(response.id));
+                }
+                ui.separator();
+
+                let mut result = None;
+                ScrollArea::vertical()
+                    .max_height(ui.available_height() - 80.0)
+                    .show(ui, |ui| {
+                        ui.label(RichText::new("User").color(Color32::GRAY));
+                        for name in self
+                            .user_presets
+                            .iter()
+                            .filter(|x| is_subsequence_case_insensitive(x, &self.buffer))
+                        {
+                            let label = ui.selectable_label(false, name);
+                            if label.clicked() {
+                                result = Some(ReturnState::UserLoad(name.clone()));
+                            }
+                            label.context_menu(|ui| {
+                                if ui.button("Overwrite").clicked() {
+                                    result = Some(ReturnState::Save(name.clone()));
+                                    ui.close_menu();
+                                }
+                                if ui.button("Delete").clicked() {
+                                    result = Some(ReturnState::UserDelete(name.clone()));
+                                    ui.close_menu();
+                                }
+                            });
+                        }
+
+                        ui.separator();
+                        let heading = if factory_presets_indexing_p {
+                            "Factory (indexing...)"
+                        } else {
+                            "Factory"
+                        };
+                        ui.label(RichText::new(heading).color(Color32::GRAY));
+                        for preset in factory_presets
+                            .iter()
+                            .filter(|x| is_subsequence_case_insensitive(&x.name, &self.buffer))
+                        {
+                            let label = ui.selectable_label(false, &preset.name);
+                            let label = match &preset.location {
+                                Some(location) => label.on_hover_text(location),
+                                None => label,
+                            };
+                            if label.clicked() {
+                                result = Some(ReturnState::FactoryLoad(preset.clone()));
+                            }
+                        }
+                    });
+                if let Some(result) = result {
+                    return Ok(result);
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| -> Result<ReturnState> {
+                    let response = ui.add(
+                        TextEdit::singleline(&mut self.save_buffer)
+                            .hint_text("preset name")
+                            .desired_width(200.0),
+                    );
+                    let enter_p = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+                    if (ui.button("Save").clicked() || enter_p)
+                        && !self.save_buffer.trim().is_empty()
+                    {
+                        return Ok(ReturnState::Save(std::mem::take(&mut self.save_buffer)));
+                    }
+                    if ui.button("Close").clicked() || ui.input(|i| i.key_pressed(Key::Escape)) {
+                        return Ok(ReturnState::Close);
+                    }
+                    Ok(ReturnState::Continue)
+                })
+                .inner
+            })
+            .inner
+    }
+}
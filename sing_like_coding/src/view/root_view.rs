@@ -13,11 +13,16 @@ use crate::{
 };
 
 use super::{
+    analyzer_window::AnalyzerWindow,
+    audio_device_view::{self, AudioDeviceView},
     command_view::CommandView,
     eval_window::EvalWindow,
     main_view::MainView,
     param_select_view::ParamSelectView,
+    plugin_scan_view::{self, PluginScanView},
     plugin_select_view::{self, PluginSelectView},
+    preset_select_view::{self, PresetSelectView},
+    sampler_view::{self, SamplerView},
     select_view::{self, SelectItem, SelectView},
     shortcut_key::{shortcut_key, Modifier},
     sidechain_select_view::{self, SidechainSelectView},
@@ -26,10 +31,14 @@ use super::{
 #[derive(Debug)]
 pub enum Route {
     Track,
+    AudioDevice,
     Command,
     MidiDeviceInputSelect,
+    PluginScanResult,
     PluginSelect,
+    PresetSelect,
     ParamSelect,
+    Sampler,
     SidechainSelect,
 }
 
@@ -45,13 +54,18 @@ impl SelectItem for MidiPort {
 }
 
 pub struct RootView {
+    analyzer_window: AnalyzerWindow,
     eval_window: EvalWindow,
     shortcut_map: HashMap<(Modifier, Key), UiCommand>,
     main_view: MainView,
+    audio_device_view: Option<AudioDeviceView>,
     command_view: CommandView,
     midi_device_input_select_view: Option<SelectView<MidiPort>>,
     param_select_view: Option<ParamSelectView>,
+    plugin_scan_view: PluginScanView,
     plugin_select_view: Option<PluginSelectView>,
+    preset_select_view: Option<PresetSelectView>,
+    sampler_view: Option<SamplerView>,
     sidechain_select_view: Option<SidechainSelectView>,
 }
 
@@ -69,13 +83,18 @@ impl RootView {
         let shortcut_map: HashMap<_, _> = shortcut_map.into_iter().collect();
 
         Self {
+            analyzer_window: AnalyzerWindow::new(),
             eval_window: EvalWindow::new(),
             shortcut_map,
             main_view: MainView::new(),
+            audio_device_view: None,
             command_view: CommandView::new(),
             midi_device_input_select_view: None,
             param_select_view: None,
+            plugin_scan_view: PluginScanView::new(),
             plugin_select_view: None,
+            preset_select_view: None,
+            sampler_view: None,
             sidechain_select_view: None,
         }
     }
@@ -93,22 +112,125 @@ impl RootView {
             self.eval_window.view(gui_context, state)?;
         }
 
+        self.analyzer_window.view(gui_context, state)?;
+
         state.receive_from_communicator()?;
+        state.plugin_scan_receive();
+
+        // プラグインの activate しなおしが終わってからストリームを開始する
+        if std::mem::take(&mut state.device_start_request_p) {
+            if let Some(device) = device.as_mut().filter(|x| !x.start_p()) {
+                device.start()?;
+            }
+        }
 
         match &state.route {
             Route::Track => self.main_view.view(gui_context, state, device)?,
+            Route::AudioDevice => self.audio_device_view(gui_context, state, device)?,
             Route::Command => self.command_view.view(gui_context, state)?,
             Route::MidiDeviceInputSelect => {
                 self.midi_device_input_select_view(gui_context, state)?
             }
             Route::ParamSelect => self.param_select_view(gui_context, state)?,
+            Route::PluginScanResult => self.plugin_scan_view(gui_context, state)?,
             Route::PluginSelect => self.plugin_select_view(gui_context, state)?,
+            Route::PresetSelect => self.preset_select_view(gui_context, state)?,
+            Route::Sampler => self.sampler_view(gui_context, state)?,
             Route::SidechainSelect => self.sidechain_select_view(gui_context, state)?,
         }
 
         Ok(())
     }
 
+    fn audio_device_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+        device: &mut Option<Device>,
+    ) -> Result<()> {
+        let view = self
+            .audio_device_view
+            .get_or_insert_with(|| AudioDeviceView::new(&state.config));
+
+        match view.view(gui_context)? {
+            audio_device_view::ReturnState::Apply(config) => {
+                state.config = config;
+                state.config.save()?;
+                if let Some(device) = device.as_mut() {
+                    device.reopen(&state.config)?;
+                    state.audio_config_send(device.sample_rate(), device.max_frames_count())?;
+                }
+                self.audio_device_view = None;
+                state.route = Route::Track;
+            }
+            audio_device_view::ReturnState::Continue => {}
+            audio_device_view::ReturnState::Cancel => {
+                self.audio_device_view = None;
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
+    fn plugin_scan_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+    ) -> Result<()> {
+        match self.plugin_scan_view.view(
+            gui_context,
+            &state.plugin_scan_reports,
+            &state.config.clap_paths,
+            state.plugin_scanner.scanning_p(),
+        )? {
+            plugin_scan_view::ReturnState::Retry(path) => {
+                state.plugin_unblock(path)?;
+            }
+            plugin_scan_view::ReturnState::Rescan => {
+                state.plugin_scan()?;
+            }
+            plugin_scan_view::ReturnState::ClapPaths(clap_paths) => {
+                state.clap_paths_set(clap_paths)?;
+            }
+            plugin_scan_view::ReturnState::Continue => {}
+            plugin_scan_view::ReturnState::Close => {
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
+    fn sampler_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+    ) -> Result<()> {
+        let Some((module_index, sampler_state)) = &state.sampler_edit else {
+            state.route = Route::Track;
+            return Ok(());
+        };
+        let module_index = *module_index;
+        let view = self
+            .sampler_view
+            .get_or_insert_with(|| SamplerView::new(sampler_state.clone()));
+
+        match view.view(gui_context)? {
+            sampler_view::ReturnState::Apply(sampler_state) => {
+                state.sampler_state_set(module_index, &sampler_state)?;
+                self.sampler_view = None;
+                state.sampler_edit = None;
+                state.route = Route::Track;
+            }
+            sampler_view::ReturnState::Continue => {}
+            sampler_view::ReturnState::Cancel => {
+                self.sampler_view = None;
+                state.sampler_edit = None;
+                state.route = Route::Track;
+            }
+        }
+        Ok(())
+    }
+
     fn midi_device_input_select_view(
         &mut self,
         gui_context: &eframe::egui::Context,
@@ -199,9 +321,9 @@ impl RootView {
     ) -> Result<()> {
         let plugin_select_view = self
             .plugin_select_view
-            .get_or_insert_with(|| PluginSelectView::new());
+            .get_or_insert_with(|| PluginSelectView::new(state.claps_version));
 
-        match plugin_select_view.view(gui_context)? {
+        match plugin_select_view.view(gui_context, state.claps_version)? {
             plugin_select_view::ReturnState::Selected(description) => {
                 state.plugin_load(&description, !gui_context.input(|i| i.modifiers.shift))?;
                 self.plugin_select_view = None;
@@ -216,6 +338,50 @@ impl RootView {
         Ok(())
     }
 
+    fn preset_select_view(
+        &mut self,
+        gui_context: &eframe::egui::Context,
+        state: &mut AppState,
+    ) -> Result<()> {
+        let Some(module) = state
+            .preset_module_id
+            .and_then(|x| state.song.module_by_id(x))
+        else {
+            // 開いている間にモジュールが消された
+            self.preset_select_view = None;
+            state.presets_close();
+            return Ok(());
+        };
+        let view = self.preset_select_view.get_or_insert_with(|| {
+            PresetSelectView::new(
+                module.plugin_id.clone(),
+                module.name.clone(),
+                state.presets_version,
+            )
+        });
+
+        state.factory_presets_poll()?;
+        match view.view(
+            gui_context,
+            &state.factory_presets,
+            state.factory_presets_retry_at.is_some(),
+            state.presets_version,
+        )? {
+            preset_select_view::ReturnState::Save(name) => state.preset_save(name)?,
+            preset_select_view::ReturnState::UserLoad(name) => state.preset_user_load(&name)?,
+            preset_select_view::ReturnState::UserDelete(name) => state.preset_user_delete(&name)?,
+            preset_select_view::ReturnState::FactoryLoad(preset) => {
+                state.preset_factory_load(preset)?
+            }
+            preset_select_view::ReturnState::Continue => {}
+            preset_select_view::ReturnState::Close => {
+                self.preset_select_view = None;
+                state.presets_close();
+            }
+        }
+        Ok(())
+    }
+
     fn process_shortcut(
         &mut self,
         state: &mut AppState,
@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use anyhow::Result;
+use common::sampler::{PlayMode, SamplerState, Zone};
+use eframe::egui::{CentralPanel, DragValue, Grid, Key, ScrollArea, Ui};
+use rfd::FileDialog;
+
+pub enum ReturnState {
+    Apply(SamplerState),
+    Continue,
+    Cancel,
+}
+
+pub struct SamplerView {
+    sampler_state: SamplerState,
+}
+
+impl SamplerView {
+    pub fn new(sampler_state: SamplerState) -> Self {
+        Self { sampler_state }
+    }
+
+    pub fn view(&mut self, gui_context: &eframe::egui::Context) -> Result<ReturnState> {
+        CentralPanel::default()
+            .show(gui_context, |ui: &mut Ui| -> Result<ReturnState> {
+                ScrollArea::both().show(ui, |ui| {
+                    let mut delete_index = None;
+                    Grid::new("sampler_grid").striped(true).show(ui, |ui| {
+                        for text in [
+                            "File", "Key", "", "Root", "Vel", "", "Gain", "A", "D", "S", "R",
+                            "Loop", "", "", "One Shot", "Choke", "",
+                        ] {
+                            ui.label(text);
+                        }
+                        ui.end_row();
+
+                        for (zone_index, zone) in self.sampler_state.zones.iter_mut().enumerate() {
+                            view_zone(ui, zone);
+                            if ui.button("x").clicked() {
+                                delete_index = Some(zone_index);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                    if let Some(zone_index) = delete_index {
+                        self.sampler_state.zones.remove(zone_index);
+                    }
+                });
+
+                if ui.button("Add").clicked() {
+                    if let Some(paths) = FileDialog::new()
+                        .add_filter("Audio", &["wav", "flac"])
+                        .pick_files()
+                    {
+                        for path in paths {
+                            self.sampler_state
+                                .zones
+                                .push(Zone::new(path.to_string_lossy().to_string()));
+                        }
+                    }
+                }
+
+                ui.separator();
+
+                if ui.button("Apply").clicked() {
+                    return Ok(ReturnState::Apply(self.sampler_state.clone()));
+                }
+                if ui.button("Cancel").clicked() || ui.input(|i| i.key_pressed(Key::Escape)) {
+                    return Ok(ReturnState::Cancel);
+                }
+
+                Ok(ReturnState::Continue)
+            })
+            .inner
+    }
+}
+
+fn view_zone(ui: &mut Ui, zone: &mut Zone) {
+    let name = Path::new(&zone.path)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    ui.label(name).on_hover_text(&zone.path);
+
+    ui.add(DragValue::new(&mut zone.key_min).range(0..=127));
+    ui.add(DragValue::new(&mut zone.key_max).range(0..=127));
+    ui.add(DragValue::new(&mut zone.root_key).range(0..=127));
+    ui.add(DragValue::new(&mut zone.velocity_min).range(0.0..=127.0));
+    ui.add(DragValue::new(&mut zone.velocity_max).range(0.0..=127.0));
+    ui.add(
+        DragValue::new(&mut zone.gain)
+            .range(-60.0..=24.0)
+            .speed(0.1)
+            .suffix(" dB"),
+    );
+    for value in [&mut zone.attack, &mut zone.decay] {
+        ui.add(DragValue::new(value).range(0.0..=10.0).speed(0.001));
+    }
+    ui.add(
+        DragValue::new(&mut zone.sustain)
+            .range(0.0..=1.0)
+            .speed(0.01),
+    );
+    ui.add(
+        DragValue::new(&mut zone.release)
+            .range(0.0..=10.0)
+            .speed(0.001),
+    );
+
+    let mut loop_p = zone.loop_range.is_some();
+    ui.checkbox(&mut loop_p, "");
+    let (mut loop_start, mut loop_end) = zone.loop_range.unwrap_or((0, 0));
+    ui.add_enabled(loop_p, DragValue::new(&mut loop_start));
+    ui.add_enabled(loop_p, DragValue::new(&mut loop_end));
+    zone.loop_range = loop_p.then_some((loop_start, loop_end.max(loop_start)));
+
+    let mut one_shot_p = zone.mode == PlayMode::OneShot;
+    if ui.checkbox(&mut one_shot_p, "").changed() {
+        zone.mode = if one_shot_p {
+            PlayMode::OneShot
+        } else {
+            PlayMode::Gate
+        };
+    }
+
+    // 0 はグループなし
+    let mut choke_group = zone.choke_group.unwrap_or(0);
+    ui.add(DragValue::new(&mut choke_group).range(0..=16));
+    zone.choke_group = (choke_group != 0).then_some(choke_group);
+}
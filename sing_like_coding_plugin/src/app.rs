@@ -8,14 +8,14 @@ use common::protocol::PluginToMain;
 use crate::communicator::Communicator;
 use crate::manager::Manager;
 
-pub fn main() {
+pub fn main(pipe_name: String) {
     let (sender_to_loop, receiver_from_main) = channel();
     let (sender_to_main, receiver_from_loop) = channel();
     let mut plugin_host = Manager::new(sender_to_loop, receiver_from_loop).unwrap();
     log::debug!("$$$$$$$ before thread::spawn");
     tokio::spawn(async move {
         log::debug!("$$$$$$$ before receive_from_main_process");
-        receive_from_main_process(sender_to_main, receiver_from_main)
+        receive_from_main_process(pipe_name, sender_to_main, receiver_from_main)
             .await
             .unwrap();
     });
@@ -23,10 +23,12 @@ pub fn main() {
 }
 
 async fn receive_from_main_process(
+    pipe_name: String,
     sender_to_main: Sender<MainToPlugin>,
     receiver_from_main: Receiver<PluginToMain>,
 ) -> anyhow::Result<()> {
-    let mut main_comminicator = Communicator::new(sender_to_main, receiver_from_main).await?;
+    let mut main_comminicator =
+        Communicator::new(&pipe_name, sender_to_main, receiver_from_main).await?;
     main_comminicator.run().await?;
 
     Ok(())
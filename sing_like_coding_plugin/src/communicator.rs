@@ -1,9 +1,6 @@
 use std::sync::mpsc::{Receiver, Sender};
 
-use common::{
-    protocol::{receive, send, MainToPlugin, PluginToMain},
-    PIPE_CTRL_NAME,
-};
+use common::protocol::{receive, send, MainToPlugin, PluginToMain};
 use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
 
 pub struct Communicator {
@@ -14,10 +11,11 @@ pub struct Communicator {
 
 impl Communicator {
     pub async fn new(
+        pipe_name: &str,
         sender_to_main: Sender<MainToPlugin>,
         receiver_from_main: Receiver<PluginToMain>,
     ) -> anyhow::Result<Self> {
-        let pipe = ClientOptions::new().open(PIPE_CTRL_NAME)?;
+        let pipe = ClientOptions::new().open(pipe_name)?;
 
         Ok(Self {
             pipe,
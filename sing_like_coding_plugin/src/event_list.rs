@@ -53,16 +53,20 @@ impl EventListInput {
     }
 
     pub fn midi(&mut self, value: u8, time: u32) {
+        self.midi_data([value, 0, 0], time);
+    }
+
+    pub fn midi_data(&mut self, data: [u8; 3], time: u32) {
         let event = Box::new(clap_event_midi {
             header: clap_event_header {
-                size: size_of::<clap_event_note>() as u32,
+                size: size_of::<clap_event_midi>() as u32,
                 time,
                 space_id: CLAP_CORE_EVENT_SPACE_ID,
                 type_: CLAP_EVENT_MIDI,
                 flags: 0,
             },
             port_index: 0,
-            data: [value, 0, 0],
+            data,
         });
         self.events
             .push(Box::into_raw(event) as *const clap_event_header);
@@ -213,7 +217,8 @@ impl EventListOutput {
             }
             CLAP_EVENT_NOTE_OFF => {
                 let event_note: &clap_event_note = unsafe { &*(event as *const clap_event_note) };
-                this.events.push(Event::NoteOff(event_note.key, delay))
+                this.events
+                    .push(Event::NoteOff(event_note.key, event_note.velocity, delay))
             }
             CLAP_EVENT_NOTE_CHOKE => {}
             CLAP_EVENT_NOTE_END => {}
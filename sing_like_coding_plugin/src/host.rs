@@ -1,8 +1,13 @@
-use std::{path::Path, pin::Pin, sync::mpsc::Sender};
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::{mpsc::Sender, Arc, Mutex},
+};
 
 use anyhow::Result;
 use common::{
-    plugin::{description::Description, param::Param},
+    channel_layout::ChannelLayout,
+    plugin::{description::Description, note_name::NoteName, param::Param, preset::FactoryPreset},
     process_data::ProcessData,
     shmem::{
         event_quit_name, event_request_name, event_response_name, open_shared_memory,
@@ -19,11 +24,18 @@ use windows::Win32::{
     },
 };
 
-use crate::{manager::EVENT_QUIT_ALL_NAME, plugin::Plugin, plugin_ptr::PluginPtr};
+use crate::{
+    manager::event_quit_all_name, plugin::Plugin, plugin_ptr::PluginPtr, sampler::Sampler,
+};
+
+pub enum Processor {
+    Plugin(Pin<Box<Plugin>>),
+    Sampler(Arc<Mutex<Sampler>>),
+}
 
 pub struct Host {
     event_quit: HANDLE,
-    pub plugin: Pin<Box<Plugin>>,
+    pub processor: Processor,
 }
 
 impl Host {
@@ -33,6 +45,7 @@ impl Host {
         sender: Sender<PluginPtr>,
         gui_open_p: bool,
         hwnd: isize,
+        audio_config: (f64, u32, u32),
     ) -> Result<Self> {
         let (event_quit_name, _x) = event_quit_name(id);
         let event_quit =
@@ -40,6 +53,8 @@ impl Host {
 
         let mut plugin = Plugin::new(sender, hwnd);
         plugin.load(Path::new(&description.path), description.index);
+        let (sample_rate, min_frames_count, max_frames_count) = audio_config;
+        plugin.audio_config_set(sample_rate, min_frames_count, max_frames_count)?;
         plugin.start()?;
         if gui_open_p {
             plugin.gui_open()?;
@@ -47,22 +62,126 @@ impl Host {
 
         let plugin_ptr: PluginPtr = (&mut plugin).into();
         tokio::spawn(async move {
-            process_loop(id, plugin_ptr).await.unwrap();
+            process_loop(id, move |process_data| {
+                let plugin = unsafe { plugin_ptr.as_mut() };
+                plugin.process(process_data)
+            })
+            .await
+            .unwrap();
         });
 
-        Ok(Self { event_quit, plugin })
+        Ok(Self {
+            event_quit,
+            processor: Processor::Plugin(plugin),
+        })
+    }
+
+    pub fn new_sampler(id: usize, audio_config: (f64, u32, u32)) -> Result<Self> {
+        let (event_quit_name, _x) = event_quit_name(id);
+        let event_quit =
+            unsafe { CreateEventA(None, false.into(), false.into(), event_quit_name)? };
+
+        let sampler = Arc::new(Mutex::new(Sampler::new(audio_config.0)));
+        let sampler_process = sampler.clone();
+        tokio::spawn(async move {
+            process_loop(id, move |process_data| {
+                sampler_process.lock().unwrap().process(process_data)
+            })
+            .await
+            .unwrap();
+        });
+
+        Ok(Self {
+            event_quit,
+            processor: Processor::Sampler(sampler),
+        })
+    }
+
+    pub fn audio_config_set(
+        &mut self,
+        sample_rate: f64,
+        min_frames_count: u32,
+        max_frames_count: u32,
+    ) -> Result<()> {
+        match &mut self.processor {
+            Processor::Plugin(plugin) => {
+                plugin.audio_config_set(sample_rate, min_frames_count, max_frames_count)
+            }
+            Processor::Sampler(sampler) => {
+                Sampler::audio_config_set(sampler, sample_rate);
+                Ok(())
+            }
+        }
+    }
+
+    /// サンプラーはいまのところステレオだけ
+    pub fn channel_layout_set(&mut self, layout: ChannelLayout) -> Result<()> {
+        match &mut self.processor {
+            Processor::Plugin(plugin) => plugin.channel_layout_set(layout),
+            Processor::Sampler(_) => Ok(()),
+        }
+    }
+
+    /// サンプラーの画面はメイン側にある
+    pub fn gui_toggle(&mut self) -> Result<()> {
+        match &mut self.processor {
+            Processor::Plugin(plugin) => {
+                if plugin.gui_open_p {
+                    plugin.gui_close()
+                } else {
+                    plugin.gui_open()
+                }
+            }
+            Processor::Sampler(_) => Ok(()),
+        }
     }
 
     pub fn latency(&self) -> u32 {
-        self.plugin.latency().unwrap_or(0)
+        match &self.processor {
+            Processor::Plugin(plugin) => plugin.latency().unwrap_or(0),
+            Processor::Sampler(_) => 0,
+        }
     }
 
     pub fn load(&mut self, state: Vec<u8>) -> Result<()> {
-        self.plugin.state_load(state)
+        match &mut self.processor {
+            Processor::Plugin(plugin) => plugin.state_load(state),
+            Processor::Sampler(sampler) => Sampler::state_load(sampler, state),
+        }
+    }
+
+    pub fn note_names(&mut self) -> Result<Vec<NoteName>> {
+        match &mut self.processor {
+            Processor::Plugin(plugin) => plugin.note_names(),
+            Processor::Sampler(_) => Ok(vec![]),
+        }
     }
 
     pub fn params(&mut self) -> Result<Vec<Param>> {
-        self.plugin.params()
+        match &mut self.processor {
+            Processor::Plugin(plugin) => plugin.params(),
+            Processor::Sampler(_) => Ok(vec![]),
+        }
+    }
+
+    pub fn plugin_id(&self) -> Option<String> {
+        match &self.processor {
+            Processor::Plugin(plugin) => plugin.id(),
+            Processor::Sampler(_) => None,
+        }
+    }
+
+    pub fn preset_load(&mut self, preset: &FactoryPreset) -> Result<bool> {
+        match &mut self.processor {
+            Processor::Plugin(plugin) => plugin.preset_load(preset),
+            Processor::Sampler(_) => Ok(false),
+        }
+    }
+
+    pub fn timers_run(&mut self) {
+        if let Processor::Plugin(plugin) = &mut self.processor {
+            plugin.timers_run();
+        }
     }
 
     pub fn unload(&mut self) -> Result<()> {
@@ -71,11 +190,17 @@ impl Host {
     }
 
     pub fn save(&mut self) -> Result<Vec<u8>> {
-        self.plugin.state_save()
+        match &mut self.processor {
+            Processor::Plugin(plugin) => plugin.state_save(),
+            Processor::Sampler(sampler) => sampler.lock().unwrap().state_save(),
+        }
     }
 }
 
-async fn process_loop(id: usize, plugin_ptr: PluginPtr) -> Result<()> {
+async fn process_loop(
+    id: usize,
+    mut process: impl FnMut(&mut ProcessData) -> Result<()>,
+) -> Result<()> {
     let shmem = open_shared_memory::<ProcessData>(&process_data_name(id))?;
     let process_data: &mut ProcessData = unsafe { &mut *(shmem.as_ptr() as *mut ProcessData) };
 
@@ -97,7 +222,7 @@ async fn process_loop(id: usize, plugin_ptr: PluginPtr) -> Result<()> {
         )?
     };
 
-    let (event_quit_all_name, _x) = to_pcstr(EVENT_QUIT_ALL_NAME)?;
+    let (event_quit_all_name, _x) = to_pcstr(&event_quit_all_name())?;
     let event_quit_all = unsafe {
         OpenEventA(
             SYNCHRONIZATION_ACCESS_RIGHTS(SYNCHRONIZE.0),
@@ -111,12 +236,11 @@ async fn process_loop(id: usize, plugin_ptr: PluginPtr) -> Result<()> {
     let (event_name, _x) = event_response_name(id);
     let event_response = unsafe { OpenEventA(EVENT_MODIFY_STATE, false, event_name)? };
 
-    let plugin = unsafe { plugin_ptr.as_mut() };
     loop {
         // log::debug!("$$$$ host will WaitForSingleObject process request");
         let event = unsafe { WaitForMultipleObjects(&events_wait, false.into(), INFINITE) };
         if event == WAIT_OBJECT_0 {
-            plugin.process(process_data)?;
+            process(process_data)?;
             unsafe { SetEvent(event_response) }?;
         } else if event == WAIT_EVENT(1) || event == WAIT_EVENT(2) {
             return Ok(());
@@ -5,3 +5,4 @@ mod host;
 mod manager;
 mod plugin;
 mod plugin_ptr;
+mod sampler;
@@ -2,7 +2,20 @@
 async fn main() -> tokio::io::Result<()> {
     unsafe { std::env::set_var("RUST_LOG", "sing_like_coding_plugin=debug") };
     env_logger::init();
+    let args = std::env::args().collect::<Vec<_>>();
+    // スキャン用の子プロセス
+    if args.get(1).map(|x| x.as_str()) == Some(common::clap_manager::SCAN_ARG) {
+        if let Some(path) = args.get(2) {
+            common::clap_manager::scan_and_print(std::path::Path::new(path));
+        }
+        return Ok(());
+    }
     log::debug!("Start sing like coding plugin...");
-    sing_like_coding_plugin::app::main();
+    // モジュール専用のプロセスのときはパイプの名前が渡される
+    let pipe_name = args
+        .get(1)
+        .cloned()
+        .unwrap_or(common::PIPE_CTRL_NAME.to_string());
+    sing_like_coding_plugin::app::main(pipe_name);
     Ok(())
 }
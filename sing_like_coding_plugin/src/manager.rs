@@ -1,14 +1,17 @@
 use std::{
     collections::HashMap,
+    path::Path,
     sync::mpsc::{channel, Receiver, Sender},
     thread::{self, sleep},
     time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use common::{
     clap_manager::ClapManager,
+    process_data::MAX_FRAMES,
     protocol::{MainToPlugin, PluginToMain},
+    sampler::SAMPLER_PLUGIN_ID,
     str::to_pcstr,
 };
 use windows::Win32::{
@@ -20,7 +23,7 @@ use windows::Win32::{
 };
 use windows::Win32::{System::Threading::GetCurrentThreadId, UI::WindowsAndMessaging::PM_REMOVE};
 
-use crate::{host::Host, plugin_ptr::PluginPtr};
+use crate::{host::Host, plugin::preset_discovery::PresetIndex, plugin_ptr::PluginPtr};
 
 pub struct Manager {
     sender_to_loop: Sender<PluginToMain>,
@@ -30,18 +33,26 @@ pub struct Manager {
     event_quit_all: HANDLE,
     hosts: HashMap<usize, Host>,
     clap_manager: ClapManager,
+    preset_index: PresetIndex,
     hwnd: isize,
+    // (sample_rate, min_frames_count, max_frames_count)
+    audio_config: (f64, u32, u32),
 }
 
 pub const EVENT_QUIT_ALL_NAME: &str = "SingLikeCoding.Plugin.Quit.All";
 
+/// モジュール専用のプロセスもあるので、ほかのプロセスの process_loop まで止めないようにプロセスごとに分ける
+pub fn event_quit_all_name() -> String {
+    format!("{}.{}", EVENT_QUIT_ALL_NAME, std::process::id())
+}
+
 impl Manager {
     pub fn new(
         sender_to_loop: Sender<PluginToMain>,
         receiver_from_loop: Receiver<MainToPlugin>,
     ) -> anyhow::Result<Self> {
         let (sender_from_plugin, receiver_from_plugin) = channel();
-        let (event_quit_all_name, _x) = to_pcstr(EVENT_QUIT_ALL_NAME)?;
+        let (event_quit_all_name, _x) = to_pcstr(&event_quit_all_name())?;
         let event_quit_all = unsafe {
             CreateEventA(
                 None,
@@ -59,7 +70,9 @@ impl Manager {
             event_quit_all,
             hosts: Default::default(),
             clap_manager: ClapManager::new(),
+            preset_index: PresetIndex::new(),
             hwnd: 0,
+            audio_config: (48000.0, 1, MAX_FRAMES as u32),
         })
     }
 
@@ -74,24 +87,33 @@ impl Manager {
                         self.hwnd = hwnd;
                         self.sender_to_loop.send(PluginToMain::DidHwnd)?;
                     }
+                    MainToPlugin::AudioConfig(sample_rate, min_frames_count, max_frames_count) => {
+                        self.audio_config = (sample_rate, min_frames_count, max_frames_count);
+                        for host in self.hosts.values_mut() {
+                            host.audio_config_set(sample_rate, min_frames_count, max_frames_count)?;
+                        }
+                        self.sender_to_loop.send(PluginToMain::DidAudioConfig)?;
+                    }
                     MainToPlugin::Load(id, clap_id, gui_open_p, state) => {
                         log::debug!("will load {id}");
-                        let description = self.clap_manager.description(&clap_id).unwrap();
-                        let mut host = Host::new(
-                            id,
-                            description,
-                            self.sender_from_plugin.clone(),
-                            gui_open_p,
-                            self.hwnd,
-                        )?;
-                        let latency = host.latency();
-                        if let Some(state) = state {
-                            host.load(state)?;
-                        }
-                        self.hosts.insert(id, host);
+                        // ないプラグインで落ちると立ち上げ直しを繰り返すので返事で知らせる
+                        match self.host_new(id, &clap_id, gui_open_p) {
+                            Ok(mut host) => {
+                                let latency = host.latency();
+                                if let Some(state) = state {
+                                    host.load(state)?;
+                                }
+                                self.hosts.insert(id, host);
 
-                        self.sender_to_loop
-                            .send(PluginToMain::DidLoad(id, latency))?;
+                                self.sender_to_loop
+                                    .send(PluginToMain::DidLoad(id, latency))?;
+                            }
+                            Err(e) => {
+                                log::error!("failed to load {clap_id} {:?}", e);
+                                self.sender_to_loop
+                                    .send(PluginToMain::LoadFailed(id, e.to_string()))?;
+                            }
+                        }
                     }
                     MainToPlugin::Unload(id) => {
                         if let Some(host) = self.host(id) {
@@ -100,13 +122,15 @@ impl Manager {
                         }
                         self.sender_to_loop.send(PluginToMain::DidUnload(id))?;
                     }
+                    MainToPlugin::ChannelLayout(id, layout) => {
+                        if let Some(host) = self.host(id) {
+                            host.channel_layout_set(layout)?;
+                        }
+                        self.sender_to_loop.send(PluginToMain::DidChannelLayout)?;
+                    }
                     MainToPlugin::GuiOpen(id) => {
                         if let Some(host) = self.host(id) {
-                            if host.plugin.gui_open_p {
-                                host.plugin.gui_close()?;
-                            } else {
-                                host.plugin.gui_open()?;
-                            }
+                            host.gui_toggle()?;
                         }
                         self.sender_to_loop.send(PluginToMain::DidGuiOpen)?;
                     }
@@ -117,6 +141,14 @@ impl Manager {
                         }
                         self.sender_to_loop.send(PluginToMain::DidParams(params))?;
                     }
+                    MainToPlugin::NoteNames(id) => {
+                        let mut note_names = vec![];
+                        if let Some(host) = self.host(id) {
+                            note_names = host.note_names()?;
+                        }
+                        self.sender_to_loop
+                            .send(PluginToMain::DidNoteNames(id, note_names))?;
+                    }
                     MainToPlugin::StateLoad(id, state) => {
                         if let Some(host) = self.host(id) {
                             host.load(state)?;
@@ -132,11 +164,36 @@ impl Manager {
                         self.sender_to_loop
                             .send(PluginToMain::DidStateSave(id, state))?;
                     }
-                    MainToPlugin::Scan => {
-                        log::debug!("clap_manager.scan() start...");
-                        self.clap_manager.scan();
-                        log::debug!("clap_manager.scan() end");
-                        self.sender_to_loop.send(PluginToMain::DidScan)?;
+                    MainToPlugin::Presets(id) => {
+                        let plugin_id = self.host(id).and_then(|host| host.plugin_id());
+                        let presets = match plugin_id.as_deref().and_then(|plugin_id| {
+                            self.clap_manager
+                                .description(plugin_id)
+                                .map(|x| (plugin_id, x.path.clone()))
+                        }) {
+                            Some((plugin_id, path)) => {
+                                self.preset_index.presets(plugin_id, Path::new(&path))
+                            }
+                            None => Some(vec![]),
+                        };
+                        self.sender_to_loop
+                            .send(PluginToMain::DidPresets(presets))?;
+                    }
+                    MainToPlugin::PresetLoad(id, preset) => {
+                        let mut loaded_p = false;
+                        if let Some(host) = self.host(id) {
+                            loaded_p = host.preset_load(&preset)?;
+                        }
+                        self.sender_to_loop
+                            .send(PluginToMain::DidPresetLoad(loaded_p))?;
+                    }
+                    MainToPlugin::Restart => {
+                        // メイン側で処理するのでここには来ない
+                        self.sender_to_loop.send(PluginToMain::DidRestart)?;
+                    }
+                    MainToPlugin::Isolate(_) => {
+                        // メイン側で処理するのでここには来ない
+                        self.sender_to_loop.send(PluginToMain::DidIsolate)?;
                     }
                     MainToPlugin::Quit => {
                         log::debug!("$$$$ quit");
@@ -156,6 +213,10 @@ impl Manager {
                 log::debug!("did on_main_thread");
             }
 
+            for host in self.hosts.values_mut() {
+                host.timers_run();
+            }
+
             unsafe {
                 while PeekMessageW(&mut win_msg, None, 0, 0, PM_REMOVE).as_bool() {
                     let _ = TranslateMessage(&win_msg);
@@ -169,6 +230,28 @@ impl Manager {
         }
     }
 
+    fn host_new(&mut self, id: usize, clap_id: &str, gui_open_p: bool) -> Result<Host> {
+        if clap_id == SAMPLER_PLUGIN_ID {
+            return Host::new_sampler(id, self.audio_config);
+        }
+        // メイン側のスキャンで claps.json が変わっているかもしれない
+        if self.clap_manager.description(clap_id).is_none() {
+            let _ = self.clap_manager.load();
+        }
+        let description = self
+            .clap_manager
+            .description(clap_id)
+            .ok_or_else(|| anyhow!("{} is not installed or was not scanned", clap_id))?;
+        Host::new(
+            id,
+            description,
+            self.sender_from_plugin.clone(),
+            gui_open_p,
+            self.hwnd,
+            self.audio_config,
+        )
+    }
+
     fn host(&mut self, id: usize) -> Option<&mut Host> {
         self.hosts.get_mut(&id)
     }
@@ -4,7 +4,12 @@ use std::{
     path::Path,
     pin::Pin,
     ptr::{null, null_mut},
-    sync::mpsc::Sender,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    thread::ThreadId,
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
@@ -22,6 +27,9 @@ use clap_sys::{
             clap_audio_port_info, clap_host_audio_ports, clap_plugin_audio_ports,
             CLAP_EXT_AUDIO_PORTS,
         },
+        audio_ports_config::{
+            clap_audio_ports_config, clap_plugin_audio_ports_config, CLAP_EXT_AUDIO_PORTS_CONFIG,
+        },
         gui::{
             clap_host_gui, clap_plugin_gui, clap_window, clap_window_handle, CLAP_EXT_GUI,
             CLAP_WINDOW_API_WIN32,
@@ -31,11 +39,24 @@ use clap_sys::{
             clap_host_log, clap_log_severity, CLAP_EXT_LOG, CLAP_LOG_DEBUG, CLAP_LOG_ERROR,
             CLAP_LOG_INFO, CLAP_LOG_WARNING,
         },
+        note_name::{
+            clap_host_note_name, clap_note_name, clap_plugin_note_name, CLAP_EXT_NOTE_NAME,
+        },
+        note_ports::{
+            clap_host_note_ports, clap_note_port_info, clap_plugin_note_ports, CLAP_EXT_NOTE_PORTS,
+            CLAP_NOTE_DIALECT_CLAP, CLAP_NOTE_DIALECT_MIDI,
+        },
         params::{
             clap_host_params, clap_param_clear_flags, clap_param_info, clap_param_rescan_flags,
             clap_plugin_params, CLAP_EXT_PARAMS,
         },
+        preset_load::{clap_host_preset_load, clap_plugin_preset_load, CLAP_EXT_PRESET_LOAD},
         state::{clap_plugin_state, CLAP_EXT_STATE},
+        thread_check::{clap_host_thread_check, CLAP_EXT_THREAD_CHECK},
+        thread_pool::{clap_host_thread_pool, clap_plugin_thread_pool, CLAP_EXT_THREAD_POOL},
+        timer_support::{
+            clap_host_timer_support, clap_plugin_timer_support, CLAP_EXT_TIMER_SUPPORT,
+        },
     },
     factory::plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID},
     host::clap_host,
@@ -45,9 +66,11 @@ use clap_sys::{
     version::{clap_version_is_compatible, CLAP_VERSION},
 };
 use common::{
+    channel_layout::ChannelLayout,
+    clap_manager::clap_binary_path,
     cstr,
-    plugin::param::Param,
-    process_data::{EventKind, ProcessData, MAX_PORTS},
+    plugin::{note_name::NoteName, param::Param, preset::FactoryPreset},
+    process_data::{EventKind, ProcessData, MAX_CHANNELS, MAX_FRAMES, MAX_PORTS},
 };
 use libloading::{Library, Symbol};
 use stream::{IStream, OStream};
@@ -58,7 +81,9 @@ use crate::{
     plugin_ptr::PluginPtr,
 };
 
+pub mod preset_discovery;
 mod stream;
+mod thread;
 mod window;
 
 pub struct Plugin {
@@ -66,30 +91,61 @@ pub struct Plugin {
     lib: Option<Library>,
     pub plugin: *const clap_plugin,
     ext_audio_ports: Option<*const clap_plugin_audio_ports>,
+    ext_audio_ports_config: Option<*const clap_plugin_audio_ports_config>,
     ext_gui: Option<*const clap_plugin_gui>,
     ext_latency: Option<*const clap_plugin_latency>,
+    ext_note_name: Option<*const clap_plugin_note_name>,
+    ext_note_ports: Option<*const clap_plugin_note_ports>,
     ext_params: Option<*const clap_plugin_params>,
+    ext_preset_load: Option<*const clap_plugin_preset_load>,
     ext_state: Option<*const clap_plugin_state>,
+    ext_thread_pool: Option<*const clap_plugin_thread_pool>,
+    ext_timer_support: Option<*const clap_plugin_timer_support>,
     pub gui_open_p: bool,
     window_handler: Option<*mut c_void>,
     process_start_p: bool,
+    sample_rate: f64,
+    min_frames_count: u32,
+    max_frames_count: u32,
     sender_to_view: Sender<PluginPtr>,
     audio_port_info_inputs: Vec<clap_audio_port_info>,
     audio_port_info_outputs: Vec<clap_audio_port_info>,
+    // ノートの入力ポートが CLAP のノートを受け付けないので MIDI で送る
+    // note-ports の rescan はメインスレッドから来るので process と取り合わないように
+    note_midi_p: AtomicBool,
+    // note-name の changed から次の process でメイン側に知らせる
+    note_names_changed_p: AtomicBool,
     event_list_input: Pin<Box<EventListInput>>,
     event_list_output: Pin<Box<EventListOutput>>,
     host_audio_ports: clap_host_audio_ports,
     host_gui: clap_host_gui,
     host_latency: clap_host_latency,
     host_log: clap_host_log,
+    host_note_name: clap_host_note_name,
+    host_note_ports: clap_host_note_ports,
     host_params: clap_host_params,
+    host_preset_load: clap_host_preset_load,
+    host_thread_check: clap_host_thread_check,
+    host_thread_pool: clap_host_thread_pool,
+    host_timer_support: clap_host_timer_support,
     hwnd: isize,
+    // Manager::run のスレッド
+    main_thread_id: ThreadId,
+    timers: Vec<Timer>,
+    timer_id_next: clap_id,
     params: BTreeMap<clap_id, Param>,
 
     next_clock_sample: f64,
     play_p: bool,
 }
 
+/// timer-support で登録されたタイマー Manager::run のループで回す
+struct Timer {
+    id: clap_id,
+    period: Duration,
+    next: Instant,
+}
+
 pub const NAME: &CStr = cstr!("Sing Like Coding");
 pub const VENDER: &CStr = cstr!("Sing Like Coding");
 pub const URL: &CStr = cstr!("https://github.com/quek/sing_like_coding");
@@ -131,35 +187,84 @@ impl Plugin {
             log: Some(Self::log_log),
         };
 
+        let host_note_name = clap_host_note_name {
+            changed: Some(Self::note_name_changed),
+        };
+
+        let host_note_ports = clap_host_note_ports {
+            supported_dialects: Some(Self::note_ports_supported_dialects),
+            rescan: Some(Self::note_ports_rescan),
+        };
+
         let host_params = clap_host_params {
             rescan: Some(Self::params_rescan),
             clear: Some(Self::params_clear),
             request_flush: Some(Self::params_request_flush),
         };
 
+        let host_preset_load = clap_host_preset_load {
+            on_error: Some(Self::preset_load_on_error),
+            loaded: Some(Self::preset_load_loaded),
+        };
+
+        let host_thread_check = clap_host_thread_check {
+            is_main_thread: Some(Self::thread_check_is_main_thread),
+            is_audio_thread: Some(Self::thread_check_is_audio_thread),
+        };
+
+        let host_thread_pool = clap_host_thread_pool {
+            request_exec: Some(Self::thread_pool_request_exec),
+        };
+
+        let host_timer_support = clap_host_timer_support {
+            register_timer: Some(Self::timer_support_register_timer),
+            unregister_timer: Some(Self::timer_support_unregister_timer),
+        };
+
         let mut this = Box::pin(Self {
             clap_host,
             lib: None,
             plugin: null(),
             ext_audio_ports: None,
+            ext_audio_ports_config: None,
             ext_gui: None,
             ext_latency: None,
+            ext_note_name: None,
+            ext_note_ports: None,
             ext_params: None,
+            ext_preset_load: None,
             ext_state: None,
+            ext_thread_pool: None,
+            ext_timer_support: None,
             gui_open_p: false,
             window_handler: None,
             process_start_p: false,
+            sample_rate: 48000.0,
+            // min_frames_count が 0 だと activate できないみたい
+            min_frames_count: 1,
+            max_frames_count: MAX_FRAMES as u32,
             sender_to_view,
             audio_port_info_inputs: vec![],
             audio_port_info_outputs: vec![],
+            note_midi_p: AtomicBool::new(false),
+            note_names_changed_p: AtomicBool::new(false),
             event_list_input: EventListInput::new(),
             event_list_output: EventListOutput::new(),
             host_audio_ports,
             host_gui,
             host_latency,
             host_log,
+            host_note_name,
+            host_note_ports,
             host_params,
+            host_preset_load,
+            host_thread_check,
+            host_thread_pool,
+            host_timer_support,
             hwnd,
+            main_thread_id: std::thread::current().id(),
+            timers: vec![],
+            timer_id_next: 0,
             params: Default::default(),
 
             next_clock_sample: 0.0,
@@ -234,6 +339,22 @@ impl Plugin {
         }
     }
 
+    unsafe extern "C" fn note_name_changed(host: *const clap_host) {
+        log::debug!("note_name_changed");
+        let this = unsafe { &*((*host).host_data as *const Self) };
+        this.note_names_changed_p.store(true, Ordering::Relaxed);
+    }
+
+    unsafe extern "C" fn note_ports_supported_dialects(_host: *const clap_host) -> u32 {
+        CLAP_NOTE_DIALECT_CLAP | CLAP_NOTE_DIALECT_MIDI
+    }
+
+    unsafe extern "C" fn note_ports_rescan(host: *const clap_host, _flags: u32) {
+        log::debug!("note_ports_rescan");
+        let this = unsafe { &*((*host).host_data as *const Self) };
+        this.note_ports();
+    }
+
     unsafe extern "C" fn params_rescan(host: *const clap_host, _flags: clap_param_rescan_flags) {
         log::debug!("params_rescan start");
         let this = unsafe { &mut *((*host).host_data as *mut Self) };
@@ -253,6 +374,95 @@ impl Plugin {
         log::debug!("params_request_flush");
     }
 
+    unsafe extern "C" fn preset_load_on_error(
+        _host: *const clap_host,
+        _location_kind: u32,
+        location: *const c_char,
+        load_key: *const c_char,
+        os_error: i32,
+        msg: *const c_char,
+    ) {
+        unsafe {
+            let to_string =
+                |s: *const c_char| (!s.is_null()).then(|| CStr::from_ptr(s).to_string_lossy());
+            log::error!(
+                "preset load error {:?} {:?} {} {:?}",
+                to_string(location),
+                to_string(load_key),
+                os_error,
+                to_string(msg)
+            );
+        }
+    }
+
+    unsafe extern "C" fn preset_load_loaded(
+        _host: *const clap_host,
+        _location_kind: u32,
+        _location: *const c_char,
+        _load_key: *const c_char,
+    ) {
+        log::debug!("preset loaded");
+    }
+
+    unsafe extern "C" fn thread_check_is_main_thread(host: *const clap_host) -> bool {
+        let this = unsafe { &*((*host).host_data as *const Self) };
+        std::thread::current().id() == this.main_thread_id
+    }
+
+    unsafe extern "C" fn thread_check_is_audio_thread(_host: *const clap_host) -> bool {
+        thread::audio_thread_p()
+    }
+
+    /// process の中からだけ受け付ける
+    unsafe extern "C" fn thread_pool_request_exec(host: *const clap_host, num_tasks: u32) -> bool {
+        let this = unsafe { &*((*host).host_data as *const Self) };
+        if !thread::audio_thread_p() {
+            return false;
+        }
+        let Some(exec) = this
+            .ext_thread_pool
+            .and_then(|thread_pool| unsafe { (*thread_pool).exec })
+        else {
+            return false;
+        };
+        thread::exec(this.plugin, exec, num_tasks);
+        true
+    }
+
+    unsafe extern "C" fn timer_support_register_timer(
+        host: *const clap_host,
+        period_ms: u32,
+        timer_id: *mut clap_id,
+    ) -> bool {
+        let this = unsafe { &mut *((*host).host_data as *mut Self) };
+        if timer_id.is_null() {
+            return false;
+        }
+        // ループが 60 Hz なのでそれより細かくはならない
+        let period = Duration::from_millis(period_ms.max(1) as u64);
+        let id = this.timer_id_next;
+        this.timer_id_next += 1;
+        this.timers.push(Timer {
+            id,
+            period,
+            next: Instant::now() + period,
+        });
+        unsafe { *timer_id = id };
+        log::debug!("register_timer {id} {period_ms}ms");
+        true
+    }
+
+    unsafe extern "C" fn timer_support_unregister_timer(
+        host: *const clap_host,
+        timer_id: clap_id,
+    ) -> bool {
+        let this = unsafe { &mut *((*host).host_data as *mut Self) };
+        let len = this.timers.len();
+        this.timers.retain(|x| x.id != timer_id);
+        log::debug!("unregister_timer {timer_id}");
+        this.timers.len() != len
+    }
+
     unsafe extern "C" fn request_callback(host: *const clap_host) {
         log::debug!("request_callback start...");
         let this = unsafe { &mut *((*host).host_data as *mut Self) };
@@ -295,16 +505,34 @@ impl Plugin {
             if id == CLAP_EXT_LOG {
                 return &host.host_log as *const _ as *const c_void;
             }
+            if id == CLAP_EXT_NOTE_NAME {
+                return &host.host_note_name as *const _ as *const c_void;
+            }
+            if id == CLAP_EXT_NOTE_PORTS {
+                return &host.host_note_ports as *const _ as *const c_void;
+            }
             if id == CLAP_EXT_PARAMS {
                 return &host.host_params as *const _ as *const c_void;
             }
+            if id == CLAP_EXT_PRESET_LOAD {
+                return &host.host_preset_load as *const _ as *const c_void;
+            }
+            if id == CLAP_EXT_THREAD_CHECK {
+                return &host.host_thread_check as *const _ as *const c_void;
+            }
+            if id == CLAP_EXT_THREAD_POOL {
+                return &host.host_thread_pool as *const _ as *const c_void;
+            }
+            if id == CLAP_EXT_TIMER_SUPPORT {
+                return &host.host_timer_support as *const _ as *const c_void;
+            }
             std::ptr::null()
         }
     }
 
     pub fn load(&mut self, path: &Path, index: u32) {
         unsafe {
-            let lib = Library::new(path).expect("Failed to load plugin");
+            let lib = Library::new(clap_binary_path(path)).expect("Failed to load plugin");
             self.lib = Some(lib);
             let entry: Symbol<*const clap_plugin_entry> = self
                 .lib
@@ -363,6 +591,13 @@ impl Plugin {
                 self.ext_audio_ports = Some(audio_ports);
             }
 
+            let audio_ports_config =
+                (plugin.get_extension.unwrap())(plugin, CLAP_EXT_AUDIO_PORTS_CONFIG.as_ptr())
+                    as *const clap_plugin_audio_ports_config;
+            if !audio_ports_config.is_null() {
+                self.ext_audio_ports_config = Some(audio_ports_config);
+            }
+
             let gui = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_GUI.as_ptr())
                 as *const clap_plugin_gui;
             if !gui.is_null() {
@@ -375,21 +610,53 @@ impl Plugin {
                 self.ext_latency = Some(latency);
             }
 
+            let note_name = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_NOTE_NAME.as_ptr())
+                as *const clap_plugin_note_name;
+            if !note_name.is_null() {
+                self.ext_note_name = Some(note_name);
+            }
+
+            let note_ports = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_NOTE_PORTS.as_ptr())
+                as *const clap_plugin_note_ports;
+            if !note_ports.is_null() {
+                self.ext_note_ports = Some(note_ports);
+            }
+
             let params = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_PARAMS.as_ptr())
                 as *const clap_plugin_params;
             if !params.is_null() {
                 self.ext_params = Some(params);
             }
 
+            let preset_load = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_PRESET_LOAD.as_ptr())
+                as *const clap_plugin_preset_load;
+            if !preset_load.is_null() {
+                self.ext_preset_load = Some(preset_load);
+            }
+
             let state = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_STATE.as_ptr())
                 as *const clap_plugin_state;
             if !state.is_null() {
                 self.ext_state = Some(state);
             }
 
+            let thread_pool = (plugin.get_extension.unwrap())(plugin, CLAP_EXT_THREAD_POOL.as_ptr())
+                as *const clap_plugin_thread_pool;
+            if !thread_pool.is_null() {
+                self.ext_thread_pool = Some(thread_pool);
+            }
+
+            let timer_support =
+                (plugin.get_extension.unwrap())(plugin, CLAP_EXT_TIMER_SUPPORT.as_ptr())
+                    as *const clap_plugin_timer_support;
+            if !timer_support.is_null() {
+                self.ext_timer_support = Some(timer_support);
+            }
+
             self.plugin = clap_plugin;
 
             self.audio_ports().unwrap();
+            self.note_ports();
             self.params().unwrap();
         }
     }
@@ -417,11 +684,12 @@ impl Plugin {
                     let mut info = std::mem::zeroed::<clap_audio_port_info>();
                     if get(self.plugin, i, is_input, &mut info) {
                         log::debug!(
-                            "{} {} {} {}ch",
+                            "{} {} {} {}ch {:?}",
                             if is_input { "入力" } else { "出力" },
                             i,
                             CStr::from_ptr(info.name.as_ptr()).to_string_lossy(),
-                            info.channel_count
+                            info.channel_count,
+                            (!info.port_type.is_null()).then(|| CStr::from_ptr(info.port_type))
                         );
                         xs.push(info);
                     } else {
@@ -451,6 +719,53 @@ impl Plugin {
         Ok(())
     }
 
+    /// layout に合う audio-ports-config があればそれにする
+    pub fn channel_layout_set(&mut self, layout: ChannelLayout) -> Result<()> {
+        let Some(ext_audio_ports_config) = self.ext_audio_ports_config else {
+            return Ok(());
+        };
+        let plugin = self.plugin;
+        let ext_audio_ports_config = unsafe { &*ext_audio_ports_config };
+        let (Some(count), Some(get), Some(select)) = (
+            ext_audio_ports_config.count,
+            ext_audio_ports_config.get,
+            ext_audio_ports_config.select,
+        ) else {
+            return Ok(());
+        };
+        let config_id = unsafe {
+            (0..count(plugin)).find_map(|index| {
+                let mut config = std::mem::zeroed::<clap_audio_ports_config>();
+                if !get(plugin, index, &mut config) || !config.has_main_output {
+                    return None;
+                }
+                let port_type = (!config.main_output_port_type.is_null())
+                    .then(|| CStr::from_ptr(config.main_output_port_type));
+                (ChannelLayout::from_port_type(
+                    port_type,
+                    config.main_output_channel_count as usize,
+                ) == Some(layout))
+                .then_some(config.id)
+            })
+        };
+        let Some(config_id) = config_id else {
+            log::debug!("audio ports config for {:?} not found", layout);
+            return Ok(());
+        };
+
+        // select は deactivate している間しか呼べない
+        let process_start_p = self.process_start_p;
+        self.stop()?;
+        if !unsafe { select(plugin, config_id) } {
+            log::warn!("audio ports config select {} failed", config_id);
+        }
+        self.audio_ports()?;
+        if process_start_p {
+            self.start()?;
+        }
+        Ok(())
+    }
+
     pub fn gui_available(&self) -> bool {
         if self.ext_gui.is_none() {
             return false;
@@ -570,6 +885,66 @@ impl Plugin {
         }
     }
 
+    pub fn note_names(&mut self) -> Result<Vec<NoteName>> {
+        let mut note_names = vec![];
+        let Some(ext_note_name) = self.ext_note_name else {
+            return Ok(note_names);
+        };
+        unsafe {
+            let ext_note_name = &*ext_note_name;
+            let (Some(count), Some(get)) = (ext_note_name.count, ext_note_name.get) else {
+                return Ok(note_names);
+            };
+            for i in 0..count(self.plugin) {
+                let mut note_name = std::mem::zeroed::<clap_note_name>();
+                if get(self.plugin, i, &mut note_name) {
+                    note_names.push(NoteName {
+                        name: CStr::from_ptr(note_name.name.as_ptr())
+                            .to_string_lossy()
+                            .to_string(),
+                        port: note_name.port,
+                        key: note_name.key,
+                        channel: note_name.channel,
+                    });
+                }
+            }
+        }
+        Ok(note_names)
+    }
+
+    /// 最初のノートの入力ポートに送るノートの形式を決める
+    pub fn note_ports(&self) {
+        let note_midi_p = self.note_midi_p_get();
+        self.note_midi_p.store(note_midi_p, Ordering::Relaxed);
+    }
+
+    fn note_midi_p_get(&self) -> bool {
+        let Some(ext_note_ports) = self.ext_note_ports else {
+            return false;
+        };
+        unsafe {
+            let ext_note_ports = &*ext_note_ports;
+            let (Some(count), Some(get)) = (ext_note_ports.count, ext_note_ports.get) else {
+                return false;
+            };
+            if count(self.plugin, true) == 0 {
+                return false;
+            }
+            let mut info = std::mem::zeroed::<clap_note_port_info>();
+            if !get(self.plugin, 0, true, &mut info) {
+                return false;
+            }
+            log::debug!(
+                "note port {} dialects {:#x} preferred {:#x}",
+                CStr::from_ptr(info.name.as_ptr()).to_string_lossy(),
+                info.supported_dialects,
+                info.preferred_dialect
+            );
+            info.supported_dialects & CLAP_NOTE_DIALECT_CLAP == 0
+                && info.supported_dialects & CLAP_NOTE_DIALECT_MIDI != 0
+        }
+    }
+
     pub fn params(&mut self) -> Result<Vec<Param>> {
         unsafe {
             let plugin = &*self.plugin;
@@ -614,13 +989,20 @@ impl Plugin {
     }
 
     pub fn process(&mut self, context: &mut ProcessData) -> Result<()> {
+        // thread-check と thread-pool のため
+        thread::audio_thread_set();
+        if self.note_names_changed_p.swap(false, Ordering::Relaxed) {
+            context.note_names_changed_p = 1;
+        }
         context.nports_in = self.audio_port_info_inputs.len().min(MAX_PORTS);
         context.nports_out = self.audio_port_info_outputs.len().min(MAX_PORTS);
         for port in 0..context.nports_in {
-            context.nchannels_in[port] = self.audio_port_info_inputs[port].channel_count as usize;
+            context.nchannels_in[port] =
+                (self.audio_port_info_inputs[port].channel_count as usize).min(MAX_CHANNELS);
         }
         for port in 0..context.nports_out {
-            context.nchannels_out[port] = self.audio_port_info_outputs[port].channel_count as usize;
+            context.nchannels_out[port] =
+                (self.audio_port_info_outputs[port].channel_count as usize).min(MAX_CHANNELS);
         }
 
         let mut audio_inputs = Vec::with_capacity(context.nports_in);
@@ -695,10 +1077,27 @@ impl Plugin {
             (context.sample_rate * 60.0) / (context.bpm * context.lpb as f64 * 256.0);
         self.event_list_output.samples_per_delay = samples_per_delay;
 
+        let note_midi_p = self.note_midi_p.load(Ordering::Relaxed);
         for i in 0..context.nevents_input {
             let event = &context.events_input[i];
             let delay = (event.delay as f64 * samples_per_delay).round() as u32;
             match &event.kind {
+                EventKind::NoteOn if note_midi_p => self.event_list_input.midi_data(
+                    [
+                        0x90 | event.channel.clamp(0, 15) as u8,
+                        event.key.clamp(0, 127) as u8,
+                        event.velocity.round().clamp(1.0, 127.0) as u8,
+                    ],
+                    delay,
+                ),
+                EventKind::NoteOff if note_midi_p => self.event_list_input.midi_data(
+                    [
+                        0x80 | event.channel.clamp(0, 15) as u8,
+                        event.key.clamp(0, 127) as u8,
+                        event.velocity.round().clamp(0.0, 127.0) as u8,
+                    ],
+                    delay,
+                ),
                 EventKind::NoteOn => {
                     self.event_list_input
                         .note_on(event.key, event.channel, event.velocity, delay)
@@ -768,8 +1167,8 @@ impl Plugin {
                 common::event::Event::NoteOn(key, velocity, delay) => {
                     context.output_note_on(*key, *velocity, 0, *delay);
                 }
-                common::event::Event::NoteOff(key, delay) => {
-                    context.output_note_off(*key, 0, *delay);
+                common::event::Event::NoteOff(key, velocity, delay) => {
+                    context.output_note_off(*key, *velocity, 0, *delay);
                 }
                 common::event::Event::NoteAllOff => { /* 無視 */ }
                 common::event::Event::ParamValue(_, param_id, value, delay) => {
@@ -917,21 +1316,46 @@ impl Plugin {
     //     Ok(())
     // }
 
+    /// 時間が来たタイマーの on_timer を呼ぶ メインスレッドから
+    pub fn timers_run(&mut self) {
+        let Some(on_timer) = self
+            .ext_timer_support
+            .and_then(|timer_support| unsafe { (*timer_support).on_timer })
+        else {
+            return;
+        };
+        let now = Instant::now();
+        let ids = self
+            .timers
+            .iter_mut()
+            .filter(|timer| timer.next <= now)
+            .map(|timer| {
+                // 遅れた分はまとめて 1 回にする
+                timer.next = now + timer.period;
+                timer.id
+            })
+            .collect::<Vec<_>>();
+        // on_timer の中で登録、解除されることがあるので集めてから呼ぶ
+        for id in ids {
+            if self.timers.iter().any(|x| x.id == id) {
+                unsafe { on_timer(self.plugin, id) };
+            }
+        }
+    }
+
     pub fn start(&mut self) -> Result<()> {
         if self.process_start_p {
             return Ok(());
         }
         let plugin = unsafe { &*self.plugin };
-        // let sample_rate = self.supported_stream_config.sample_rate().0 as f64;
-        // min_frames_count が 0 だと activate できないみたい
-        // let (min_frames_count, max_frames_count): (u32, u32) =
-        //     match self.supported_stream_config.buffer_size() {
-        //         cpal::SupportedBufferSize::Range { min, max } => (*min, *max),
-        //         cpal::SupportedBufferSize::Unknown => (64, 4096),
-        //     };
         unsafe {
             // TODO main-thread
-            plugin.activate.unwrap()(plugin, 48000.0, 64, 4096);
+            plugin.activate.unwrap()(
+                plugin,
+                self.sample_rate,
+                self.min_frames_count,
+                self.max_frames_count,
+            );
             // TODO audio-thread
             plugin.start_processing.unwrap()(plugin);
         };
@@ -939,6 +1363,23 @@ impl Plugin {
         Ok(())
     }
 
+    /// デバイスが変わったら activate しなおす
+    pub fn audio_config_set(
+        &mut self,
+        sample_rate: f64,
+        min_frames_count: u32,
+        max_frames_count: u32,
+    ) -> Result<()> {
+        self.sample_rate = sample_rate;
+        self.min_frames_count = min_frames_count.max(1);
+        self.max_frames_count = max_frames_count.max(self.min_frames_count);
+        if self.process_start_p {
+            self.stop()?;
+            self.start()?;
+        }
+        Ok(())
+    }
+
     pub fn stop(&mut self) -> Result<()> {
         if !self.process_start_p {
             return Ok(());
@@ -975,6 +1416,37 @@ impl Plugin {
         }
         Ok(ostream.into_inner())
     }
+
+    pub fn id(&self) -> Option<String> {
+        if self.plugin.is_null() {
+            return None;
+        }
+        unsafe {
+            Some(
+                CStr::from_ptr((*(*self.plugin).desc).id)
+                    .to_string_lossy()
+                    .to_string(),
+            )
+        }
+    }
+
+    pub fn preset_load(&mut self, preset: &FactoryPreset) -> anyhow::Result<bool> {
+        let Some(preset_load) = &self.ext_preset_load else {
+            return Ok(false);
+        };
+        let location = preset.location.clone().map(CString::new).transpose()?;
+        let load_key = preset.load_key.clone().map(CString::new).transpose()?;
+        unsafe {
+            let plugin = &*self.plugin;
+            let preset_load = &**preset_load;
+            Ok(preset_load.from_location.unwrap()(
+                plugin,
+                preset.location_kind,
+                location.as_ref().map_or(null(), |x| x.as_ptr()),
+                load_key.as_ref().map_or(null(), |x| x.as_ptr()),
+            ))
+        }
+    }
 }
 
 impl Drop for Plugin {
@@ -0,0 +1,396 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{c_char, c_void, CStr, CString},
+    fs,
+    path::{Path, PathBuf},
+    ptr::null,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+
+use clap_sys::{
+    entry::clap_plugin_entry,
+    factory::preset_discovery::{
+        clap_preset_discovery_factory, clap_preset_discovery_filetype,
+        clap_preset_discovery_indexer, clap_preset_discovery_location,
+        clap_preset_discovery_metadata_receiver, clap_preset_discovery_soundpack,
+        CLAP_PRESET_DISCOVERY_FACTORY_ID, CLAP_PRESET_DISCOVERY_LOCATION_FILE,
+        CLAP_PRESET_DISCOVERY_LOCATION_PLUGIN,
+    },
+    timestamp::clap_timestamp,
+    universal_plugin_id::clap_universal_plugin_id,
+    version::CLAP_VERSION,
+};
+use common::{clap_manager::clap_binary_path, plugin::preset::FactoryPreset};
+use libloading::{Library, Symbol};
+
+use super::{NAME, URL, VENDER, VERSION};
+
+/// プラグインの id ごとに 1 回だけ別スレッドで集めて覚えておく
+/// ファイルを全部読むプラグインもあるので Manager::run のスレッドではやらない
+pub struct PresetIndex {
+    presets: HashMap<String, Vec<FactoryPreset>>,
+    indexing: HashSet<String>,
+    sender: Sender<(String, Vec<FactoryPreset>)>,
+    receiver: Receiver<(String, Vec<FactoryPreset>)>,
+}
+
+impl PresetIndex {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            presets: Default::default(),
+            indexing: Default::default(),
+            sender,
+            receiver,
+        }
+    }
+
+    /// 集め終わっていなければ None
+    pub fn presets(&mut self, plugin_id: &str, path: &Path) -> Option<Vec<FactoryPreset>> {
+        while let Ok((plugin_id, presets)) = self.receiver.try_recv() {
+            self.indexing.remove(&plugin_id);
+            self.presets.insert(plugin_id, presets);
+        }
+        if let Some(presets) = self.presets.get(plugin_id) {
+            return Some(presets.clone());
+        }
+        if self.indexing.insert(plugin_id.to_string()) {
+            let plugin_id = plugin_id.to_string();
+            let path = path.to_path_buf();
+            let sender = self.sender.clone();
+            thread::spawn(move || {
+                let presets = index(&path, &plugin_id).unwrap_or_else(|e| {
+                    log::warn!("preset discovery {} {:?}", plugin_id, e);
+                    vec![]
+                });
+                let _ = sender.send((plugin_id, presets));
+            });
+        }
+        None
+    }
+}
+
+/// 読み込み中のプラグインとは別にライブラリを開くので、途中でプラグインが外されても困らない
+fn index(path: &Path, plugin_id: &str) -> Result<Vec<FactoryPreset>> {
+    unsafe {
+        let lib = Library::new(clap_binary_path(path))?;
+        let entry: Symbol<*const clap_plugin_entry> = lib.get(b"clap_entry\0")?;
+        let entry = &**entry;
+        let init = entry
+            .init
+            .ok_or_else(|| anyhow!("CLAP init function is missing"))?;
+        let c_path = CString::new(path.to_string_lossy().as_bytes())?;
+        if !init(c_path.as_ptr()) {
+            return Err(anyhow!("CLAP init failed"));
+        }
+        let presets = factory_presets(entry, plugin_id);
+        if let Some(deinit) = entry.deinit {
+            deinit();
+        }
+        Ok(presets)
+    }
+}
+
+/// provider の init で宣言されたもの
+struct Indexer {
+    /// 拡張子 空なら全部のファイル
+    extensions: Vec<String>,
+    /// (kind, location)
+    locations: Vec<(u32, Option<String>)>,
+}
+
+/// get_metadata 1 回分の受け取り
+struct Receiver {
+    location_kind: u32,
+    location: Option<String>,
+    /// (preset, 対応するプラグインの id)
+    presets: Vec<(FactoryPreset, Vec<String>)>,
+}
+
+/// プラグインの preset-discovery factory からプリセットを集める
+/// 別のプラグインのものは除く
+fn factory_presets(entry: &clap_plugin_entry, plugin_id: &str) -> Vec<FactoryPreset> {
+    let mut result = vec![];
+    unsafe {
+        let Some(get_factory) = entry.get_factory else {
+            return result;
+        };
+        let factory = get_factory(CLAP_PRESET_DISCOVERY_FACTORY_ID.as_ptr())
+            as *const clap_preset_discovery_factory;
+        if factory.is_null() {
+            return result;
+        }
+        let factory = &*factory;
+        let (Some(count), Some(get_descriptor), Some(create)) =
+            (factory.count, factory.get_descriptor, factory.create)
+        else {
+            return result;
+        };
+
+        for index in 0..count(factory) {
+            let descriptor = get_descriptor(factory, index);
+            if descriptor.is_null() {
+                continue;
+            }
+            let mut indexer_data = Indexer {
+                extensions: vec![],
+                locations: vec![],
+            };
+            let indexer = clap_preset_discovery_indexer {
+                clap_version: CLAP_VERSION,
+                name: NAME.as_ptr(),
+                vendor: VENDER.as_ptr(),
+                url: URL.as_ptr(),
+                version: VERSION.as_ptr(),
+                indexer_data: &mut indexer_data as *mut _ as *mut c_void,
+                declare_filetype: Some(declare_filetype),
+                declare_location: Some(declare_location),
+                declare_soundpack: Some(declare_soundpack),
+                get_extension: Some(indexer_get_extension),
+            };
+            let provider = create(factory, &indexer, (*descriptor).id);
+            if provider.is_null() {
+                continue;
+            }
+            let provider = &*provider;
+            if provider.init.is_some_and(|init| init(provider)) {
+                if let Some(get_metadata) = provider.get_metadata {
+                    for (kind, location) in indexer_data.locations.iter() {
+                        let files = match (kind, location) {
+                            (&CLAP_PRESET_DISCOVERY_LOCATION_FILE, Some(location)) => {
+                                preset_files(Path::new(location), &indexer_data.extensions)
+                                    .into_iter()
+                                    .map(|x| Some(x.to_string_lossy().to_string()))
+                                    .collect()
+                            }
+                            (&CLAP_PRESET_DISCOVERY_LOCATION_PLUGIN, _) => vec![None],
+                            _ => vec![],
+                        };
+                        for file in files {
+                            let mut receiver_data = Receiver {
+                                location_kind: *kind,
+                                location: file.clone(),
+                                presets: vec![],
+                            };
+                            let receiver = metadata_receiver(&mut receiver_data);
+                            let c_file = file.and_then(|x| CString::new(x).ok());
+                            let c_file_ptr = c_file.as_ref().map_or(null(), |x| x.as_ptr());
+                            if !get_metadata(provider, *kind, c_file_ptr, &receiver) {
+                                continue;
+                            }
+                            result.extend(receiver_data.presets.into_iter().filter_map(
+                                |(preset, plugin_ids)| {
+                                    // 宣言がなければこのプラグインのものとみなす
+                                    (plugin_ids.is_empty()
+                                        || plugin_ids.contains(&plugin_id.to_string()))
+                                    .then_some(preset)
+                                },
+                            ));
+                        }
+                    }
+                }
+            }
+            if let Some(destroy) = provider.destroy {
+                destroy(provider);
+            }
+        }
+    }
+    result
+}
+
+fn preset_files(path: &Path, extensions: &[String]) -> Vec<PathBuf> {
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    let mut result = vec![];
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                result.extend(preset_files(&path, extensions));
+            } else if extensions.is_empty()
+                || path
+                    .extension()
+                    .and_then(|x| x.to_str())
+                    .is_some_and(|x| extensions.iter().any(|e| e.eq_ignore_ascii_case(x)))
+            {
+                result.push(path);
+            }
+        }
+    }
+    result.sort();
+    result
+}
+
+unsafe fn to_string(s: *const c_char) -> Option<String> {
+    (!s.is_null()).then(|| unsafe { CStr::from_ptr(s) }.to_string_lossy().to_string())
+}
+
+unsafe extern "C" fn declare_filetype(
+    indexer: *const clap_preset_discovery_indexer,
+    filetype: *const clap_preset_discovery_filetype,
+) -> bool {
+    unsafe {
+        let this = &mut *((*indexer).indexer_data as *mut Indexer);
+        if let Some(extension) = to_string((*filetype).file_extension).filter(|x| !x.is_empty()) {
+            this.extensions
+                .push(extension.trim_start_matches('.').to_string());
+        }
+    }
+    true
+}
+
+unsafe extern "C" fn declare_location(
+    indexer: *const clap_preset_discovery_indexer,
+    location: *const clap_preset_discovery_location,
+) -> bool {
+    unsafe {
+        let this = &mut *((*indexer).indexer_data as *mut Indexer);
+        this.locations
+            .push(((*location).kind, to_string((*location).location)));
+    }
+    true
+}
+
+unsafe extern "C" fn declare_soundpack(
+    _indexer: *const clap_preset_discovery_indexer,
+    _soundpack: *const clap_preset_discovery_soundpack,
+) -> bool {
+    true
+}
+
+unsafe extern "C" fn indexer_get_extension(
+    _indexer: *const clap_preset_discovery_indexer,
+    _extension_id: *const c_char,
+) -> *const c_void {
+    null()
+}
+
+fn metadata_receiver(receiver_data: &mut Receiver) -> clap_preset_discovery_metadata_receiver {
+    clap_preset_discovery_metadata_receiver {
+        receiver_data: receiver_data as *mut _ as *mut c_void,
+        on_error: Some(receiver_on_error),
+        begin_preset: Some(receiver_begin_preset),
+        add_plugin_id: Some(receiver_add_plugin_id),
+        set_soundpack_id: Some(receiver_set_soundpack_id),
+        set_flags: Some(receiver_set_flags),
+        add_creator: Some(receiver_add_creator),
+        set_description: Some(receiver_set_description),
+        set_timestamps: Some(receiver_set_timestamps),
+        add_feature: Some(receiver_add_feature),
+        add_extra_info: Some(receiver_add_extra_info),
+    }
+}
+
+unsafe extern "C" fn receiver_on_error(
+    receiver: *const clap_preset_discovery_metadata_receiver,
+    os_error: i32,
+    error_message: *const c_char,
+) {
+    unsafe {
+        let this = &*((*receiver).receiver_data as *const Receiver);
+        log::warn!(
+            "preset discovery error {:?} {} {:?}",
+            this.location,
+            os_error,
+            to_string(error_message)
+        );
+    }
+}
+
+unsafe extern "C" fn receiver_begin_preset(
+    receiver: *const clap_preset_discovery_metadata_receiver,
+    name: *const c_char,
+    load_key: *const c_char,
+) -> bool {
+    unsafe {
+        let this = &mut *((*receiver).receiver_data as *mut Receiver);
+        let load_key = to_string(load_key);
+        // 名前がなければファイル名
+        let name = to_string(name)
+            .or_else(|| {
+                this.location.as_ref().and_then(|x| {
+                    Path::new(x)
+                        .file_stem()
+                        .map(|x| x.to_string_lossy().to_string())
+                })
+            })
+            .or_else(|| load_key.clone())
+            .unwrap_or_default();
+        this.presets.push((
+            FactoryPreset {
+                name,
+                location_kind: this.location_kind,
+                location: this.location.clone(),
+                load_key,
+            },
+            vec![],
+        ));
+    }
+    true
+}
+
+unsafe extern "C" fn receiver_add_plugin_id(
+    receiver: *const clap_preset_discovery_metadata_receiver,
+    plugin_id: *const clap_universal_plugin_id,
+) {
+    unsafe {
+        let this = &mut *((*receiver).receiver_data as *mut Receiver);
+        if plugin_id.is_null() || to_string((*plugin_id).abi).as_deref() != Some("clap") {
+            return;
+        }
+        if let (Some((_, plugin_ids)), Some(id)) =
+            (this.presets.last_mut(), to_string((*plugin_id).id))
+        {
+            plugin_ids.push(id);
+        }
+    }
+}
+
+unsafe extern "C" fn receiver_set_soundpack_id(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _soundpack_id: *const c_char,
+) {
+}
+
+unsafe extern "C" fn receiver_set_flags(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _flags: u32,
+) {
+}
+
+unsafe extern "C" fn receiver_add_creator(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _creator: *const c_char,
+) {
+}
+
+unsafe extern "C" fn receiver_set_description(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _description: *const c_char,
+) {
+}
+
+unsafe extern "C" fn receiver_set_timestamps(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _creation_time: clap_timestamp,
+    _modification_time: clap_timestamp,
+) {
+}
+
+unsafe extern "C" fn receiver_add_feature(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _feature: *const c_char,
+) {
+}
+
+unsafe extern "C" fn receiver_add_extra_info(
+    _receiver: *const clap_preset_discovery_metadata_receiver,
+    _namespace_id: *const c_char,
+    _name: *const c_char,
+    _value: *const c_char,
+) {
+}
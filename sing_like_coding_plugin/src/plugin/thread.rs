@@ -0,0 +1,54 @@
+use std::{cell::Cell, sync::OnceLock};
+
+use clap_sys::plugin::clap_plugin;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+
+thread_local! {
+    // process を呼ぶスレッドと thread-pool のワーカー
+    static AUDIO_THREAD_P: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn audio_thread_set() {
+    AUDIO_THREAD_P.with(|x| x.set(true));
+}
+
+pub fn audio_thread_p() -> bool {
+    AUDIO_THREAD_P.with(|x| x.get())
+}
+
+/// プロセス内の全プラグインで共有する
+fn thread_pool() -> &'static ThreadPool {
+    static THREAD_POOL: OnceLock<ThreadPool> = OnceLock::new();
+    THREAD_POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .thread_name(|index| format!("clap-thread-pool-{}", index))
+            .start_handler(|_| audio_thread_set())
+            .build()
+            .unwrap()
+    })
+}
+
+struct PluginRaw(*const clap_plugin);
+unsafe impl Send for PluginRaw {}
+unsafe impl Sync for PluginRaw {}
+
+impl PluginRaw {
+    // クロージャーが .0 だけをキャプチャーすると Sync にならないので
+    fn get(&self) -> *const clap_plugin {
+        self.0
+    }
+}
+
+/// thread-pool の request_exec 全部のタスクが終わるまで戻らない
+pub fn exec(
+    plugin: *const clap_plugin,
+    exec: unsafe extern "C" fn(*const clap_plugin, u32),
+    num_tasks: u32,
+) {
+    let plugin = PluginRaw(plugin);
+    thread_pool().install(|| {
+        (0..num_tasks)
+            .into_par_iter()
+            .for_each(|task_index| unsafe { exec(plugin.get(), task_index) });
+    });
+}
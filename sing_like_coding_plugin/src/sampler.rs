@@ -0,0 +1,329 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use common::{
+    audio_file,
+    process_data::{EventKind, ProcessData, MAX_FRAMES},
+    sampler::{PlayMode, SamplerState, Zone},
+};
+
+// チョークされたときのリリース
+const CHOKE_RELEASE: f32 = 0.005;
+
+/// sample_rate に変換済みのサンプル
+struct Sample {
+    channels: Vec<Vec<f32>>,
+    // 変換後のフレーム数 / ファイルのフレーム数
+    ratio: f64,
+}
+
+impl Sample {
+    fn len(&self) -> usize {
+        self.channels.first().map(|x| x.len()).unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+struct Voice {
+    sample: Arc<Sample>,
+    key: i16,
+    position: f64,
+    step: f64,
+    gain: f32,
+    stage: Stage,
+    level: f32,
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    release_step: f32,
+    loop_range: Option<(f64, f64)>,
+    mode: PlayMode,
+    choke_group: Option<u8>,
+}
+
+impl Voice {
+    fn new(zone: &Zone, sample: Arc<Sample>, key: i16, velocity: f64) -> Self {
+        let loop_range = zone
+            .loop_range
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| (start as f64 * sample.ratio, end as f64 * sample.ratio))
+            .filter(|(_, end)| *end <= sample.len() as f64);
+        Self {
+            sample,
+            key,
+            position: 0.0,
+            step: 2.0f64.powf((key - zone.root_key) as f64 / 12.0),
+            gain: 10.0f32.powf(zone.gain / 20.0) * (velocity / 127.0) as f32,
+            stage: Stage::Attack,
+            level: 0.0,
+            attack: zone.attack,
+            decay: zone.decay,
+            sustain: zone.sustain.clamp(0.0, 1.0),
+            release: zone.release,
+            release_step: 0.0,
+            loop_range,
+            mode: zone.mode,
+            choke_group: zone.choke_group,
+        }
+    }
+
+    fn release_start(&mut self, release: f32, sample_rate: f32) {
+        if matches!(self.stage, Stage::Release | Stage::Done) {
+            return;
+        }
+        self.stage = Stage::Release;
+        self.release_step = self.level / (release * sample_rate).max(1.0);
+    }
+
+    fn envelope_next(&mut self, sample_rate: f32) -> f32 {
+        match self.stage {
+            Stage::Attack => {
+                self.level += 1.0 / (self.attack * sample_rate).max(1.0);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= (1.0 - self.sustain) / (self.decay * sample_rate).max(1.0);
+                if self.level <= self.sustain {
+                    self.level = self.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => {
+                if self.level <= 0.0 {
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Release => {
+                self.level -= self.release_step;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Done;
+                }
+            }
+            Stage::Done => {}
+        }
+        self.level
+    }
+
+    fn render(
+        &mut self,
+        output: &mut [[f32; MAX_FRAMES]],
+        nchannels: usize,
+        frames: Range<usize>,
+        sample_rate: f32,
+    ) {
+        let len = self.sample.len() as f64;
+        for frame in frames {
+            if self.stage == Stage::Done {
+                return;
+            }
+            if let (PlayMode::Gate, Some((start, end))) = (self.mode, self.loop_range) {
+                while self.position >= end {
+                    self.position -= end - start;
+                }
+            }
+            if self.position >= len - 1.0 {
+                self.stage = Stage::Done;
+                return;
+            }
+            let level = self.envelope_next(sample_rate) * self.gain;
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+            for (channel, buffer) in output.iter_mut().take(nchannels).enumerate() {
+                let src = &self.sample.channels[channel % self.sample.channels.len()];
+                let value = src[index] + (src[index + 1] - src[index]) * t;
+                buffer[frame] += value * level;
+            }
+            self.position += self.step;
+        }
+    }
+}
+
+/// キーとベロシティでゾーンを選んで WAV を鳴らす
+pub struct Sampler {
+    state: SamplerState,
+    samples: HashMap<String, Arc<Sample>>,
+    voices: Vec<Voice>,
+    sample_rate: f64,
+}
+
+impl Sampler {
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            state: Default::default(),
+            samples: Default::default(),
+            voices: vec![],
+            sample_rate,
+        }
+    }
+
+    /// 読み込み中に process を止めないようにロックの外で読む
+    pub fn state_load(this: &Mutex<Self>, state: Vec<u8>) -> Result<()> {
+        let state = SamplerState::from_bytes(&state)?;
+        let (sample_rate, loaded) = {
+            let this = this.lock().unwrap();
+            (this.sample_rate, this.samples.clone())
+        };
+        let samples = samples_load(&state, sample_rate, &loaded);
+        let mut this = this.lock().unwrap();
+        this.state = state;
+        this.samples = samples;
+        this.voices.clear();
+        Ok(())
+    }
+
+    pub fn state_save(&self) -> Result<Vec<u8>> {
+        self.state.to_bytes()
+    }
+
+    pub fn audio_config_set(this: &Mutex<Self>, sample_rate: f64) {
+        let state = {
+            let this = this.lock().unwrap();
+            if this.sample_rate == sample_rate {
+                return;
+            }
+            this.state.clone()
+        };
+        let samples = samples_load(&state, sample_rate, &HashMap::new());
+        let mut this = this.lock().unwrap();
+        this.sample_rate = sample_rate;
+        this.samples = samples;
+        this.voices.clear();
+    }
+
+    pub fn process(&mut self, process_data: &mut ProcessData) -> Result<()> {
+        // 共有メモリはゼロ埋めなので Plugin::process と同じく毎回ポートを教える
+        process_data.nports_in = 1;
+        process_data.nports_out = 1;
+        process_data.nchannels_in[0] = 2;
+        process_data.nchannels_out[0] = 2;
+        let nframes = process_data.nframes;
+        let nchannels = process_data.nchannels_out[0];
+        let sample_rate = self.sample_rate as f32;
+        let samples_per_delay = (process_data.sample_rate * 60.0)
+            / (process_data.bpm * process_data.lpb as f64 * 256.0);
+
+        let mut events = process_data.events_input[..process_data.nevents_input]
+            .iter()
+            .map(|event| {
+                let frame = (event.delay as f64 * samples_per_delay).round() as usize;
+                (frame.min(nframes.saturating_sub(1)), *event)
+            })
+            .collect::<Vec<_>>();
+        events.sort_by_key(|(frame, _)| *frame);
+
+        let output = &mut process_data.buffer_out[0];
+        for buffer in output.iter_mut().take(nchannels) {
+            buffer[..nframes].fill(0.0);
+        }
+        process_data.constant_mask_out[0] = 0;
+
+        let mut frame = 0;
+        let mut events = events.into_iter().peekable();
+        while frame < nframes {
+            while let Some((_, event)) = events.next_if(|(x, _)| *x <= frame) {
+                match event.kind {
+                    EventKind::NoteOn => self.note_on(event.key, event.velocity),
+                    EventKind::NoteOff => self.note_off(event.key),
+                    EventKind::ParamValue => {}
+                }
+            }
+            let frame_next = events.peek().map(|(x, _)| *x).unwrap_or(nframes);
+            for voice in self.voices.iter_mut() {
+                voice.render(output, nchannels, frame..frame_next, sample_rate);
+            }
+            frame = frame_next;
+        }
+        self.voices.retain(|voice| voice.stage != Stage::Done);
+        Ok(())
+    }
+
+    fn note_on(&mut self, key: i16, velocity: f64) {
+        let sample_rate = self.sample_rate as f32;
+        for zone in self
+            .state
+            .zones
+            .iter()
+            .filter(|x| x.contains(key, velocity))
+        {
+            let Some(sample) = self.samples.get(&zone.path) else {
+                continue;
+            };
+            if let Some(choke_group) = zone.choke_group {
+                for voice in self
+                    .voices
+                    .iter_mut()
+                    .filter(|x| x.choke_group == Some(choke_group))
+                {
+                    voice.release_start(CHOKE_RELEASE, sample_rate);
+                }
+            }
+            self.voices
+                .push(Voice::new(zone, sample.clone(), key, velocity));
+        }
+    }
+
+    fn note_off(&mut self, key: i16) {
+        let sample_rate = self.sample_rate as f32;
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|x| x.key == key && x.mode == PlayMode::Gate)
+        {
+            let release = voice.release;
+            voice.release_start(release, sample_rate);
+        }
+    }
+}
+
+fn samples_load(
+    state: &SamplerState,
+    sample_rate: f64,
+    loaded: &HashMap<String, Arc<Sample>>,
+) -> HashMap<String, Arc<Sample>> {
+    let mut samples = HashMap::new();
+    for zone in state.zones.iter() {
+        if samples.contains_key(&zone.path) {
+            continue;
+        }
+        if let Some(sample) = loaded.get(&zone.path) {
+            samples.insert(zone.path.clone(), sample.clone());
+            continue;
+        }
+        match audio_file::read(&zone.path) {
+            Ok((file_sample_rate, channels)) => {
+                let channels = if file_sample_rate == sample_rate {
+                    channels
+                } else {
+                    channels
+                        .iter()
+                        .map(|x| audio_file::resample(x, file_sample_rate, sample_rate))
+                        .collect()
+                };
+                let sample = Sample {
+                    channels,
+                    ratio: sample_rate / file_sample_rate,
+                };
+                samples.insert(zone.path.clone(), Arc::new(sample));
+            }
+            Err(e) => log::warn!("sampler {} {:?}", zone.path, e),
+        }
+    }
+    samples
+}